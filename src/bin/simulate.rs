@@ -0,0 +1,202 @@
+//! Runs a WAV file through core 0's playback on the host, and writes the duty values the PWM
+//! would get back out as a WAV file. Like the firmware, the output ramps up to mid-scale before
+//! the track fades in, and fades out and goes back to mid-scale after it.
+//!
+//! ```sh
+//! cargo run --release --target x86_64-unknown-linux-gnu --features simulator --bin simulate -- in.wav out.wav
+//! ```
+//!
+//! Options follow the settings of the firmware: `--rate <Hz>` plays at a fixed output rate,
+//! `--linear` resamples with linear interpolation, `--stereo` keeps both channels and
+//! `--volume <step>` plays at a volume step instead of full scale.
+
+use std::{collections::VecDeque, fs, process::ExitCode};
+
+use dropstick::{
+    io::{mock::MemorySink, CommandChannel},
+    pcm::ChannelMode,
+    playback::{self, Playback, HALF_BUFFER_FRAMES, PLAYER_BUFFER_SIZE},
+    player::{wav, wav_streaming::WAVStreamPlayer},
+    protocol::{Command, Event},
+    pwm_timing::{OutputRate, AUDIO_SYS_FREQS},
+    resample::{Quality, Resampler},
+    volume::{self, Volume},
+};
+
+/// Core 1's end of the channel, it picks the system clock once the output stopped for a track.
+struct Core1 {
+    commands: VecDeque<Command>,
+    sys_freq: u32,
+    events: Vec<Event>,
+}
+
+impl CommandChannel for Core1 {
+    fn try_receive(&mut self) -> Option<Command> {
+        self.commands.pop_front()
+    }
+
+    fn receive(&mut self) -> Command {
+        self.commands.pop_front().expect("core 0 waits for a command that never comes")
+    }
+
+    fn notify(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    fn send(&mut self, event: Event) {
+        if event == Event::OutputStopped {
+            self.commands.push_back(Command::SystemClock { sys_freq: self.sys_freq });
+        }
+        self.events.push(event);
+    }
+}
+
+struct Options {
+    input: String,
+    output: String,
+    output_rate: OutputRate,
+    quality: Quality,
+    channel_mode: ChannelMode,
+    volume: u8,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut paths = Vec::new();
+    let mut options = Options {
+        input: String::new(),
+        output: String::new(),
+        output_rate: OutputRate::MatchFile,
+        quality: Quality::Sinc,
+        channel_mode: ChannelMode::Downmix,
+        volume: volume::MAX_STEP,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            let value = args.next().ok_or(format!("{name} needs a value"))?;
+            value.parse::<u32>().map_err(|_| format!("{name} needs a number, not {value:?}"))
+        };
+        match arg.as_str() {
+            "--rate" => options.output_rate = OutputRate::Fixed(value("--rate")?),
+            "--volume" => options.volume = value("--volume")?.min(volume::MAX_STEP as u32) as u8,
+            "--linear" => options.quality = Quality::Linear,
+            "--stereo" => options.channel_mode = ChannelMode::Stereo,
+            flag if flag.starts_with("--") => return Err(format!("unknown option {flag}")),
+            _ => paths.push(arg),
+        }
+    }
+
+    let [input, output] = <[String; 2]>::try_from(paths).map_err(|_| "expected an input and an output file".to_string())?;
+    Ok(Options { input, output, ..options })
+}
+
+/// Plays the file the way both cores would, and returns the output rate, channels and duty values.
+fn render(file: &[u8], options: &Options) -> Result<(u32, u16, Vec<i16>), String> {
+    let header = wav::parse_header_from_slice(file).map_err(|error| format!("can not read the file: {error:?}"))?;
+    let format = WAVStreamPlayer::supports::<()>(&header.format).map_err(|error| format!("can not play the file: {error:?}"))?;
+    let sample_rate = header.format.sample_rate;
+    let (output_rate, clock) = options.output_rate.choose(sample_rate, &AUDIO_SYS_FREQS)
+        .filter(|&(output_rate, _)| Resampler::supports(sample_rate, output_rate))
+        .ok_or(format!("can not play {sample_rate}Hz"))?;
+    eprintln!(
+        "{}: {:?} at {}Hz, played at {}Hz with a {}MHz system clock ({:?})",
+        options.input, format, sample_rate, output_rate, clock.sys_freq / 1_000_000, clock.timing,
+    );
+
+    let channels = match options.channel_mode {
+        ChannelMode::Downmix => 1,
+        ChannelMode::Stereo => 2,
+    };
+    let top = clock.timing.top;
+    let mut output = Vec::new();
+    {
+        let mut buf = [0; PLAYER_BUFFER_SIZE];
+        let mut player = WAVStreamPlayer::new(&mut buf, options.channel_mode, options.quality);
+        player.set_timing(&clock.timing);
+        let sink = MemorySink::new(HALF_BUFFER_FRAMES, |(a, b)| {
+            output.push(duty_to_sample(a, top));
+            if channels == 2 {
+                output.push(duty_to_sample(b, top));
+            }
+        });
+        let mut playback = Playback::new(player, sink, &[][..], output_rate, playback::FADES);
+        let mut core1 = Core1 { commands: VecDeque::new(), sys_freq: clock.sys_freq, events: Vec::new() };
+        let step_until = |playback: &mut Playback<_, _>, core1: &mut Core1, event: Event| {
+            while !core1.events.contains(&event) {
+                playback.step(core1);
+            }
+        };
+
+        // Core 1 writes the samples once the track started, starting it drops what was there
+        let gain = Volume::new(options.volume, volume::MAX_STEP).gain();
+        core1.commands.extend([Command::SetVolume { gain }, Command::TrackStart { format, sample_rate, output_rate }]);
+        step_until(&mut playback, &mut core1, Event::OutputStopped);
+        core1.events.clear();
+        let start = header.data_offset as usize;
+        *playback.source() = &file[start..start + header.data_length as usize];
+
+        // Play it all, and stop once core 0 ran out
+        step_until(&mut playback, &mut core1, Event::Underrun);
+        core1.commands.push_back(Command::Stop);
+        step_until(&mut playback, &mut core1, Event::Flushed);
+
+        // Let the output go back to mid-scale
+        let frames = output_rate as usize / 1_000 * playback::FADES.fade_ms as usize;
+        for _ in 0..frames.div_ceil(HALF_BUFFER_FRAMES) {
+            playback.step(&mut core1);
+        }
+    }
+
+    Ok((output_rate, channels, output))
+}
+
+/// Maps a duty value back onto the full range of a 16 bit sample.
+fn duty_to_sample(duty: u16, top: u16) -> i16 {
+    let sample = duty as i64 * 65_536 / (top as i64 + 1) - 32_768;
+    sample.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
+
+/// Builds a 16 bit PCM WAV file.
+fn encode_wav(sample_rate: u32, channels: u16, samples: &[i16]) -> Vec<u8> {
+    let data_length = samples.len() as u32 * 2;
+    let block_align = channels * 2;
+
+    let mut file = Vec::with_capacity(44 + data_length as usize);
+    file.extend_from_slice(b"RIFF");
+    file.extend_from_slice(&(36 + data_length).to_le_bytes());
+    file.extend_from_slice(b"WAVEfmt ");
+    file.extend_from_slice(&16u32.to_le_bytes());
+    file.extend_from_slice(&wav::WAVE_FORMAT_PCM.to_le_bytes());
+    file.extend_from_slice(&channels.to_le_bytes());
+    file.extend_from_slice(&sample_rate.to_le_bytes());
+    file.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    file.extend_from_slice(&block_align.to_le_bytes());
+    file.extend_from_slice(&16u16.to_le_bytes());
+    file.extend_from_slice(b"data");
+    file.extend_from_slice(&data_length.to_le_bytes());
+    for sample in samples {
+        file.extend_from_slice(&sample.to_le_bytes());
+    }
+    file
+}
+
+fn run() -> Result<(), String> {
+    let options = parse_options(std::env::args().skip(1))?;
+    let file = fs::read(&options.input).map_err(|error| format!("can not read {}: {error}", options.input))?;
+    let (output_rate, channels, samples) = render(&file, &options)?;
+    fs::write(&options.output, encode_wav(output_rate, channels, &samples))
+        .map_err(|error| format!("can not write {}: {error}", options.output))?;
+    eprintln!("{}: {} frames", options.output, samples.len() / channels as usize);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            eprintln!("usage: simulate <input.wav> <output.wav> [--rate <Hz>] [--linear] [--stereo] [--volume <step>]");
+            ExitCode::FAILURE
+        }
+    }
+}
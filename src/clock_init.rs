@@ -1,12 +1,12 @@
-use fugit::RateExtU32;
-use rp2040_hal::{self as hal, clocks::{ClocksManager, InitError}, pac, pll::PLLConfig, Watchdog};
+use dropstick::pwm_timing::AUDIO_SYS_FREQS;
+use fugit::{HertzU32, RateExtU32};
+use rp2040_hal::{self as hal, clocks::{ClockSource, ClocksManager, InitError}, pac::{self, PLL_SYS, PLL_USB}, pll::{Locked, PLLConfig, PhaseLockedLoop}, xosc::{CrystalOscillator, Stable}, Clock, Watchdog};
 
 
 /// The frequency of the on-board crystal
 pub const XTAL_FREQ_HZ: u32 = 12_000_000u32;
 
 /// This clock rate is closest to 176,400,000 Hz, which is a multiple of 44,100 Hz.
-#[allow(dead_code)]
 pub const PLL_SYS_176MHZ: PLLConfig = PLLConfig {
     vco_freq: fugit::Rate::<u32, 1, 1>::MHz(528),
     refdiv: 1,
@@ -15,7 +15,6 @@ pub const PLL_SYS_176MHZ: PLLConfig = PLLConfig {
 };
 
 /// This clock rate is closest to 131,072,000 Hz, which is a multiple of 32,000 Hz (the audio sample rate).
-#[allow(dead_code)]
 pub const PLL_SYS_131MHZ: PLLConfig = PLLConfig {
     vco_freq: fugit::Rate::<u32, 1, 1>::MHz(1572),
     refdiv: 1,
@@ -23,6 +22,79 @@ pub const PLL_SYS_131MHZ: PLLConfig = PLLConfig {
     post_div2: 2,
 };
 
+/// System clocks the player can switch between to match the sample rate of a file
+pub const AUDIO_PLL_SYS_CONFIGS: [PLLConfig; 2] = [PLL_SYS_131MHZ, PLL_SYS_176MHZ];
+
+// The timing is picked from the frequencies alone, which the library has to agree on
+const _: () = assert!(pll_freq_hz(&AUDIO_PLL_SYS_CONFIGS[0]) == AUDIO_SYS_FREQS[0]);
+const _: () = assert!(pll_freq_hz(&AUDIO_PLL_SYS_CONFIGS[1]) == AUDIO_SYS_FREQS[1]);
+
+/// Frequency a PLL config results in
+pub const fn pll_freq_hz(config: &PLLConfig) -> u32 {
+    config.vco_freq.to_Hz() / (config.post_div1 as u32 * config.post_div2 as u32)
+}
+
+/// The clock tree together with the oscillators and PLLs that drive it,
+/// kept around so the system clock can be changed at runtime.
+pub struct SystemClocks {
+    pub manager: ClocksManager,
+    xosc: CrystalOscillator<Stable>,
+    pll_sys: Option<PhaseLockedLoop<Locked, PLL_SYS>>,
+    _pll_usb: PhaseLockedLoop<Locked, PLL_USB>,
+}
+
+impl SystemClocks {
+    pub fn system_freq(&self) -> HertzU32 {
+        self.manager.system_clock.freq()
+    }
+
+    pub fn peripheral_freq(&self) -> HertzU32 {
+        self.manager.peripheral_clock.freq()
+    }
+
+    /// Relocks the system PLL with a new config.
+    ///
+    /// The system and peripheral clocks (and with them SPI baud rates and PWM periods)
+    /// change, so anything clocked from them must be idle and reconfigured afterwards.
+    /// The reference clock is moved back to the crystal, which keeps the watchdog tick,
+    /// and with it the Timer, at 1MHz.
+    pub fn set_pll_sys(&mut self, pll_sys_cfg: PLLConfig, resets: &mut pac::RESETS) -> Result<(), InitError> {
+        let xosc_freq = self.xosc.operating_frequency();
+
+        // Runs the system from its reset source while the PLL relocks
+        let pll_sys_dev = match self.pll_sys.take() {
+            Some(pll_sys) => pll_sys.free(),
+            // A previous relock failed and took the PLL with it
+            None => unsafe { pac::Peripherals::steal() }.PLL_SYS,
+        };
+        let pll_sys = hal::pll::setup_pll_blocking(
+            pll_sys_dev,
+            xosc_freq,
+            pll_sys_cfg,
+            &mut self.manager,
+            resets,
+        )
+        .map_err(InitError::PllError)?;
+
+        self.manager
+            .reference_clock
+            .configure_clock(&self.xosc, self.xosc.get_freq())
+            .map_err(InitError::ClockError)?;
+        self.manager
+            .system_clock
+            .configure_clock(&pll_sys, pll_sys.get_freq())
+            .map_err(InitError::ClockError)?;
+        let system_freq = self.manager.system_clock.freq();
+        self.manager
+            .peripheral_clock
+            .configure_clock(&self.manager.system_clock, system_freq)
+            .map_err(InitError::ClockError)?;
+
+        self.pll_sys = Some(pll_sys);
+        Ok(())
+    }
+}
+
 /// Initialize system clocks and PLLs according to specified configs
 #[allow(clippy::too_many_arguments)]
 pub fn init_system_clocks(
@@ -35,7 +107,7 @@ pub fn init_system_clocks(
     pll_usb_cfg: PLLConfig,
     resets: &mut pac::RESETS,
     watchdog: &mut Watchdog,
-) -> Result<ClocksManager, InitError> {
+) -> Result<SystemClocks, InitError> {
     let xosc = hal::xosc::setup_xosc_blocking(xosc_dev, xosc_crystal_freq.Hz())
         .map_err(InitError::XoscErr)?;
 
@@ -64,5 +136,10 @@ pub fn init_system_clocks(
     clocks
         .init_default(&xosc, &pll_sys, &pll_usb)
         .map_err(InitError::ClockError)?;
-    Ok(clocks)
+    Ok(SystemClocks {
+        manager: clocks,
+        xosc,
+        pll_sys: Some(pll_sys),
+        _pll_usb: pll_usb,
+    })
 }
@@ -1,27 +1,43 @@
 use core::cell::{OnceCell, UnsafeCell};
-use cortex_m::prelude::_embedded_hal_PwmPin;
+use cortex_m::singleton;
 use critical_section::Mutex;
-use defmt::{error, info, trace};
-use embedded_hal::{digital::InputPin};
-use rp2040_hal::{self as hal, gpio::{bank0::{Gpio16, Gpio6}, FunctionNull, Pin, PullDown}, pac::{self, interrupt}, pwm::{FreeRunning, Pwm0, Slice, Slices}, sio::SioFifo, Timer};
+use defmt::{error, info, trace, warn};
+use rp2040_hal::{self as hal, dma::{Channel, CH0, CH1}, gpio::{bank0::{Gpio16, Gpio17}, FunctionNull, Pin, PullDown}, pac::{self, interrupt}, pwm::{CcFormat, FreeRunning, Pwm0, Slice, Slices}, sio::SioFifo, Timer};
 
-use crate::player::wav_streaming::WAVStreamPlayer;
+use dropstick::{io::{AudioSink, CommandChannel, SampleSource}, pcm::{s16_to_pwm, scale_to_top, ChannelMode}, playback::{self, Fades, Playback, HALF_BUFFER_FRAMES, PLAYER_BUFFER_SIZE}, player::wav_streaming::{WAVStreamPlayer, SAMPLE_RING_SIZE}, protocol::{Command, Event, MAX_MESSAGE_WORDS}, pwm_timing::PwmTiming, resample::Quality, ring_buffer::Consumer};
+
+use crate::pwm_dma::{DutyDma, HalfBuffer};
+
+
+/// How stereo files are played: mixed down to channel A (GPIO16),
+/// or with the right channel on channel B (GPIO17).
+const CHANNEL_MODE: ChannelMode = ChannelMode::Downmix;
+
+/// How files are resampled when the PWM can't run at their sample rate.
+/// Sinc sounds clean, linear takes a lot less time per sample.
+const RESAMPLE_QUALITY: Quality = Quality::Sinc;
+
+/// How long the sound fades, and the output ramps between 0 and mid-scale.
+/// The simulator fades the same way.
+const FADES: Fades = playback::FADES;
+/// Rate the PWM runs at until we know the rate of the first file
+const FIRST_RATE: u32 = 32_000;
 
 
 /* SHARED WITH INTERRUPT */
 
-// The hardware PWM driver that is shared with the interrupt routine.
+// The hardware PWM driver, the DMA writes its duty values but we still change its timing.
 static PWM: Mutex<UnsafeCell<OnceCell<hal::pwm::Slice<Pwm0, FreeRunning>>>> = Mutex::new(UnsafeCell::new(OnceCell::new()));
 
 /// Safely accesses global PWM variable.
 /// WARNING: Uses critical section.
-fn access_pwm<T: Fn(&mut hal::pwm::Slice<Pwm0, FreeRunning>) -> ()> (function: T) -> () {
+fn access_pwm<R, T: FnOnce(&mut hal::pwm::Slice<Pwm0, FreeRunning>) -> R> (function: T) -> R {
     critical_section::with(|cs| {
         let pwm_cell = PWM.borrow(cs);
         let pwm = unsafe {pwm_cell.as_mut_unchecked()}.get_mut().unwrap();
 
-        function(pwm);
-    });
+        function(pwm)
+    })
 }
 
 /// Safely set global PWM variable.
@@ -37,93 +53,290 @@ fn set_pwm(pwm: hal::pwm::Slice<Pwm0, FreeRunning>) -> () {
 }
 
 
+// The DMA channels feeding the PWM, shared with the interrupt routine.
+static DUTY_DMA: Mutex<UnsafeCell<OnceCell<DutyDma<Pwm0>>>> = Mutex::new(UnsafeCell::new(OnceCell::new()));
+
+/// Safely accesses global DUTY_DMA variable.
+/// WARNING: Uses critical section.
+fn access_duty_dma<R, T: FnOnce(&mut DutyDma<Pwm0>) -> R> (function: T) -> R {
+    critical_section::with(|cs| {
+        let duty_dma_cell = DUTY_DMA.borrow(cs);
+        let duty_dma = unsafe {duty_dma_cell.as_mut_unchecked()}.get_mut().unwrap();
+
+        function(duty_dma)
+    })
+}
+
+/// Safely set global DUTY_DMA variable.
+/// WARNING: Only call this *once*, else forced panic.
+fn set_duty_dma(duty_dma: DutyDma<Pwm0>) {
+    critical_section::with(|cs| {
+        let duty_dma_cell = DUTY_DMA.borrow(cs);
+        let result = unsafe {duty_dma_cell.as_mut_unchecked()}.set(duty_dma);
+        if result.is_err() {
+            error!("Shared DUTY_DMA Mutex failed to set!");
+        };
+    });
+}
+
+
 #[interrupt]
-fn PWM_IRQ_WRAP() {
-    access_pwm(|pwm| {
-        // Clear the interrupt so we don't immediately re-enter this routine
-        pwm.clear_interrupt();
+fn DMA_IRQ_0() {
+    access_duty_dma(|duty_dma| {
+        // Rewind the channel that finished, and remember its half needs refilling
+        duty_dma.on_interrupt();
     });
 }
 
+/// Sleeps until the DMA has played a half of the buffer, and returns which one.
+fn await_finished_half() -> usize {
+    loop {
+        // Interrupts stay masked between checking and sleeping, so a half finishing
+        // in between still wakes us up instead of being missed until the next one.
+        cortex_m::interrupt::disable();
+        let finished = access_duty_dma(|duty_dma| duty_dma.take_finished());
+        if finished.is_none() {
+            cortex_m::asm::wfi();
+        }
+        unsafe {cortex_m::interrupt::enable()};
+
+        if let Some(half) = finished {
+            return half;
+        }
+    }
+}
+
+/// The duty value of silence for a PWM period of `top`, like the player's.
+fn mid_scale(top: u16) -> u16 {
+    scale_to_top(s16_to_pwm(0), top)
+}
+
+/// Sets the PWM period, and with it the sample rate.
+///
+/// fPWM = fSYS / ((TOP + 1) * (CSR_PH_CORRECT + 1) * (DIV_INT + (DIV_FRAC / 16)))
+fn set_pwm_timing(pwm: &mut Slice<Pwm0, FreeRunning>, timing: &PwmTiming) {
+    pwm.set_top(timing.top);
+    pwm.set_div_int(timing.div_int);
+    pwm.set_div_frac(timing.div_frac);
+}
+
+
+/* HARDWARE FOR THE PLAYBACK */
+
+/// Plays duty values through the audio PWM, with the DMA halves as the room to fill.
+struct PwmSink {
+    halves: &'static mut [HalfBuffer; 2],
+    /// The half that is being refilled
+    half: usize,
+    /// Duty values pushed into it so far
+    filled: usize,
+    timer: Timer,
+    /// When we started refilling the half, for performance debugging
+    start_time: u64,
+    /// The DMA is paused and the PWM held at mid-scale, while the system clock may change
+    paused: bool,
+}
+
+impl AudioSink for PwmSink {
+    fn configure(&mut self, timing: &PwmTiming) {
+        trace!("PWM timing: {}", timing);
+        access_pwm(|pwm| set_pwm_timing(pwm, timing));
+
+        // The halves are scaled to the old period, so they'd play off mid-scale until refilled
+        if self.paused {
+            let mid = mid_scale(timing.top);
+            self.halves.iter_mut().flatten().for_each(|duty| *duty = CcFormat { a: mid, b: mid });
+            access_duty_dma(|duty_dma| duty_dma.pause(mid));
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        // Switching the PWM off would leave the pin at one of the rails, which thumps,
+        // so it keeps running at mid-scale while the DMA pauses
+        self.paused = !enabled;
+        if enabled {
+            access_duty_dma(|duty_dma| duty_dma.resume());
+        } else {
+            let mid = mid_scale(access_pwm(|pwm| pwm.get_top()));
+            access_duty_dma(|duty_dma| duty_dma.pause(mid));
+        }
+    }
+
+    fn wait_for_space(&mut self) -> usize {
+        // Log time it took to fill the last half for performance debugging
+        let current_time = self.timer.get_counter().ticks();
+        trace!("Filling half took: {}us", current_time - self.start_time);
+
+        // Wait till the DMA is done with one half
+        self.half = await_finished_half();
+        self.filled = 0;
+        self.start_time = self.timer.get_counter().ticks();
+        HALF_BUFFER_FRAMES
+    }
+
+    fn push(&mut self, (a, b): (u16, u16)) {
+        self.halves[self.half][self.filled] = CcFormat { a, b };
+        self.filled += 1;
+    }
+
+    fn take_underrun(&mut self) -> bool {
+        let missed = access_duty_dma(|duty_dma| duty_dma.take_missed());
+        if missed {
+            warn!("DMA played a half before it was refilled!");
+        }
+        missed
+    }
+}
+
+/// The ring core 1 fills with samples.
+struct RingSource(Consumer<'static, SAMPLE_RING_SIZE>);
+
+impl SampleSource for RingSource {
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let read = self.0.read(buffer);
+
+        // Wake core 1 up in case it waits for room in the ring
+        cortex_m::asm::sev();
+        read
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Commands from core 1 and events back, over the SIO FIFO.
+struct FifoChannel<'a> {
+    fifo: &'a mut SioFifo,
+    /// Words of an event that didn't fit in the FIFO yet, they go before any others
+    unsent: [u32; MAX_MESSAGE_WORDS],
+    /// Words of `unsent` that went out already, and how many it holds
+    sent: usize,
+    unsent_count: usize,
+}
+
+impl FifoChannel<'_> {
+    fn new(fifo: &mut SioFifo) -> FifoChannel<'_> {
+        FifoChannel { fifo, unsent: [0; MAX_MESSAGE_WORDS], sent: 0, unsent_count: 0 }
+    }
+
+    /// Writes as many of the unsent words as fit, and returns whether all of them did.
+    fn try_write_unsent(&mut self) -> bool {
+        while self.sent < self.unsent_count {
+            if !self.fifo.is_write_ready() {
+                return false;
+            }
+            self.fifo.write(self.unsent[self.sent]);
+            self.sent += 1;
+        }
+        true
+    }
+}
+
+impl CommandChannel for FifoChannel<'_> {
+    fn try_receive(&mut self) -> Option<Command> {
+        // This runs every step, core 1 waits for the rest of an event it started reading
+        self.try_write_unsent();
+        while let Some(first) = self.fifo.read() {
+            match Command::decode(first, || self.fifo.read_blocking()) {
+                Ok(command) => {
+                    trace!("Command: {}", command);
+                    return Some(command);
+                }
+                Err(error) => error!("Bad command from core 1: {}", error),
+            }
+        }
+        None
+    }
+
+    fn receive(&mut self) -> Command {
+        loop {
+            let first = self.fifo.read_blocking();
+            match Command::decode(first, || self.fifo.read_blocking()) {
+                Ok(command) => {
+                    trace!("Command: {}", command);
+                    return command;
+                }
+                Err(error) => error!("Bad command from core 1: {}", error),
+            }
+        }
+    }
+
+    /// Reports an event to core 1, unless the one before still didn't fit. We must never block on
+    /// core 1, it may be waiting on us to make room in the ring. The FIFO only tells whether there is
+    /// room for one more word, so the words that don't fit go out with the next call.
+    fn notify(&mut self, event: Event) {
+        if !self.try_write_unsent() {
+            return;
+        }
+        let mut count = 0;
+        event.encode(|word| {
+            self.unsent[count] = word;
+            count += 1;
+        });
+        (self.sent, self.unsent_count) = (0, count);
+        self.try_write_unsent();
+    }
+
+    fn send(&mut self, event: Event) {
+        // Core 1 reads the rest of a message before it looks at the next one
+        while !self.try_write_unsent() {}
+        event.encode(|word| self.fifo.write_blocking(word));
+    }
+}
 
 
-pub fn main(gpio6: Pin<Gpio6, FunctionNull, PullDown>, gpio16: Pin<Gpio16, FunctionNull, PullDown>, timer: Timer, pwm_slices: Slices, inter_core_fifo: &mut SioFifo) -> ! {
+#[allow(clippy::too_many_arguments)]
+pub fn main(gpio16: Pin<Gpio16, FunctionNull, PullDown>, gpio17: Pin<Gpio17, FunctionNull, PullDown>, timer: Timer, pwm_slices: Slices, dma_channels: (Channel<CH0>, Channel<CH1>), sample_consumer: Consumer<'static, SAMPLE_RING_SIZE>, inter_core_fifo: &mut SioFifo, sys_freq: u32) -> ! {
     info!("Core 0 says hiii! X3");
 
     // Set up wav player
-    let mut buf = [0; 128];
-    let mut wav_player = WAVStreamPlayer::new(&mut buf);
+    let mut buf = [0; PLAYER_BUFFER_SIZE];
+    let mut wav_player = WAVStreamPlayer::new(&mut buf, CHANNEL_MODE, RESAMPLE_QUALITY);
     
     {
         // Get our audio PWM peripheral
         let mut pwm: Slice<Pwm0, FreeRunning> = pwm_slices.pwm0;
 
-        // Let the player configure it
-        wav_player.init(&mut pwm);
+        // Run it at a rate of its own until we know the rate of the first file,
+        // the halves start out at 0 so the output only moves once the ramp up starts
+        let timing = PwmTiming::for_rate(sys_freq, FIRST_RATE).unwrap();
+        pwm.default_config();
+        set_pwm_timing(&mut pwm, &timing);
+        wav_player.set_timing(&timing);
+        pwm.enable();
         
-        // Set its output channel
+        // Set its output channels
         pwm.channel_a.output_to(gpio16);
+        if wav_player.channel_mode() == ChannelMode::Stereo {
+            pwm.channel_b.output_to(gpio17);
+        }
         
         // Give it away to our shared Mutex for it,
-        // so the interrupt handler can access it as well
+        // so we can still change its timing between tracks
         set_pwm(pwm);
-
-        // Unmask the PWM_IRQ_WRAP interrupt so we start receiving events.
-        unsafe {pac::NVIC::unmask(pac::Interrupt::PWM_IRQ_WRAP)};
     }
 
+    // Duty values for the DMA to play, one half is refilled while the other one plays
+    let halves = singleton!(: [HalfBuffer; 2] = [[CcFormat { a: 0, b: 0 }; HALF_BUFFER_FRAMES]; 2]).unwrap();
+    {
+        // Safety: the halves are a static singleton, so they live as long as the DMA runs
+        let duty_dma = unsafe {DutyDma::start(dma_channels, halves)};
 
-    // Pause button state
-    let mut button_pin = gpio6.into_pull_up_input();
-    let mut button_already_down: bool = button_pin.is_low().ok().expect("huh?? :0");
+        // Give it away to our shared Mutex for it,
+        // so the interrupt handler can access it as well
+        set_duty_dma(duty_dma);
+
+        // Unmask the DMA_IRQ_0 interrupt so we start receiving events.
+        unsafe {pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0)};
+    }
 
-    // Player state
-    let mut paused: bool = false;
-    let mut start_time: u64 = timer.get_counter().ticks();
 
     // Playback loop
+    let sink = PwmSink { halves, half: 0, filled: 0, timer, start_time: 0, paused: false };
+    let mut playback = Playback::new(wav_player, sink, RingSource(sample_consumer), FIRST_RATE, FADES);
+    let mut channel = FifoChannel::new(inter_core_fifo);
     loop {
-        // Pause button
-        if button_pin.is_low().is_ok_and(|val| val == true) {
-            if !button_already_down {
-                button_already_down = true;
-                paused = !paused;
-            }
-        }
-        else {
-            button_already_down = false;
-        }
-
-        // Do not play if we are paused
-        if paused {continue;}
-
-        // Get sample
-        let val = wav_player.get_next_sample();
-
-        // Play sample
-        access_pwm(|pwm| {
-            pwm.channel_a.set_duty(val);
-        });
-
-        // Get more samples if we're out
-        if wav_player.counter >= wav_player.current_buffer.len() -1 {
-            // Read from inter-core fifo
-            for i in 0..wav_player.current_buffer.len() {
-                wav_player.current_buffer[i] = inter_core_fifo.read_blocking() as u8;
-            };
-            wav_player.counter = 0;
-
-            // Log time it took to play last buffer for performance debugging
-            let new_start_time = timer.get_counter().ticks();
-            let current_time = timer.get_counter().ticks();
-            trace!("Stream frame took: {}us goal: {}us", current_time - start_time, 31.25*wav_player.current_buffer.len() as f32);
-            start_time = new_start_time;
-        }
-        // Else wait till we need to supply next sample
-        else {
-            wav_player.await_next_tick();
-        }
-
-        // Loop, so we play next sample
+        playback.step(&mut channel);
     }
 }
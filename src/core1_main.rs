@@ -1,17 +1,116 @@
 use core::cell::{OnceCell, UnsafeCell};
 
 use critical_section::Mutex;
-use defmt::{debug, error, info, trace};
-use embedded_hal::digital::InputPin;
+use defmt::{debug, error, info, trace, warn};
+use embedded_hal::{delay::DelayNs, digital::{InputPin, OutputPin}, spi::{Operation, SpiDevice}};
 use embedded_hal_bus::spi::ExclusiveDevice;
-use embedded_sdmmc::{Mode, SdCard, TimeSource, Timestamp, VolumeIdx, VolumeManager};
-use fugit::RateExtU32;
-use rp2040_hal::{clocks::ClocksManager, gpio::{self, bank0::{Gpio2, Gpio3, Gpio4, Gpio5, Gpio7, Gpio8}, FunctionSio, PullUp, SioInput}, multicore::{Multicore, Stack}, pac::{self, interrupt, PPB, PSM, RESETS, SPI0}, sio::SioFifo, spi, Clock, Sio, Timer};
+use embedded_sdmmc::{LfnBuffer, Mode, RawDirectory, RawFile, RawVolume, SdCard, SdCardError, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use cortex_m::singleton;
+use dropstick::{error::{Backoff, DropstickError}, fade::TailMix, m3u::{self, M3uParser}, pcm::PcmFormat, playback::{HALF_BUFFER_FRAMES, PLAYER_BUFFER_SIZE}, player::{wav::{self, WavError, WavHeader}, wav_streaming::{WAVStreamPlayer, SAMPLE_RING_SIZE}}, input::{Button, Gesture, InputEvent, Inputs}, io::InputSource, playlist::{LongName, Playlist, Track, TrackName, ROOT}, protocol::{Command, Event}, pwm_timing::{ClockChoice, OutputRate, AUDIO_SYS_FREQS}, repeat::{AbLoop, Repeat}, resample::Resampler, ring_buffer::Producer, shuffle::ShuffleState, state::{self, PlayState, StateSlots}, volume::{self, Volume}};
+use fugit::{HertzU32, MicrosDurationU32};
+use rp2040_hal::{gpio::{self, bank0::{Gpio2, Gpio3, Gpio4, Gpio5, Gpio6, Gpio7, Gpio8, Gpio25}, FunctionSio, PullUp, SioInput}, multicore::{Multicore, Stack}, pac::{self, interrupt, PPB, PSM, RESETS, ROSC, SPI0}, rosc::{Enabled, RingOscillator}, sio::SioFifo, spi, timer::{Alarm, Alarm0}, Sio, Timer};
+
+use crate::clock_init::{SystemClocks, AUDIO_PLL_SYS_CONFIGS};
 
 /// Allocate stack for the second core
 static mut CORE1_STACK: Stack<4096> = Stack::new();
 
+/// SPI clock of the SD card while it is initialized, cards only have to answer this slowly
+const SD_INIT_BAUDRATE: HertzU32 = HertzU32::kHz(400);
+/// SPI clock of the SD card once it is initialized
+const SD_BAUDRATE: HertzU32 = HertzU32::MHz(16);
+
+/// How often the card is asked whether it is still there, while it isn't being read
+const CARD_POLL_INTERVAL_US: u64 = 500_000;
+/// CMD13 (SEND_STATUS) with its CRC, which the card checks since it was initialized
+const CMD13: [u8; 6] = [0x40 | 13, 0, 0, 0, 0, 0x0D];
+
+/// Delay before the first retry after the card failed, doubling up to `RETRY_MAX_US`
+const RETRY_FIRST_US: u64 = 250_000;
+const RETRY_MAX_US: u64 = 8_000_000;
+
+/// How long the LED is on, and off, for every blink of an error
+const BLINK_US: u32 = 150_000;
+/// Pause between the blinks of an error
+const BLINK_PAUSE_US: u32 = 1_000_000;
+
+/// Rate the PWM plays at, files at other rates are resampled to it
+const OUTPUT_RATE: OutputRate = OutputRate::MatchFile;
+
+/// Size of an SD card block, samples are read and handed to core 0 in these
+const SD_BLOCK_SIZE: usize = 512;
+
+/// How often the buttons are sampled
+const INPUT_POLL_INTERVAL: MicrosDurationU32 = MicrosDurationU32::millis(5);
+
+/// Loudest volume step the buttons can reach, 4dB below full scale to protect your hearing.
+/// `volume::MAX_STEP` allows all of it.
+const VOLUME_LIMIT: u8 = volume::MAX_STEP - 2;
+/// Volume step until one is saved, 6dB below full scale
+const DEFAULT_VOLUME: u8 = volume::MAX_STEP - 3;
+/// File in the root directory the volume is saved in
+const VOLUME_FILE: &str = "VOLUME.DAT";
+/// The volume is saved once it hasn't changed for this long, so holding a button doesn't
+/// write the card at every step
+const VOLUME_SAVE_DELAY_US: u64 = 2_000_000;
+
+/// File in the root directory where playback is saved in, to resume from after a power cycle
+const STATE_FILE: &str = "STATE.DAT";
+/// How often where playback is gets saved while playing, it is saved right away on pause
+const STATE_SAVE_INTERVAL_US: u64 = 30_000_000;
+
+/// Seconds every jump goes while a held button scans through a track, there is a jump every
+/// `input::REPEAT_US`. After `SCAN_JUMPS_PER_SPEED` jumps, they go on at the next speed.
+const SCAN_SPEEDS: [u32; 3] = [1, 5, 20];
+const SCAN_JUMPS_PER_SPEED: u32 = 12;
+
+/// Seconds the end of a track is mixed with the start of the next one, 0 plays them one after the other.
+/// Only tracks that play at the same rate are mixed, and only when the next one is long enough.
+const CROSSFADE_S: u32 = 0;
 
+/// Random bits of the ring oscillator that go into a shuffle seed
+const SEED_BITS: u32 = 256;
+
+/// Most tracks the playlist holds, about 21kB
+const MAX_TRACKS: usize = 256;
+/// Playlist files in the root directory that set the order tracks play in, instead of their names.
+/// With more than one on the card, the first of these is used.
+const PLAYLIST_FILES: [&str; 2] = ["playlist.m3u8", "playlist.m3u"];
+/// Bytes of UTF-8 a long file name is read into, enough for the longest FAT allows
+const LFN_BUFFER_SIZE: usize = 255 * 3;
+/// Most folders in one directory that are looked into for tracks
+const MAX_SUBFOLDERS: usize = 16;
+
+
+
+/// The SPI bus the SD card is connected to
+type SdSpi = spi::Spi<
+    spi::Enabled,
+    SPI0,
+    (
+        gpio::Pin<Gpio3, gpio::FunctionSpi, gpio::PullNone>,
+        gpio::Pin<Gpio4, gpio::FunctionSpi, gpio::PullUp>,
+        gpio::Pin<Gpio2, gpio::FunctionSpi, gpio::PullNone>,
+    ),
+    8,
+>;
+type SdVolumeManager = VolumeManager<
+    SdCard<ExclusiveDevice<SdSpi, gpio::Pin<Gpio5, gpio::FunctionSioOutput, gpio::PullDown>, Timer>, Timer>,
+    DummyTimesource,
+>;
+
+/// The Pico's own LED, it blinks what is wrong while nothing can play
+type StatusLed = gpio::Pin<Gpio25, gpio::FunctionSioOutput, gpio::PullDown>;
+
+/// Sets the SPI clock of the SD card, which is derived from the peripheral clock.
+fn set_sd_baudrate(volume_mgr: &SdVolumeManager, peripheral_freq: HertzU32, baudrate: HertzU32) {
+    volume_mgr.device(|device| {
+        device.spi(|spi| {
+            spi.bus_mut().set_baudrate(peripheral_freq, baudrate);
+            DummyTimesource::default()
+        })
+    });
+}
 
 /// A dummy timesource, which is mostly important for creating files.
 #[derive(Default)]
@@ -32,16 +131,855 @@ impl TimeSource for DummyTimesource {
     }
 }
 
+/// What an SD card error means for the listener.
+fn card_error(error: embedded_sdmmc::Error<SdCardError>) -> DropstickError {
+    use embedded_sdmmc::Error;
+    match error {
+        Error::DeviceError(SdCardError::CardNotFound | SdCardError::TimeoutCommand(_) | SdCardError::TimeoutACommand(_)) => DropstickError::NoCard,
+        Error::FormatError(_) | Error::NoSuchVolume | Error::Unsupported => DropstickError::NoFilesystem,
+        _ => DropstickError::CardFailed,
+    }
+}
+
+/// Asks the card for its status (CMD13), which only a card that is still there answers.
+fn card_responds(volume_mgr: &SdVolumeManager) -> bool {
+    let mut responds = false;
+    volume_mgr.device(|device| {
+        device.spi(|spi| {
+            // The answer comes within 8 bytes, MISO stays high until then
+            let mut response = [0xFF; 10];
+            let sent = spi.transaction(&mut [Operation::Write(&CMD13), Operation::TransferInPlace(&mut response)]);
+            responds = sent.is_ok() && response.iter().any(|&byte| byte & 0x80 == 0);
+        });
+        DummyTimesource::default()
+    });
+    responds
+}
+
+/// Tells cards apart, so playback only resumes on the card it stopped on.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+struct CardId {
+    size: u64,
+    /// `Playlist::fingerprint` of the tracks on it
+    tracks: u32,
+}
+
+/// Where playback was when the card failed or the power went out, to go on from there
+/// if the same card comes back.
+#[derive(Clone, Copy, defmt::Format)]
+struct ResumePoint {
+    card: CardId,
+    track: usize,
+    /// Bytes into the samples of the track
+    offset: u32,
+    shuffle: Option<ShuffleState>,
+}
+
+/// The open root directory of a mounted card.
+struct Card {
+    volume: RawVolume,
+    dir: RawDirectory,
+    id: CardId,
+    timer: Timer,
+    /// When `check_present` last asked the card
+    checked_at: u64,
+    /// The slots of the state file
+    state: StateSlots,
+    /// When `save_state` last saved, or found nothing changed
+    saved_at: u64,
+    /// The state and volume files, which stay open once they were written
+    state_file: Option<RawFile>,
+    volume_file: Option<RawFile>,
+}
+
+impl Card {
+    /// Makes sure the card is still there, asking it every `CARD_POLL_INTERVAL_US`.
+    /// Reading the card finds out on its own, this is for while it isn't.
+    fn check_present(&mut self, volume_mgr: &SdVolumeManager) -> Result<(), DropstickError> {
+        let now = self.timer.get_counter().ticks();
+        if now - self.checked_at < CARD_POLL_INTERVAL_US {
+            return Ok(());
+        }
+        self.checked_at = now;
+
+        if !card_responds(volume_mgr) {
+            warn!("The SD card was taken out!");
+            return Err(DropstickError::NoCard);
+        }
+        Ok(())
+    }
+
+    /// Where the state file says playback was, if it was on these tracks.
+    fn saved_resume_point(&self) -> Option<ResumePoint> {
+        let saved = self.state.saved().filter(|saved| saved.tracks == self.id.tracks)?;
+        Some(ResumePoint { card: self.id, track: saved.track as usize, offset: saved.offset, shuffle: saved.shuffle })
+    }
+
+    /// Saves that playback is `offset` bytes into the track at `index` of `playlist`, right away if `now`
+    /// and otherwise every `STATE_SAVE_INTERVAL_US`. Only writes the card if playback moved since the last save.
+    fn save_state<const N: usize>(&mut self, volume_mgr: &SdVolumeManager, playlist: &Playlist<N>, index: usize, offset: u32, now: bool) {
+        let time = self.timer.get_counter().ticks();
+        if !now && time - self.saved_at < STATE_SAVE_INTERVAL_US {
+            return;
+        }
+        self.saved_at = time;
+
+        let state = PlayState { tracks: self.id.tracks, track: index as u16, offset, shuffle: playlist.shuffle() };
+        let Some((slot, bytes)) = self.state.save(state) else {
+            return;
+        };
+        let length = (state::SLOT_COUNT as u32) * state::SLOT_STRIDE;
+        match write_in_place(volume_mgr, self.dir, &mut self.state_file, STATE_FILE, length, slot as u32 * state::SLOT_STRIDE, &bytes) {
+            Ok(()) => debug!("Saved {} in slot {}", state, slot),
+            Err(error) => error!("Failed to save where playback is: {}", error),
+        }
+    }
+
+    /// Writes `volume` to the card, replacing the one saved before.
+    fn save_volume(&mut self, volume_mgr: &SdVolumeManager, volume: Volume) {
+        let bytes = volume.to_bytes();
+        match write_in_place(volume_mgr, self.dir, &mut self.volume_file, VOLUME_FILE, bytes.len() as u32, 0, &bytes) {
+            Ok(()) => debug!("Saved volume step {}", volume.step()),
+            Err(error) => error!("Failed to save the volume: {}", error),
+        }
+    }
+}
+
+/// Initializes the card, opens its root directory and fills `playlist` with the WAV files in it
+/// and the folders below it, in order of their names or of the playlist file in the root.
+fn mount<const N: usize>(volume_mgr: &SdVolumeManager, clocks: &SystemClocks, timer: Timer, playlist: &mut Playlist<N>) -> Result<Card, DropstickError> {
+    let peripheral_freq = clocks.peripheral_freq();
+
+    // The card has to be initialized slowly, after that the clock can go faster
+    trace!("Init SD card controller...");
+    set_sd_baudrate(volume_mgr, peripheral_freq, SD_INIT_BAUDRATE);
+    let mut size = Ok(0);
+    volume_mgr.device(|device| {
+        device.mark_card_uninit();
+        size = device.num_bytes();
+        DummyTimesource::default()
+    });
+    let size = size.map_err(|error| {
+        error!("Can not initialize the SD card: {}", error);
+        card_error(embedded_sdmmc::Error::DeviceError(error))
+    })?;
+    set_sd_baudrate(volume_mgr, peripheral_freq, SD_BAUDRATE);
+    info!("Initialized SD card of {} bytes.", size);
+
+    trace!("Getting Volume 0...");
+    let volume = volume_mgr.open_raw_volume(VolumeIdx(0)).map_err(|error| {
+        error!("Can not open the volume: {}", error);
+        card_error(error)
+    })?;
+    if let Ok(Some(volume_name)) = volume_mgr.get_root_volume_label(volume) {
+        trace!("Card name is \"{}\"", str::from_utf8(volume_name.name()).unwrap_or("?"));
+    }
+
+    // After we have the volume (partition) of the drive we got to open the
+    // root directory:
+    let dir = match volume_mgr.open_root_dir(volume) {
+        Ok(dir) => dir,
+        Err(error) => {
+            error!("Can not open the root directory: {}", error);
+            let _ = volume_mgr.close_volume(volume);
+            return Err(card_error(error));
+        }
+    };
+    playlist.clear();
+    let mut lfn_storage = [0; LFN_BUFFER_SIZE];
+    let mut lfn_buffer = LfnBuffer::new(&mut lfn_storage);
+    let listed = list_folder(volume_mgr, dir, ROOT, playlist, &mut lfn_buffer).and_then(|()| {
+        playlist.sort();
+        match find_playlist_file(volume_mgr, dir, &mut lfn_buffer)? {
+            Some(name) => arrange(volume_mgr, dir, name, playlist),
+            None => Ok(()),
+        }
+    });
+
+    let id = CardId { size, tracks: playlist.fingerprint() };
+    let now = timer.get_counter().ticks();
+    let card = Card { volume, dir, id, timer, checked_at: now, state: load_state(volume_mgr, dir), saved_at: now, state_file: None, volume_file: None };
+
+    let result = match listed {
+        Err(error) => {
+            error!("Can not list the root directory: {}", error);
+            Err(card_error(error))
+        }
+        Ok(()) if playlist.is_empty() => Err(DropstickError::NoTracks),
+        Ok(()) => Ok(()),
+    };
+    if let Err(error) = result {
+        unmount(volume_mgr, card);
+        return Err(error);
+    }
+    info!("Found {} tracks", playlist.len());
+    Ok(card)
+}
+
+/// Adds the WAV files in `dir` to `playlist`, and then the ones in the folders in it, every folder
+/// after the one before in order of their names. `dir` has to be the folder added to `playlist` last.
+fn list_folder<const N: usize>(
+    volume_mgr: &SdVolumeManager,
+    dir: RawDirectory,
+    folder: usize,
+    playlist: &mut Playlist<N>,
+    lfn_buffer: &mut LfnBuffer,
+) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+    let mut subfolders: [Option<(LongName, TrackName)>; MAX_SUBFOLDERS] = [None; MAX_SUBFOLDERS];
+    let mut subfolder_count = 0;
+    volume_mgr.iterate_dir_lfn(dir, lfn_buffer, |entry, long_name| {
+        if entry.attributes.is_hidden() || entry.attributes.is_system() || entry.attributes.is_volume() {
+            return;
+        }
+        let Some(short_name) = TrackName::from_parts(entry.name.base_name(), entry.name.extension()) else {
+            return;
+        };
+        // Files without a long file name go by their short one
+        let name = long_name.map_or(short_name.into(), LongName::new);
+
+        if entry.attributes.is_directory() {
+            // Skip the "." and ".." every folder but the root has
+            if entry.name.base_name().starts_with(b".") {
+                return;
+            }
+            if subfolder_count < MAX_SUBFOLDERS {
+                subfolders[subfolder_count] = Some((name, short_name));
+                subfolder_count += 1;
+            } else {
+                warn!("Too many folders, skipping {}!", name.as_str());
+            }
+            return;
+        }
+        if entry.name.extension() != b"WAV" {
+            return;
+        }
+        if !playlist.push(name, short_name) {
+            warn!("No room for {} in the playlist!", name.as_str());
+        }
+    })?;
+
+    let subfolders = &mut subfolders[..subfolder_count];
+    subfolders.sort_unstable();
+    for &(name, short_name) in subfolders.iter().flatten() {
+        let Some(subfolder) = playlist.add_folder(name, short_name, folder) else {
+            warn!("Skipping folder {}, it is too deep or there are too many!", name.as_str());
+            continue;
+        };
+        let subdir = match volume_mgr.open_dir(dir, short_name.as_str()) {
+            Ok(subdir) => subdir,
+            // The card failing stops the mount, one folder that can't be opened doesn't
+            Err(error @ embedded_sdmmc::Error::DeviceError(_)) => return Err(error),
+            Err(error) => {
+                warn!("Skipping folder {}, it can not be opened: {}", name.as_str(), error);
+                continue;
+            }
+        };
+        let listed = list_folder(volume_mgr, subdir, subfolder, playlist, lfn_buffer);
+        let _ = volume_mgr.close_dir(subdir);
+        listed?;
+    }
+    Ok(())
+}
+
+/// Finds the short name of the playlist file in `dir`, if it has one.
+fn find_playlist_file(volume_mgr: &SdVolumeManager, dir: RawDirectory, lfn_buffer: &mut LfnBuffer) -> Result<Option<TrackName>, embedded_sdmmc::Error<SdCardError>> {
+    // Which of `PLAYLIST_FILES` it is, and its short name
+    let mut found: Option<(usize, TrackName)> = None;
+    volume_mgr.iterate_dir_lfn(dir, lfn_buffer, |entry, long_name| {
+        if entry.attributes.is_directory() {
+            return;
+        }
+        let Some(short_name) = TrackName::from_parts(entry.name.base_name(), entry.name.extension()) else {
+            return;
+        };
+        let name = long_name.unwrap_or(short_name.as_str());
+        let Some(preference) = PLAYLIST_FILES.iter().position(|file| file.eq_ignore_ascii_case(name)) else {
+            return;
+        };
+        let (used, ignored) = match found {
+            Some((other, _)) if other < preference => (other, preference),
+            Some((other, _)) => (preference, other),
+            None => (preference, preference),
+        };
+        if used != ignored {
+            warn!("Ignoring {}, {} sets the order!", PLAYLIST_FILES[ignored], PLAYLIST_FILES[used]);
+        }
+        if used == preference {
+            found = Some((preference, short_name));
+        }
+    })?;
+    Ok(found.map(|(_, short_name)| short_name))
+}
+
+/// Puts the tracks in `playlist` in the order of the playlist file `name` in `dir`,
+/// and drops the ones it doesn't have. Paths in it are from the root.
+fn arrange<const N: usize>(volume_mgr: &SdVolumeManager, dir: RawDirectory, name: TrackName, playlist: &mut Playlist<N>) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+    let file = volume_mgr.open_file_in_dir(dir, name.as_str(), Mode::ReadOnly)?;
+
+    let mut parser = M3uParser::new();
+    let mut placed = 0;
+    let mut place = |entry: m3u::Entry| match playlist.find_path(ROOT, entry.path) {
+        Some(index) if playlist.place(index) => {
+            trace!("Playlist entry {} ({})", entry.path, entry.title);
+            placed += 1;
+        }
+        Some(_) => warn!("No room to play {} again!", entry.path),
+        None => warn!("{} from the playlist is not on the card!", entry.path),
+    };
+    let mut buffer = [0; SD_BLOCK_SIZE];
+    let read = loop {
+        match volume_mgr.read(file, &mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(amount_read) => parser.feed(&buffer[..amount_read], &mut place),
+            Err(error) => break Err(error),
+        }
+    };
+    parser.finish(&mut place);
+    let _ = volume_mgr.close_file(file);
+    read?;
+
+    if parser.skipped() > 0 {
+        warn!("Skipped {} lines of {} that are too long or not UTF-8!", parser.skipped(), name.as_str());
+    }
+    if placed == 0 {
+        warn!("{} has no tracks on the card, playing all of them instead", name.as_str());
+        return Ok(());
+    }
+    info!("Playing the {} tracks in {}", placed, name.as_str());
+    playlist.keep_placed();
+    Ok(())
+}
+
+/// Opens `track`, going down the folders it is in from `root`.
+fn open_track<const N: usize>(volume_mgr: &SdVolumeManager, root: RawDirectory, playlist: &Playlist<N>, track: &Track) -> Result<RawFile, embedded_sdmmc::Error<SdCardError>> {
+    let mut dir = root;
+    for folder in playlist.path(track.folder as usize) {
+        let subdir = volume_mgr.open_dir(dir, folder.short_name.as_str());
+        if dir != root {
+            let _ = volume_mgr.close_dir(dir);
+        }
+        dir = subdir?;
+    }
+
+    // The file stays open on its own
+    let file = volume_mgr.open_file_in_dir(dir, track.short_name.as_str(), Mode::ReadOnly);
+    if dir != root {
+        let _ = volume_mgr.close_dir(dir);
+    }
+    file
+}
+
+/// A track that is open, and how it is played.
+#[derive(Clone, Copy)]
+struct OpenTrack {
+    /// Index in the playlist
+    index: usize,
+    file: RawFile,
+    header: WavHeader,
+    sample_format: PcmFormat,
+    output_rate: u32,
+    clock: ClockChoice,
+    /// Bytes of samples in the file, in whole frames
+    data_length: u32,
+}
+
+impl OpenTrack {
+    /// Whether core 0 can play these samples right after the ones of `before`, as they are.
+    fn plays_like(&self, before: &OpenTrack) -> bool {
+        self.sample_format == before.sample_format
+            && self.header.format.sample_rate == before.header.format.sample_rate
+            && self.output_rate == before.output_rate
+    }
+}
+
+/// Opens the track at `index` of `playlist` and finds out how to play it.
+/// Files that can't be played are `None`, only the card failing is an error.
+fn open_wav<const N: usize>(volume_mgr: &SdVolumeManager, root: RawDirectory, playlist: &Playlist<N>, index: usize) -> Result<Option<OpenTrack>, DropstickError> {
+    let Some(track) = playlist.tracks().get(index) else {
+        return Ok(None);
+    };
+    let name = track.name.as_str();
+    let file = match open_track(volume_mgr, root, playlist, track) {
+        Ok(file) => file,
+        Err(error @ embedded_sdmmc::Error::DeviceError(_)) => return Err(card_error(error)),
+        Err(error) => {
+            error!("Can not open {}: {}", name, error);
+            return Ok(None);
+        }
+    };
+
+    // Find out where the samples are, and whether we can play them at all
+    let opened = volume_mgr.file_length(file).map_err(WavError::Io).and_then(|file_length| {
+        let header = wav::parse_header(|offset, buffer| {
+            if offset >= file_length {
+                return Ok(0);
+            }
+            volume_mgr.file_seek_from_start(file, offset)?;
+            volume_mgr.read(file, buffer)
+        })?;
+        let sample_format = WAVStreamPlayer::supports(&header.format)?;
+        let sample_rate = header.format.sample_rate;
+        let (output_rate, clock) = OUTPUT_RATE.choose(sample_rate, &AUDIO_SYS_FREQS)
+            .filter(|&(output_rate, _)| Resampler::supports(sample_rate, output_rate))
+            .ok_or(WavError::UnsupportedSampleRate(sample_rate))?;
+        // A frame cut short at the end would put the frames of a track following it out of step
+        let data_length = header.data_length.min(file_length - header.data_offset);
+        let data_length = data_length - data_length % header.format.block_align.max(1) as u32;
+        Ok(OpenTrack { index, file, header, sample_format, output_rate, clock, data_length })
+    });
+
+    match opened {
+        Ok(track) => {
+            debug!("WAV format: {}", track.header.format);
+            Ok(Some(track))
+        }
+        Err(error) => {
+            let _ = volume_mgr.close_file(file);
+            match error {
+                WavError::Io(error @ embedded_sdmmc::Error::DeviceError(_)) => {
+                    error!("Can not read {}: {}", name, error);
+                    Err(card_error(error))
+                }
+                error => {
+                    error!("Can not play {}: {}", name, error);
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+/// The end of a track that is mixed into the start of the one after it.
+#[derive(Clone, Copy)]
+struct Tail {
+    file: RawFile,
+    mix: TailMix,
+}
+
+/// The end of a track that is still in the ring, in front of the samples of the one after it,
+/// or of the same track from A once its A-B loop went around.
+#[derive(Clone, Copy)]
+struct InRing {
+    /// Index in the playlist
+    index: usize,
+    /// Bytes into its samples right after the last ones in the ring
+    end: u32,
+}
+
+/// A track that plays on from the one before, without core 0 starting over.
+#[derive(Clone, Copy)]
+struct Following {
+    track: OpenTrack,
+    /// The end of the track before, while it still has to be mixed in
+    tail: Option<Tail>,
+    /// The track before, which plays until its end drained from the ring
+    before: InRing,
+}
+
+/// Opens the track the playlist moves on to after `track`, and moves there if core 0 can play it
+/// right after `track` without a gap. Otherwise the playlist stays where it is, core 0 has to start
+/// over for a track at another rate anyway.
+///
+/// The playlist is where reading goes on, `track` still plays until its end drained from the ring.
+///
+/// For a crossfade, the next track has to be another file with at least the `tail` bytes of `track`
+/// that are mixed into it.
+fn open_following<const N: usize>(volume_mgr: &SdVolumeManager, root: RawDirectory, playlist: &mut Playlist<N>, track: &OpenTrack, tail: u32, controls: &mut Controls) -> Result<Option<OpenTrack>, DropstickError> {
+    // The buttons may have changed what comes next
+    controls.apply(playlist);
+    let Some(index) = playlist.following() else {
+        return Ok(None);
+    };
+    // Repeating the track reads the file that is open already, it can't be opened twice
+    let next = match index == track.index {
+        true if tail > 0 => return Ok(None),
+        true => Some(*track),
+        false => open_wav(volume_mgr, root, playlist, index)?,
+    };
+
+    match next {
+        Some(next) if next.plays_like(track) && next.data_length >= tail => {
+            playlist.advance();
+            Ok(Some(next))
+        }
+        Some(next) => {
+            if next.file != track.file {
+                let _ = volume_mgr.close_file(next.file);
+            }
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Closes what `mount` opened. The card may be gone already, so this can't fail.
+fn unmount(volume_mgr: &SdVolumeManager, card: Card) {
+    for file in [card.state_file, card.volume_file].into_iter().flatten() {
+        let _ = volume_mgr.close_file(file);
+    }
+    let _ = volume_mgr.close_dir(card.dir);
+    let _ = volume_mgr.close_volume(card.volume);
+}
+
+/// Blinks `error` on the LED until `delay_us` passed, but at least once.
+fn signal_error(led: &mut StatusLed, timer: &mut Timer, error: DropstickError, delay_us: u64) {
+    let until = timer.get_counter().ticks() + delay_us;
+    loop {
+        for _ in 0..error.blinks() {
+            let _ = led.set_high();
+            timer.delay_us(BLINK_US);
+            let _ = led.set_low();
+            timer.delay_us(BLINK_US);
+        }
+        timer.delay_us(BLINK_PAUSE_US);
+
+        if timer.get_counter().ticks() >= until {
+            break;
+        }
+    }
+
+    // Presses while nothing could play shouldn't skip tracks once it can again
+    while access_inputs(|inputs| inputs.pop()).is_some() {}
+}
+
+/// Sends a command to core 0.
+fn send_command(inter_core_fifo: &mut SioFifo, command: Command) {
+    trace!("Command: {}", command);
+    command.encode(|word| inter_core_fifo.write_blocking(word));
+}
+
+/// Reads the next event from core 0, if there is one.
+fn try_receive_event(inter_core_fifo: &mut SioFifo) -> Option<Event> {
+    let first = inter_core_fifo.read()?;
+    match Event::decode(first, || inter_core_fifo.read_blocking()) {
+        Ok(event) => Some(event),
+        Err(error) => {
+            error!("Bad event from core 0: {}", error);
+            None
+        }
+    }
+}
+
+/// Logs the events that need no answer. Running out of samples is only worth a warning
+/// while there are still samples to send.
+fn log_event(event: Event, streaming: bool) {
+    match event {
+        Event::PositionReport { frame } => trace!("Core 0 is at frame {}", frame),
+        Event::Underrun if streaming => warn!("Core 0 ran out of samples!"),
+        Event::Underrun => trace!("Core 0 played the last samples"),
+        event => debug!("Unexpected event: {}", event),
+    }
+}
+
+/// Handles all events that arrived so far. Core 0 never waits for these to be read,
+/// but they do keep the FIFO free for the ones it does wait on.
+fn handle_events(inter_core_fifo: &mut SioFifo, streaming: bool) {
+    while let Some(event) = try_receive_event(inter_core_fifo) {
+        log_event(event, streaming);
+    }
+}
+
+/// Waits until core 0 sends `expected`, handling any other events on the way.
+fn await_event(inter_core_fifo: &mut SioFifo, expected: Event) {
+    loop {
+        match try_receive_event(inter_core_fifo) {
+            Some(event) if event == expected => return,
+            Some(event) => log_event(event, false),
+            None => cortex_m::asm::wfe(),
+        }
+    }
+}
+
+/// Where the buttons take the playlist.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Skip {
+    Previous,
+    Next,
+    /// Start the same track over
+    Restart,
+    /// The first track of the folder before
+    PreviousFolder,
+    /// The first track of the following folder
+    NextFolder,
+}
+
+/// What the player does for each button gesture.
+fn skip_for(event: InputEvent) -> Option<Skip> {
+    if event.shifted {
+        return None;
+    }
+    match (event.button, event.gesture) {
+        (Button::Btn1, Gesture::Press) => Some(Skip::Previous),
+        (Button::Btn2, Gesture::Press) => Some(Skip::Next),
+        (Button::Pause, Gesture::DoubleClick) => Some(Skip::Restart),
+        (Button::Btn1, Gesture::DoubleClick) => Some(Skip::PreviousFolder),
+        (Button::Btn2, Gesture::DoubleClick) => Some(Skip::NextFolder),
+        _ => None,
+    }
+}
+
+/// Moves `playlist` to where `skip` goes.
+fn skip_to<const N: usize>(playlist: &mut Playlist<N>, skip: Skip) {
+    match skip {
+        Skip::Previous => playlist.previous(),
+        Skip::Next => playlist.next(),
+        Skip::Restart => {}
+        Skip::PreviousFolder => playlist.previous_folder(),
+        Skip::NextFolder => playlist.next_folder(),
+    }
+}
+
+/// Playback settings the buttons change, which core 0 has to follow.
+struct Controls {
+    paused: bool,
+    /// What plays after a track ends
+    repeat: Repeat,
+    /// Play the tracks in a shuffled order
+    shuffle: bool,
+    volume: Volume,
+    /// When the volume last changed, if it hasn't been saved since
+    volume_changed_at: Option<u64>,
+    /// Jumps since a button started scanning, which makes the next ones go further
+    scan_jumps: u32,
+    /// Seconds scanned since `take_jump`, back when negative
+    jump_s: i32,
+    /// A point of an A-B loop was marked since `take_mark`
+    marked: bool,
+    timer: Timer,
+    /// Random bits to seed shuffled orders with
+    rosc: RingOscillator<Enabled>,
+}
+
+impl Controls {
+    /// Starts playing at `volume`, which core 0 is told about right away.
+    fn new(volume: Volume, timer: Timer, rosc: RingOscillator<Enabled>, inter_core_fifo: &mut SioFifo) -> Controls {
+        info!("Volume step {}", volume.step());
+        send_command(inter_core_fifo, Command::SetVolume { gain: volume.gain() });
+        Controls {
+            paused: false,
+            repeat: Repeat::Off,
+            shuffle: false,
+            volume,
+            volume_changed_at: None,
+            scan_jumps: 0,
+            jump_s: 0,
+            marked: false,
+            timer,
+            rosc,
+        }
+    }
+
+    /// Switches to the volume saved on a card.
+    fn restore_volume(&mut self, volume: Volume, inter_core_fifo: &mut SioFifo) {
+        info!("Volume step {}", volume.step());
+        send_command(inter_core_fifo, Command::SetVolume { gain: volume.gain() });
+        self.volume = volume;
+        self.volume_changed_at = None;
+    }
+
+    /// Handles the button gestures since the last call, and returns where to skip to if any did.
+    /// The volume they settled on is saved to `card`.
+    fn poll(&mut self, inter_core_fifo: &mut SioFifo, volume_mgr: &SdVolumeManager, card: &mut Card) -> Option<Skip> {
+        self.save_volume_when_settled(volume_mgr, card);
+        while let Some(event) = access_inputs(|inputs| inputs.pop()) {
+            trace!("Input: {}", event);
+            match (event.button, event.gesture) {
+                (Button::Pause, Gesture::Press) => {
+                    self.paused = !self.paused;
+                    send_command(inter_core_fifo, if self.paused { Command::Pause } else { Command::Resume });
+                }
+                (Button::Pause, Gesture::LongPress) => {
+                    self.repeat = self.repeat.next();
+                    info!("Repeat {}", self.repeat);
+                }
+                // With pause held, double clicking btn_1 marks A, then B, then stops looping between them
+                (Button::Btn1, Gesture::DoubleClick) if event.shifted => self.marked = true,
+                // With pause held, double clicking btn_2 shuffles from the next track on
+                (Button::Btn2, Gesture::DoubleClick) if event.shifted => {
+                    self.shuffle = !self.shuffle;
+                    info!("Shuffling: {}", self.shuffle);
+                }
+                // With pause held, btn_1 turns the volume down and btn_2 turns it up
+                (Button::Btn1 | Button::Btn2, Gesture::Press | Gesture::LongPress | Gesture::Repeat) if event.shifted => {
+                    let changed = match event.button {
+                        Button::Btn1 => self.volume.down(),
+                        _ => self.volume.up(),
+                    };
+                    if changed {
+                        debug!("Volume step {}", self.volume.step());
+                        send_command(inter_core_fifo, Command::SetVolume { gain: self.volume.gain() });
+                        self.volume_changed_at = Some(self.timer.get_counter().ticks());
+                    }
+                }
+                // Holding btn_1 scans back through the track, holding btn_2 scans forward
+                (Button::Btn1 | Button::Btn2, Gesture::LongPress | Gesture::Repeat) => {
+                    if event.gesture == Gesture::LongPress {
+                        self.scan_jumps = 0;
+                    }
+                    let speed = (self.scan_jumps / SCAN_JUMPS_PER_SPEED) as usize;
+                    let seconds = SCAN_SPEEDS[speed.min(SCAN_SPEEDS.len() - 1)] as i32;
+                    self.scan_jumps += 1;
+                    self.jump_s += if event.button == Button::Btn1 { -seconds } else { seconds };
+                }
+                _ => if let Some(skip) = skip_for(event) {
+                    return Some(skip);
+                },
+            }
+        }
+        None
+    }
+
+    /// Makes `playlist` follow the repeat mode and shuffle the buttons set, before moving in it.
+    /// Shuffling starts with a new seed, at the current track.
+    fn apply<const N: usize>(&mut self, playlist: &mut Playlist<N>) {
+        playlist.set_repeat(self.repeat);
+        if self.shuffle != playlist.shuffle().is_some() {
+            let state = self.shuffle.then(|| ShuffleState::new(self.seed(), playlist.index() as u16));
+            debug!("Shuffle {}", state);
+            playlist.set_shuffle(state);
+        }
+    }
+
+    /// A seed from the jitter of the ring oscillator, which differs every time.
+    fn seed(&self) -> u32 {
+        // Its bits aren't independent, so many of them are folded into every bit of the seed
+        (0..SEED_BITS).fold(0, |seed: u32, _| seed.rotate_left(5) ^ self.rosc.get_random_bit() as u32)
+    }
+
+    /// Seconds the buttons scanned forward, or back when negative, since the last call.
+    fn take_jump(&mut self) -> Option<i32> {
+        match core::mem::take(&mut self.jump_s) {
+            0 => None,
+            seconds => Some(seconds),
+        }
+    }
+
+    /// Whether a point of an A-B loop was marked since the last call.
+    fn take_mark(&mut self) -> bool {
+        core::mem::take(&mut self.marked)
+    }
+
+    /// Saves the volume to the card once it stopped changing.
+    fn save_volume_when_settled(&mut self, volume_mgr: &SdVolumeManager, card: &mut Card) {
+        let Some(changed_at) = self.volume_changed_at else {
+            return;
+        };
+        if self.timer.get_counter().ticks() - changed_at < VOLUME_SAVE_DELAY_US {
+            return;
+        }
+
+        self.volume_changed_at = None;
+        card.save_volume(volume_mgr, self.volume);
+    }
+}
+
+/// Reads the volume saved on the card, if there is one.
+fn load_volume(volume_mgr: &SdVolumeManager, dir: RawDirectory) -> Option<Volume> {
+    let file = volume_mgr.open_file_in_dir(dir, VOLUME_FILE, Mode::ReadOnly).ok()?;
+    let mut bytes = [0; 2];
+    let read = volume_mgr.read(file, &mut bytes);
+    let _ = volume_mgr.close_file(file);
+    Volume::from_bytes(&bytes[..read.ok()?], VOLUME_LIMIT)
+}
+
+
+/// Reads the slots of the state file, if there is one.
+fn load_state(volume_mgr: &SdVolumeManager, dir: RawDirectory) -> StateSlots {
+    let mut slots = StateSlots::new();
+    let Ok(file) = volume_mgr.open_file_in_dir(dir, STATE_FILE, Mode::ReadOnly) else {
+        return slots;
+    };
+    let length = volume_mgr.file_length(file).unwrap_or(0);
+    for slot in 0..state::SLOT_COUNT as u32 {
+        let position = slot * state::SLOT_STRIDE;
+        if position + state::SLOT_SIZE as u32 > length {
+            break;
+        }
+        let mut bytes = [0; state::SLOT_SIZE];
+        let read = volume_mgr.file_seek_from_start(file, position).and_then(|()| volume_mgr.read(file, &mut bytes));
+        if let Ok(amount_read) = read {
+            slots.read(&bytes[..amount_read]);
+        }
+    }
+    let _ = volume_mgr.close_file(file);
+    slots
+}
+
+/// Writes `bytes` at `position` of the file `name` in `dir`, leaving the rest of it as it is.
+///
+/// Growing a file, and closing one that was written, writes its directory entry and the FAT.
+/// So the file grows to `length` once, and then stays open in `file` until the card is unmounted,
+/// and later writes only touch the blocks they go to.
+fn write_in_place(
+    volume_mgr: &SdVolumeManager,
+    dir: RawDirectory,
+    file: &mut Option<RawFile>,
+    name: &str,
+    length: u32,
+    position: u32,
+    bytes: &[u8],
+) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+    let open = match *file {
+        Some(open) => open,
+        None => {
+            let open = volume_mgr.open_file_in_dir(dir, name, Mode::ReadWriteCreateOrAppend)?;
+            let grown = volume_mgr.file_length(open).and_then(|mut file_length| {
+                // Writes go to the end of the file until it is long enough
+                let zeros = [0; SD_BLOCK_SIZE];
+                while file_length < length {
+                    let amount = ((length - file_length) as usize).min(zeros.len());
+                    volume_mgr.write(open, &zeros[..amount])?;
+                    file_length += amount as u32;
+                }
+                // Only writes the entry if the file grew
+                volume_mgr.flush_file(open)
+            });
+            if let Err(error) = grown {
+                let _ = volume_mgr.close_file(open);
+                return Err(error);
+            }
+            *file = Some(open);
+            open
+        }
+    };
+    let written = volume_mgr.file_seek_from_start(open, position).and_then(|()| volume_mgr.write(open, bytes));
+    if written.is_err() {
+        // Opened again for the next write, in case the card came back
+        let _ = volume_mgr.close_file(open);
+        *file = None;
+    }
+    written
+}
+
 /* SHARED WITH INTERRUPT */
 
-struct InputPins {
+/// The buttons, which pull their pin low while pressed, and the timer to time them by.
+struct ButtonPins {
+    pause: gpio::Pin<Gpio6, FunctionSio<SioInput>, PullUp>,
     btn_1: gpio::Pin<Gpio7, FunctionSio<SioInput>, PullUp>,
     btn_2: gpio::Pin<Gpio8, FunctionSio<SioInput>, PullUp>,
+    timer: Timer,
 }
-#[derive(Default)]
-struct ButtonStates {
-    btn_1_pressed: bool,
-    btn_2_pressed: bool,
+
+impl InputSource for ButtonPins {
+    fn pressed(&mut self) -> [bool; Button::ALL.len()] {
+        [
+            self.pause.is_low().unwrap_or(false),
+            self.btn_1.is_low().unwrap_or(false),
+            self.btn_2.is_low().unwrap_or(false),
+        ]
+    }
+
+    fn now_us(&self) -> u64 {
+        self.timer.get_counter().ticks()
+    }
+}
+
+/// Everything the input interrupt needs to sample the buttons.
+struct InputPins {
+    buttons: ButtonPins,
+    /// Fires every `INPUT_POLL_INTERVAL`
+    alarm: Alarm0,
 }
 
 // The INPUT_PINS that are shared with the interrupt routine.
@@ -49,21 +987,21 @@ static INPUT_PINS: Mutex<UnsafeCell<OnceCell<InputPins>>> = Mutex::new(UnsafeCel
 
 /// Safely accesses global INPUT_PINS variable.
 /// WARNING: Uses critical section.
-fn access_input_pins<T: Fn(&mut InputPins, &mut ButtonStates) -> ()> (function: T) -> () {
+fn access_input_pins<T: FnOnce(&mut InputPins, &mut Inputs)> (function: T) {
     critical_section::with(|cs| {
         let input_pins_cell = INPUT_PINS.borrow(cs);
         let input_pins = unsafe {input_pins_cell.as_mut_unchecked()}.get_mut().unwrap();
 
-        let button_states_cell = BUTTON_STATES.borrow(cs);
-        let button_states = unsafe {button_states_cell.as_mut_unchecked()}.get_mut().unwrap();
+        let inputs_cell = INPUTS.borrow(cs);
+        let inputs = unsafe {inputs_cell.as_mut_unchecked()}.get_mut().unwrap();
 
-        function(input_pins, button_states);
+        function(input_pins, inputs);
     });
 }
 
 /// Safely set global INPUT_PINS variable.
 /// WARNING: Only call this *once*, else forced panic.
-fn set_input_pins(input_pins: InputPins) -> () {
+fn set_input_pins(input_pins: InputPins) {
     critical_section::with(|cs| {
         let input_pins_cell = INPUT_PINS.borrow(cs);
         let result = unsafe {input_pins_cell.as_mut_unchecked()}.set(input_pins);
@@ -73,46 +1011,40 @@ fn set_input_pins(input_pins: InputPins) -> () {
     });
 }
 
-// The INPUT_PINS that are shared with the interrupt routine.
-static BUTTON_STATES: Mutex<UnsafeCell<OnceCell<ButtonStates>>> = Mutex::new(UnsafeCell::new(OnceCell::new()));
+// The debounced INPUTS and their event queue, filled by the interrupt routine.
+static INPUTS: Mutex<UnsafeCell<OnceCell<Inputs>>> = Mutex::new(UnsafeCell::new(OnceCell::new()));
 
-/// Safely accesses global INPUT_PINS variable.
+/// Safely accesses global INPUTS variable.
 /// WARNING: Uses critical section.
-fn access_button_states<T: Fn(&mut ButtonStates) -> ()> (function: T) -> () {
+fn access_inputs<R, T: FnOnce(&mut Inputs) -> R> (function: T) -> R {
     critical_section::with(|cs| {
-        let button_states_cell = BUTTON_STATES.borrow(cs);
-        let button_states = unsafe {button_states_cell.as_mut_unchecked()}.get_mut().unwrap();
+        let inputs_cell = INPUTS.borrow(cs);
+        let inputs = unsafe {inputs_cell.as_mut_unchecked()}.get_mut().unwrap();
 
-        function(button_states);
-    });
+        function(inputs)
+    })
 }
 
-/// Safely set global INPUT_PINS variable.
+/// Safely set global INPUTS variable.
 /// WARNING: Only call this *once*, else forced panic.
-fn set_button_states(button_states: ButtonStates) -> () {
+fn set_inputs(inputs: Inputs) {
     critical_section::with(|cs| {
-        let button_states_cell = BUTTON_STATES.borrow(cs);
-        let result = unsafe {button_states_cell.as_mut_unchecked()}.set(button_states);
+        let inputs_cell = INPUTS.borrow(cs);
+        let result = unsafe {inputs_cell.as_mut_unchecked()}.set(inputs);
         if result.is_err() {
-            error!("Shared INPUT_PINS Mutex failed to set!");
+            error!("Shared INPUTS Mutex failed to set!");
         };
     });
 }
 
+/// Samples the buttons, and queues the gestures they complete.
 #[interrupt]
-fn IO_IRQ_BANK0() {
-    
-    access_input_pins(|pins, states| {
-        
-        if pins.btn_1.is_low().ok().unwrap() {
-            states.btn_1_pressed = true;
-        }
-        if pins.btn_2.is_low().ok().unwrap() {
-            states.btn_2_pressed = true;
-        }
-        
-        pins.btn_1.clear_interrupt(gpio::Interrupt::EdgeLow);
-        pins.btn_2.clear_interrupt(gpio::Interrupt::EdgeLow);
+fn TIMER_IRQ_0() {
+    access_input_pins(|pins, inputs| {
+        pins.alarm.clear_interrupt();
+        let _ = pins.alarm.schedule(INPUT_POLL_INTERVAL);
+
+        inputs.poll(&mut pins.buttons);
     });
 }
 
@@ -131,17 +1063,22 @@ pub fn init(psm: &mut PSM, ppb: &mut PPB, fifo: &mut SioFifo, function: impl FnO
     trace!("Successfully started second core!");
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn main(
     resets: &mut RESETS,
     spi0: SPI0,
-    clocks: ClocksManager,
+    mut clocks: SystemClocks,
     gpio2: gpio::Pin<Gpio2, gpio::FunctionNull, gpio::PullDown>,
     gpio3: gpio::Pin<Gpio3, gpio::FunctionNull, gpio::PullDown>,
     gpio4: gpio::Pin<Gpio4, gpio::FunctionNull, gpio::PullDown>,
     gpio5: gpio::Pin<Gpio5, gpio::FunctionNull, gpio::PullDown>,
-    timer: Timer,
+    mut timer: Timer,
+    gpio25: gpio::Pin<Gpio25, gpio::FunctionNull, gpio::PullDown>,
+    gpio6: gpio::Pin<Gpio6, gpio::FunctionNull, gpio::PullDown>,
     gpio7: gpio::Pin<Gpio7, gpio::FunctionNull, gpio::PullDown>,
     gpio8: gpio::Pin<Gpio8, gpio::FunctionNull, gpio::PullDown>,
+    rosc: ROSC,
+    mut sample_producer: Producer<'static, SAMPLE_RING_SIZE>,
 ) -> ! {
     info!("Core 1 says hello! :3c");
 
@@ -165,8 +1102,8 @@ pub fn main(
     // Exchange the uninitialised SPI driver for an initialised one
     let spi = spi.init(
         resets,
-        clocks.peripheral_clock.freq(),
-        400_u32.kHz(), // card initialization happens at low baud rate
+        clocks.peripheral_freq(),
+        SD_INIT_BAUDRATE, // card initialization happens at low baud rate
         embedded_hal::spi::MODE_0,
     );
 
@@ -176,82 +1113,417 @@ pub fn main(
     let sdcard = SdCard::new(spi_device, timer);
     let volume_mgr = VolumeManager::new(sdcard, DummyTimesource::default());
 
-    trace!("Init SD card controller...");
-    
-    // Now that the card is initialized, clock can go faster
-    volume_mgr.device(|device| {
-        device.spi(|spi| {
-            spi.bus_mut().set_baudrate(clocks.peripheral_clock.freq(), 16_u32.MHz());
-            DummyTimesource::default()
-        })
+    // Sample the buttons from a timer interrupt, which debounces them
+    let mut alarm = timer.alarm_0().unwrap();
+    let _ = alarm.schedule(INPUT_POLL_INTERVAL);
+    alarm.enable_interrupt();
+    set_inputs(Inputs::new());
+    set_input_pins(InputPins {
+        buttons: ButtonPins {
+            pause: gpio6.into_pull_up_input(),
+            btn_1: gpio7.into_pull_up_input(),
+            btn_2: gpio8.into_pull_up_input(),
+            timer,
+        },
+        alarm,
     });
-    info!("Initialized SD card.");
-    
-    trace!("Getting Volume 0...");
-    let volume = volume_mgr.open_raw_volume(VolumeIdx(0)).expect("Failed!");
-
-    let volume_name = volume_mgr.get_root_volume_label(volume).expect("Failed!").expect("Failed!");
-    let name = str::from_utf8(volume_name.name()).expect("Failed!");
-    trace!("Card name is \"{}\"", name);
-    
-    //Button Interrupt Setup
-    let gpio7 = gpio7.into_pull_up_input();
-    let gpio8 = gpio8.into_pull_up_input();
-    gpio7.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
-    gpio8.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
-    set_button_states(ButtonStates::default());
-    set_input_pins(InputPins { btn_1: gpio7, btn_2: gpio8 });
-    unsafe {pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0)};
+    unsafe {pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0)};
 
-    // After we have the volume (partition) of the drive we got to open the
-    // root directory:
-    let dir = volume_mgr.open_root_dir(volume).expect("Failed!");
-
-    // This shows how to iterate through the directory and how
-    // to get the file names (and print them in hope they are UTF-8 compatible):
-    volume_mgr.iterate_dir(dir, |file| {
-        debug!(
-            "/{}.{}",
-            core::str::from_utf8(file.name.base_name()).unwrap(),
-            core::str::from_utf8(file.name.extension()).unwrap()
-        );
-    }).unwrap();
-
-    // Next we going to read a file from the SD card:
-    if let Ok(file) = volume_mgr.open_file_in_dir(dir, "Daisies.wav", Mode::ReadOnly) {
-        let mut read_bytes: usize = 0;
-        loop {
-            let mut buffer = [0u8; 128];
-            let amount_read = volume_mgr.read(file, &mut buffer).unwrap();
-            read_bytes += amount_read;
-    
-            for i in 0..amount_read {
-                inter_core_fifo.write_blocking(buffer[i] as u32);
+    let mut led = gpio25.into_push_pull_output();
+    let playlist = singleton!(: Playlist<MAX_TRACKS> = Playlist::new()).unwrap();
+    let rosc = RingOscillator::new(rosc).initialize();
+    let mut controls = Controls::new(Volume::new(DEFAULT_VOLUME, VOLUME_LIMIT), timer, rosc, &mut inter_core_fifo);
+    let mut backoff = Backoff::new(RETRY_FIRST_US, RETRY_MAX_US);
+    let mut resume = None;
+
+    // Play the card until it fails or is taken out, then wait for one that works
+    loop {
+        let error = match mount(&volume_mgr, &clocks, timer, playlist) {
+            Ok(mut card) => {
+                if let Some(volume) = load_volume(&volume_mgr, card.dir) {
+                    controls.restore_volume(volume, &mut inter_core_fifo);
+                }
+                let Err(error) = play_card(&volume_mgr, &mut card, playlist, &mut clocks, resets, &mut inter_core_fifo, &mut sample_producer, &mut controls, &mut backoff, &mut resume);
+                unmount(&volume_mgr, card);
+                error
             }
+            Err(error) => error,
+        };
 
-            if amount_read < buffer.len() {
-                break;
+        let delay_us = backoff.next_delay();
+        error!("Can not play: {}, trying again in {}ms", error, delay_us / 1_000);
+        // The error may have come halfway through a track, silence it before ramping down
+        send_command(&mut inter_core_fifo, Command::Stop);
+        await_event(&mut inter_core_fifo, Event::Flushed);
+        send_command(&mut inter_core_fifo, Command::Idle);
+        signal_error(&mut led, &mut timer, error, delay_us);
+    }
+}
+
+/// Plays the tracks in `playlist` from `card`, until the card fails or is taken out.
+/// Starts at `resume` if it is the card that was played last, and leaves where it stopped there.
+/// Otherwise starts where the state file on the card says playback was.
+#[allow(clippy::too_many_arguments)]
+fn play_card<const N: usize>(
+    volume_mgr: &SdVolumeManager,
+    card: &mut Card,
+    playlist: &mut Playlist<N>,
+    clocks: &mut SystemClocks,
+    resets: &mut RESETS,
+    inter_core_fifo: &mut SioFifo,
+    sample_producer: &mut Producer<'static, SAMPLE_RING_SIZE>,
+    controls: &mut Controls,
+    backoff: &mut Backoff,
+    resume: &mut Option<ResumePoint>,
+) -> Result<!, DropstickError> {
+    let mut offset = 0;
+    // Where the card was taken out is more recent than the last save
+    let point = resume.take().filter(|point| point.card == card.id).or_else(|| card.saved_resume_point());
+    if let Some(point) = point {
+        if playlist.select(point.track) {
+            info!("Resuming track {} at byte {}", point.track, point.offset);
+            offset = point.offset;
+            controls.shuffle = point.shuffle.is_some();
+            playlist.set_shuffle(point.shuffle);
+        }
+    }
+    controls.apply(playlist);
+    // The next track when it is open already, and follows the one before in the ring
+    let mut followed = None;
+
+    loop {
+        // Mounting only succeeds with tracks to play
+        let Some(&track) = playlist.current() else {
+            return Err(DropstickError::NoTracks);
+        };
+
+        let folder = playlist.folder(track.folder as usize).map_or("", |folder| folder.name.as_str());
+        info!("Playing {} in folder \"{}\"", track.name.as_str(), folder);
+        let mut playing = playlist.index();
+        let ended = match play_track(volume_mgr, card, playlist, followed.take(), &mut offset, &mut playing, clocks, resets, inter_core_fifo, sample_producer, controls) {
+            Ok(ended) => ended,
+            Err(error) => {
+                *resume = Some(ResumePoint { card: card.id, track: playing, offset, shuffle: playlist.shuffle() });
+                return Err(error);
             }
+        };
+        offset = 0;
+
+        // The card works, so the next time it fails it is tried again quickly
+        backoff.reset();
 
-            access_button_states(|states| {
-                if states.btn_1_pressed {
-                    info!("Button 1 pressed!");
-                    states.btn_1_pressed = false;
+        controls.apply(playlist);
+        match ended {
+            // The playlist is on the next track already, which core 0 plays on into
+            Ended::Followed(next) => followed = Some(next),
+            Ended::Skipped(skip) => skip_to(playlist, skip),
+            Ended::Finished if playlist.advance() => {}
+            Ended::Finished => {
+                // Wait at the end of the playlist until a button picks where to go on
+                info!("Played all tracks");
+                send_command(inter_core_fifo, Command::Idle);
+                let skip = loop {
+                    handle_events(inter_core_fifo, false);
+                    card.check_present(volume_mgr)?;
+                    if let Some(skip) = controls.poll(inter_core_fifo, volume_mgr, card) {
+                        break skip;
+                    }
+                    cortex_m::asm::wfe();
+                };
+                controls.apply(playlist);
+                skip_to(playlist, skip);
+            }
+        }
+    }
+}
+
+/// How a track stopped playing.
+enum Ended {
+    /// A button skipped it
+    Skipped(Skip),
+    /// It played to the end, or couldn't be played, the playlist is still on it
+    Finished,
+    /// It played to the end, or up to a crossfade, and the playlist moved on to the next track.
+    /// That track is open, and its samples go into the ring right behind the last ones of this track.
+    Followed(Following),
+}
+
+/// Where scanning `seconds` forward, or back when negative, from `from` bytes into the samples goes,
+/// staying within their `data_length`.
+fn scan_target(header: &WavHeader, data_length: u32, from: u32, seconds: i32) -> u32 {
+    let block_align = header.format.block_align;
+    let distance = seconds.unsigned_abs().saturating_mul(header.format.sample_rate * block_align as u32);
+    let to = match seconds {
+        0.. => from.saturating_add(distance).min(data_length),
+        _ => from.saturating_sub(distance),
+    };
+    state::align_offset(to, block_align)
+}
+
+/// Moves `file` to `to` bytes into its samples. Core 0 drops what it has from before,
+/// so none of it plays after the jump.
+fn jump(volume_mgr: &SdVolumeManager, file: RawFile, header: &WavHeader, inter_core_fifo: &mut SioFifo, to: u32) -> Result<(), embedded_sdmmc::Error<SdCardError>> {
+    trace!("Jumping to byte {}", to);
+    send_command(inter_core_fifo, Command::Seek { frame: to / header.format.block_align as u32 });
+    await_event(inter_core_fifo, Event::Flushed);
+    volume_mgr.file_seek_from_start(file, header.data_offset + to)
+}
+
+/// Closes the file of `tail`, if there still is one.
+fn end_tail(volume_mgr: &SdVolumeManager, tail: &mut Option<Tail>) {
+    if let Some(tail) = tail.take() {
+        let _ = volume_mgr.close_file(tail.file);
+    }
+}
+
+/// The index of the track core 0 plays, and how many bytes into its samples it is, of the `read_bytes`
+/// of the track at `index` read from `start` on. Whatever is still in the ring hasn't been played,
+/// which is the end of the track `before` it, or of the section before B, until that drained.
+fn played(index: usize, start: u32, read_bytes: usize, before: Option<InRing>, sample_producer: &Producer<'static, SAMPLE_RING_SIZE>) -> (usize, u32) {
+    let unplayed = SAMPLE_RING_SIZE - sample_producer.free();
+    match (read_bytes.checked_sub(unplayed), before) {
+        (None, Some(before)) => (before.index, before.end.saturating_sub((unplayed - read_bytes) as u32)),
+        (played, _) => (index, start + played.unwrap_or(0) as u32),
+    }
+}
+
+/// Bytes of `track` core 0 took out of the ring but hasn't played yet, which are in its player's buffer
+/// and at most both DMA halves. What was heard is about this much behind `played`.
+fn in_core0(track: &OpenTrack) -> u32 {
+    let format = &track.header.format;
+    let dma_frames = (2 * HALF_BUFFER_FRAMES) as u64 * format.sample_rate as u64 / track.output_rate as u64;
+    PLAYER_BUFFER_SIZE as u32 + dma_frames as u32 * format.block_align as u32
+}
+
+/// Plays `track` of `playlist` from `card`, or the current track when it isn't open yet, until it
+/// ends or a button skips it. Files that can't be played are skipped, only the card failing is an error.
+///
+/// Starts `offset` bytes into the samples, rounded down to a block, and leaves how far playback got there,
+/// with the index of the track that got there in `playing`. That is the track before while its end is
+/// still in the ring. Saves how far that is to the state file while playing, and when paused.
+///
+/// Once both points of an A-B loop are marked, reading goes back to A whenever it reaches B, so core 0
+/// gets the frame at A right after the one before B, and the section loops without a gap.
+///
+/// A `track` that `Ended::Followed` the one before is already behind it in the ring, core 0 plays on
+/// into it without starting over. The end of the track before is mixed into its start while there is
+/// a `Tail` left of it, and `CROSSFADE_S` before its own end the next track is opened to do the same.
+#[allow(clippy::too_many_arguments)]
+fn play_track<const N: usize>(
+    volume_mgr: &SdVolumeManager,
+    card: &mut Card,
+    playlist: &mut Playlist<N>,
+    following: Option<Following>,
+    offset: &mut u32,
+    playing: &mut usize,
+    clocks: &mut SystemClocks,
+    resets: &mut RESETS,
+    inter_core_fifo: &mut SioFifo,
+    sample_producer: &mut Producer<'static, SAMPLE_RING_SIZE>,
+    controls: &mut Controls,
+) -> Result<Ended, DropstickError> {
+    let (track, mut tail, mut before) = match following {
+        Some(Following { track, tail, before }) => (track, tail, Some(before)),
+        None => match open_wav(volume_mgr, card.dir, playlist, playlist.index())? {
+            Some(track) => (track, None, None),
+            None => return Ok(Ended::Finished),
+        },
+    };
+    let OpenTrack { file, header, data_length, .. } = track;
+    // Scanning or marking before this track started doesn't apply to it
+    controls.take_jump();
+    controls.take_mark();
+    let mut ab = AbLoop::Off;
+
+    if following.is_none() {
+        // Tell core 0 what's coming, and wait for it to stop its output
+        send_command(inter_core_fifo, Command::TrackStart {
+            format: track.sample_format,
+            sample_rate: header.format.sample_rate,
+            output_rate: track.output_rate,
+        });
+        await_event(inter_core_fifo, Event::OutputStopped);
+
+        // Switch to the system clock that best divides into the output rate
+        if track.clock.sys_freq != clocks.system_freq().to_Hz() {
+            let pll_sys_cfg = AUDIO_PLL_SYS_CONFIGS.into_iter().nth(track.clock.clock_index).unwrap();
+            match clocks.set_pll_sys(pll_sys_cfg, resets) {
+                Ok(()) => info!("System clock is now {}Hz", clocks.system_freq().to_Hz()),
+                Err(_) => error!("Failed to switch system clock!"),
+            }
+            set_sd_baudrate(volume_mgr, clocks.peripheral_freq(), SD_BAUDRATE);
+        }
+        send_command(inter_core_fifo, Command::SystemClock { sys_freq: clocks.system_freq().to_Hz() });
+    }
+
+    // Start further in when resuming
+    let block_align = header.format.block_align as u32;
+    let mut start = state::align_offset((*offset).min(data_length), header.format.block_align);
+    let start_frame = start / block_align;
+    if start_frame > 0 && following.is_none() {
+        send_command(inter_core_fifo, Command::Seek { frame: start_frame });
+        await_event(inter_core_fifo, Event::Flushed);
+    }
+
+    // Only stream the samples, so the header doesn't play as clicks
+    let mut result = volume_mgr.file_seek_from_start(file, header.data_offset + start).map_err(card_error);
+    let mut skip = None;
+    let mut followed = None;
+    // Bytes before the end where the next track starts to be mixed in
+    let crossfade = state::align_offset((CROSSFADE_S * header.format.sample_rate * block_align).min(data_length / 2), header.format.block_align);
+    let mut crossfade_tried = false;
+    // Next byte of the samples to read
+    let mut position = start;
+    let mut read_bytes: usize = 0;
+    while result.is_ok() {
+        // Start mixing the rest of this track into the next one, which goes on from here,
+        // once the track before is mixed in all the way
+        if crossfade > 0 && !crossfade_tried && tail.is_none() && ab.section().is_none() && data_length - position <= crossfade {
+            crossfade_tried = true;
+            let left = data_length - position;
+            match open_following(volume_mgr, card.dir, playlist, &track, left, controls) {
+                Ok(Some(next)) => {
+                    debug!("Crossfading the last {} bytes", left);
+                    let before = InRing { index: track.index, end: position };
+                    followed = Some(Following { track: next, tail: Some(Tail { file, mix: TailMix::new(track.sample_format, left) }), before });
+                    break;
                 }
-                if states.btn_2_pressed {
-                    info!("Button 2 pressed!");
-                    states.btn_2_pressed = false;
+                Ok(None) => {}
+                Err(error) => {
+                    result = Err(error);
+                    break;
                 }
-            });
+            }
+        }
 
+        // Go around the A-B loop without telling core 0, the frames just follow on
+        if let Some((a, _)) = ab.section().filter(|&(_, b)| position >= b) {
+            if let Err(error) = volume_mgr.file_seek_from_start(file, header.data_offset + a) {
+                result = Err(card_error(error));
+                break;
+            }
+            trace!("Looping back to byte {}", a);
+            // What the ring has from before B still plays first
+            before = Some(InRing { index: track.index, end: position });
+            (start, position, read_bytes) = (a, a, 0);
+            end_tail(volume_mgr, &mut tail);
+        }
+        let end = ab.section().map_or(data_length, |(_, b)| b);
+        if position >= end {
+            break;
         }
 
-        volume_mgr.close_file(file).unwrap();
+        // Whole frames, so a crossfade mixes all of them
+        let mut buffer = [0u8; SD_BLOCK_SIZE];
+        let wanted = ((end - position) as usize).min(buffer.len() - buffer.len() % block_align as usize);
+        let amount_read = match volume_mgr.read(file, &mut buffer[..wanted]) {
+            Ok(amount_read) => amount_read,
+            Err(error) => {
+                error!("Can not read {}: {}", playlist.tracks()[track.index].name.as_str(), error);
+                result = Err(card_error(error));
+                break;
+            }
+        };
+        position += amount_read as u32;
 
-        info!("Read {} bytes :3", read_bytes);
+        // Mix in what is left of the track before
+        if let Some(Tail { file, mix }) = tail.as_mut() {
+            if let Err(error) = mix.mix(&mut buffer[..amount_read], |buffer| volume_mgr.read(*file, buffer)) {
+                result = Err(card_error(error));
+                break;
+            }
+            if mix.is_done() {
+                end_tail(volume_mgr, &mut tail);
+            }
+        }
+
+        // Hand the block to core 0, sleeping while the ring is full.
+        // Core 0 sends an event whenever it took something out.
+        let mut written = sample_producer.write(&buffer[..amount_read]);
+        let mut jump_s = None;
+        while written < amount_read && skip.is_none() && jump_s.is_none() && result.is_ok() {
+            handle_events(inter_core_fifo, true);
+            cortex_m::asm::wfe();
+            written += sample_producer.write(&buffer[written..amount_read]);
+            skip = controls.poll(inter_core_fifo, volume_mgr, card);
+            jump_s = controls.take_jump();
+            // The ring stays full while paused, the card may be taken out meanwhile
+            result = card.check_present(volume_mgr);
+            let (index, played) = played(track.index, start, read_bytes, before, sample_producer);
+            card.save_state(volume_mgr, playlist, index, state::align_offset(played, header.format.block_align), controls.paused);
+        }
+        // Only what made it into the ring is on its way to be played
+        read_bytes += written;
+        handle_events(inter_core_fifo, true);
+
+        if amount_read < wanted || skip.is_some() || result.is_err() {
+            break;
+        }
+        skip = controls.poll(inter_core_fifo, volume_mgr, card);
+        let (index, played) = played(track.index, start, read_bytes, before, sample_producer);
+        card.save_state(volume_mgr, playlist, index, state::align_offset(played, header.format.block_align), controls.paused);
+        // Jumps go from this track, even while the end of the one before still plays
+        let played = if index == track.index { played } else { start };
+
+        // Go on reading from where the buttons scanned to, or from A once B is marked,
+        // since the ring already holds what comes after B
+        let mut to = jump_s.or_else(|| controls.take_jump()).map(|seconds| scan_target(&header, data_length, played, seconds));
+        if controls.take_mark() {
+            // Loop what was heard when the button was pressed, not what core 0 was given
+            let heard = played.saturating_sub(in_core0(&track));
+            ab.mark(heard - heard % block_align);
+            info!("A-B loop {}", ab);
+            to = ab.section().map(|(a, _)| a).or(to);
+        }
+        if let Some(to) = to {
+            end_tail(volume_mgr, &mut tail);
+            match jump(volume_mgr, file, &header, inter_core_fifo, to) {
+                // Core 0 dropped what the ring had of the track before too
+                Ok(()) => (start, position, read_bytes, before) = (to, to, 0, None),
+                Err(error) => result = Err(card_error(error)),
+            }
+        }
+    }
+
+    info!("Read {} bytes :3", read_bytes);
+    // A skip or jump drops what is left of the track before
+    end_tail(volume_mgr, &mut tail);
+
+    // Once all samples are in the ring, the next track can go right behind them
+    if position >= data_length && followed.is_none() && skip.is_none() && result.is_ok() {
+        match open_following(volume_mgr, card.dir, playlist, &track, 0, controls) {
+            Ok(next) => followed = next.map(|next| Following { track: next, tail: None, before: InRing { index: track.index, end: position } }),
+            Err(error) => result = Err(error),
+        }
     }
 
-    volume_mgr.free();
+    // Let core 0 play what's left in the ring, unless we're skipping it, the card failed
+    // or the next track follows it
+    while sample_producer.free() < SAMPLE_RING_SIZE && followed.is_none() && skip.is_none() && result.is_ok() {
+        handle_events(inter_core_fifo, false);
+        cortex_m::asm::wfe();
+        skip = controls.poll(inter_core_fifo, volume_mgr, card);
+        result = card.check_present(volume_mgr);
+        let (index, played) = played(track.index, start, read_bytes, before, sample_producer);
+        card.save_state(volume_mgr, playlist, index, state::align_offset(played, header.format.block_align), controls.paused);
+    }
+
+    (*playing, *offset) = played(track.index, start, read_bytes, before, sample_producer);
 
-    loop {}
+    // Silence core 0 until the next track starts
+    if followed.is_none() {
+        send_command(inter_core_fifo, Command::Stop);
+        await_event(inter_core_fifo, Event::Flushed);
+    }
+
+    let closed = match followed {
+        // Repeating the track reads the same file again, and a crossfade reads on to its end
+        Some(next) if next.track.file == file || next.tail.is_some() => Ok(()),
+        _ => volume_mgr.close_file(file).map_err(card_error),
+    };
+    result.and(closed).map(|()| match (followed, skip) {
+        (Some(next), _) => Ended::Followed(next),
+        (None, Some(skip)) => Ended::Skipped(skip),
+        (None, None) => Ended::Finished,
+    })
 }
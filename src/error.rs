@@ -0,0 +1,91 @@
+//! What keeps the player from playing, and how it waits for that to be fixed.
+//!
+//! None of these halt the player. It tells the listener what is wrong with a blink pattern,
+//! and tries again after a `Backoff` delay, so it carries on once a card is inserted.
+
+/// Why the player can't play anything right now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DropstickError {
+    /// There is no card, or it doesn't answer
+    NoCard,
+    /// The card answered, but reading it failed
+    CardFailed,
+    /// The card has no FAT volume on it
+    NoFilesystem,
+    /// The card has no files we can play
+    NoTracks,
+}
+
+impl DropstickError {
+    /// How many times the LED blinks before each pause, so the errors can be told apart.
+    pub fn blinks(&self) -> u8 {
+        match self {
+            DropstickError::NoCard => 1,
+            DropstickError::CardFailed => 2,
+            DropstickError::NoFilesystem => 3,
+            DropstickError::NoTracks => 4,
+        }
+    }
+}
+
+/// Delays between retries, doubling after every failed one up to a limit.
+///
+/// A card that was just inserted is found quickly, while a card that stays missing
+/// isn't woken up more often than it needs to be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    first_us: u64,
+    max_us: u64,
+    /// Delay before the next retry
+    next_us: u64,
+}
+
+impl Backoff {
+    /// Starts at `first_us`, never waiting longer than `max_us`.
+    pub const fn new(first_us: u64, max_us: u64) -> Backoff {
+        Backoff { first_us, max_us, next_us: first_us }
+    }
+
+    /// Returns how long to wait before the next retry, and makes the one after it longer.
+    pub fn next_delay(&mut self) -> u64 {
+        let delay = self.next_us;
+        self.next_us = delay.saturating_mul(2).min(self.max_us);
+        delay
+    }
+
+    /// Starts over at the first delay, after a retry worked.
+    pub fn reset(&mut self) {
+        self.next_us = self.first_us;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_the_limit() {
+        let mut backoff = Backoff::new(250, 1_500);
+        let delays: [u64; 5] = core::array::from_fn(|_| backoff.next_delay());
+        assert_eq!(delays, [250, 500, 1_000, 1_500, 1_500]);
+    }
+
+    #[test]
+    fn starts_over_after_a_reset() {
+        let mut backoff = Backoff::new(250, 1_500);
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), 250);
+    }
+
+    #[test]
+    fn errors_blink_differently() {
+        let errors = [DropstickError::NoCard, DropstickError::CardFailed, DropstickError::NoFilesystem, DropstickError::NoTracks];
+        for (i, error) in errors.iter().enumerate() {
+            assert!(error.blinks() > 0);
+            assert!(errors[i + 1..].iter().all(|other| other.blinks() != error.blinks()));
+        }
+    }
+}
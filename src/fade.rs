@@ -0,0 +1,337 @@
+//! Fading the sound in and out, so pausing, stopping and seeking don't click, and crossfades that
+//! mix the end of one track into the start of the next. Ramps do the same for the duty values the
+//! output rests at, which thump through the filter and amplifier when they jump.
+//!
+//! Gains are Q15, like `protocol::UNITY_GAIN`.
+
+use crate::{pcm::PcmFormat, protocol::UNITY_GAIN};
+
+/// Unity gain with 16 more bits below it, so long fades still move a little every frame
+const FULL: u32 = (UNITY_GAIN as u32) << 16;
+
+/// A gain that ramps between silence and unity in a straight line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Fade {
+    /// The gain, in units of `FULL`
+    level: u32,
+    /// How much `level` moves every frame
+    rate: u32,
+    rising: bool,
+}
+
+impl Fade {
+    /// Starts at unity, with fades that happen right away.
+    pub const fn new() -> Fade {
+        Fade { level: FULL, rate: FULL, rising: true }
+    }
+
+    /// Makes a fade from silence to unity, or back, take `frames`. With 0 they happen right away.
+    pub fn set_length(&mut self, frames: u32) {
+        self.rate = FULL / frames.max(1);
+    }
+
+    /// Jumps to silence, where the next `fade_in` starts from.
+    pub fn silence(&mut self) {
+        self.level = 0;
+    }
+
+    pub fn fade_in(&mut self) {
+        self.rising = true;
+        if self.rate == FULL {
+            self.level = FULL;
+        }
+    }
+
+    pub fn fade_out(&mut self) {
+        self.rising = false;
+        if self.rate == FULL {
+            self.level = 0;
+        }
+    }
+
+    /// Whether the gain is all the way down.
+    pub fn is_silent(&self) -> bool {
+        self.level == 0
+    }
+
+    /// Whether the gain is all the way up.
+    pub fn is_full(&self) -> bool {
+        self.level == FULL
+    }
+
+    /// The gain for the next frame, without taking the fade further.
+    pub fn gain(&self) -> u16 {
+        (self.level >> 16) as u16
+    }
+
+    /// The gain for the next frame, which takes the fade a frame further.
+    pub fn next_gain(&mut self) -> u16 {
+        let gain = self.gain();
+        self.level = match self.rising {
+            true => self.level.saturating_add(self.rate).min(FULL),
+            false => self.level.saturating_sub(self.rate),
+        };
+        gain
+    }
+}
+
+impl Default for Fade {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Duty values that go over to other ones in a straight line, so the output doesn't jump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Ramp {
+    from: (u16, u16),
+    to: (u16, u16),
+    /// How far from `from` to `to` it is
+    fade: Fade,
+}
+
+impl Ramp {
+    /// Stays at `duty`.
+    pub const fn new(duty: (u16, u16)) -> Ramp {
+        Ramp { from: duty, to: duty, fade: Fade::new() }
+    }
+
+    /// Goes over from `from` to `to` in `frames`, or right away with 0.
+    pub fn start(&mut self, from: (u16, u16), to: (u16, u16), frames: u32) {
+        self.from = from;
+        self.to = to;
+        self.fade.set_length(frames);
+        self.fade.silence();
+        self.fade.fade_in();
+    }
+
+    /// Where it goes.
+    pub fn target(&self) -> (u16, u16) {
+        self.to
+    }
+
+    /// Whether it got where it goes.
+    pub fn is_done(&self) -> bool {
+        self.fade.is_full()
+    }
+
+    /// The duty values for the next frame, without taking the ramp further.
+    pub fn value(&self) -> (u16, u16) {
+        self.at(self.fade.gain())
+    }
+
+    /// The duty values for the next frame, which takes the ramp a frame further.
+    pub fn next_value(&mut self) -> (u16, u16) {
+        let gain = self.fade.next_gain();
+        self.at(gain)
+    }
+
+    /// The duty values `gain` of the way from `from` to `to`.
+    fn at(&self, gain: u16) -> (u16, u16) {
+        let between = |from: u16, to: u16| (from as i32 + (((to as i32 - from as i32) * gain as i32) >> 15)) as u16;
+        (between(self.from.0, self.to.0), between(self.from.1, self.to.1))
+    }
+}
+
+/// Mixes `tail`, the end of a track, into `head`, the start of the one after it, going over from one
+/// to the other in a straight line over `length` frames. `at` is the frame of the crossfade the samples
+/// start at, both hold the same number of them in `format`.
+pub fn crossfade(format: PcmFormat, head: &mut [u8], tail: &[u8], at: u32, length: u32) {
+    let bytes_per_sample = format.sample_format.bytes_per_sample();
+    let bytes_per_frame = format.bytes_per_frame();
+    let length = length.max(1) as i64;
+    for (frame, (head, tail)) in head.chunks_exact_mut(bytes_per_frame).zip(tail.chunks_exact(bytes_per_frame)).enumerate() {
+        let into = (at as i64 + frame as i64).min(length);
+        for channel in (0..bytes_per_frame).step_by(bytes_per_sample) {
+            let from_head = format.sample_format.to_i16(&head[channel..]) as i64 * into;
+            let from_tail = format.sample_format.to_i16(&tail[channel..]) as i64 * (length - into);
+            // The weights add up to one, so the mix can't clip
+            let mixed = ((from_head + from_tail) / length) as i16;
+            format.sample_format.write_i16(mixed, &mut head[channel..]);
+        }
+    }
+}
+
+/// The end of a track, mixed into the start of the one after it as the bytes of both are read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct TailMix {
+    format: PcmFormat,
+    /// Bytes of the tail that are left to mix
+    left: u32,
+    /// Bytes it had to begin with
+    length: u32,
+    /// Bytes of a frame of the head the last `mix` ended in the middle of
+    split: usize,
+}
+
+impl TailMix {
+    /// Mixes `length` bytes of a tail in `format`, in whole frames.
+    pub fn new(format: PcmFormat, length: u32) -> TailMix {
+        let length = length - length % format.bytes_per_frame() as u32;
+        TailMix { format, left: length, length, split: 0 }
+    }
+
+    /// Whether all of the tail is mixed in, or it ended early.
+    pub fn is_done(&self) -> bool {
+        self.left == 0
+    }
+
+    /// Mixes the next bytes of the tail into `head`, the next bytes of the track after it.
+    /// `read` fills its buffer with the next bytes of the tail, and returns how many that were,
+    /// fewer than asked once the tail ended.
+    pub fn mix<E>(&mut self, mut head: &mut [u8], mut read: impl FnMut(&mut [u8]) -> Result<usize, E>) -> Result<(), E> {
+        let bytes_per_frame = self.format.bytes_per_frame();
+        // Read in small pieces of whole frames, there is little room on the stack for a second block
+        let mut buffer = [0u8; 64];
+        let piece = buffer.len() - buffer.len() % bytes_per_frame;
+
+        // The rest of a frame the last head ended in, its frame of the tail was dropped already
+        if self.split > 0 {
+            let rest = (bytes_per_frame - self.split).min(head.len());
+            head = &mut head[rest..];
+            self.split = (self.split + rest) % bytes_per_frame;
+        }
+
+        while !head.is_empty() && self.left > 0 {
+            let wanted = (head.len() - head.len() % bytes_per_frame).min(piece).min(self.left as usize);
+            if wanted == 0 {
+                // Half a frame can't be mixed, so it stays as it is. The tail skips its frame
+                // to stay in step with the head.
+                let amount_read = read(&mut buffer[..bytes_per_frame])?;
+                self.advance(amount_read, bytes_per_frame);
+                self.split = head.len();
+                break;
+            }
+            let amount_read = read(&mut buffer[..wanted])?;
+            let mixed = amount_read - amount_read % bytes_per_frame;
+            let at = (self.length - self.left) / bytes_per_frame as u32;
+            let (mixing, rest) = core::mem::take(&mut head).split_at_mut(mixed);
+            crossfade(self.format, mixing, &buffer[..mixed], at, self.length / bytes_per_frame as u32);
+            head = rest;
+            self.advance(amount_read, wanted);
+        }
+        Ok(())
+    }
+
+    /// Goes on past `amount_read` bytes of the tail, where fewer than `wanted` means it ended.
+    fn advance(&mut self, amount_read: usize, wanted: usize) {
+        self.left = match amount_read < wanted {
+            true => 0,
+            false => self.left - amount_read as u32,
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::SampleSource, pcm::SampleFormat};
+
+    #[test]
+    fn fades_out_and_back_in() {
+        let mut fade = Fade::new();
+        fade.set_length(4);
+        fade.fade_out();
+        let out: Vec<u16> = (0..6).map(|_| fade.next_gain()).collect();
+        assert_eq!(out, [UNITY_GAIN, UNITY_GAIN / 4 * 3, UNITY_GAIN / 2, UNITY_GAIN / 4, 0, 0]);
+        assert!(fade.is_silent());
+
+        fade.fade_in();
+        let back: Vec<u16> = (0..6).map(|_| fade.next_gain()).collect();
+        assert_eq!(back, [0, UNITY_GAIN / 4, UNITY_GAIN / 2, UNITY_GAIN / 4 * 3, UNITY_GAIN, UNITY_GAIN]);
+    }
+
+    #[test]
+    fn without_a_length_fades_right_away() {
+        let mut fade = Fade::new();
+        fade.fade_out();
+        assert!(fade.is_silent());
+        fade.fade_in();
+        assert_eq!(fade.next_gain(), UNITY_GAIN);
+    }
+
+    #[test]
+    fn long_fades_still_move() {
+        let mut fade = Fade::new();
+        fade.set_length(1_000_000);
+        fade.silence();
+        fade.fade_in();
+        fade.next_gain();
+        let gains: Vec<u16> = (0..100_000).map(|_| fade.next_gain()).collect();
+        assert!(gains.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!((3_200..3_300).contains(gains.last().unwrap()), "{}", gains.last().unwrap());
+    }
+
+    #[test]
+    fn ramps_up_and_back_down() {
+        let mut ramp = Ramp::new((0, 0));
+        ramp.start((0, 0), (2_048, 1_000), 4);
+        let up: Vec<(u16, u16)> = (0..5).map(|_| ramp.next_value()).collect();
+        assert_eq!(up, [(0, 0), (512, 250), (1_024, 500), (1_536, 750), (2_048, 1_000)]);
+        assert!(ramp.is_done());
+
+        ramp.start(ramp.value(), (0, 0), 2);
+        assert!(!ramp.is_done());
+        let down: Vec<(u16, u16)> = (0..4).map(|_| ramp.next_value()).collect();
+        assert_eq!(down, [(2_048, 1_000), (1_024, 500), (0, 0), (0, 0)]);
+
+        ramp.start((0, 0), (2_048, 2_048), 0);
+        assert_eq!(ramp.value(), (2_048, 2_048));
+    }
+
+    #[test]
+    fn crossfades_from_tail_to_head() {
+        let stereo = PcmFormat { sample_format: SampleFormat::S16Le, channels: 2 };
+        let frame = |left: i16, right: i16| [left.to_le_bytes(), right.to_le_bytes()].concat();
+        let mut head: Vec<u8> = (0..5).flat_map(|_| frame(1_000, -1_000)).collect();
+        let tail: Vec<u8> = (0..5).flat_map(|_| frame(-3_000, 3_000)).collect();
+        crossfade(stereo, &mut head, &tail, 0, 4);
+        let mixed: Vec<i16> = head.chunks(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect();
+        assert_eq!(mixed, [-3_000, 3_000, -2_000, 2_000, -1_000, 1_000, 0, 0, 1_000, -1_000]);
+
+        // Halfway through, in 8 bits
+        let mono = PcmFormat { sample_format: SampleFormat::U8, channels: 1 };
+        let mut head = [0xFF, 0xFF];
+        crossfade(mono, &mut head, &[0x00, 0x00], 2, 4);
+        assert_eq!(head, [0x7F, 0xBF]);
+    }
+
+    /// Frames of a 16 bit stereo track that count up from `first`.
+    fn counting(first: i16, frames: i16) -> Vec<u8> {
+        (first..first + frames).flat_map(|frame| [frame.to_le_bytes(), (-frame).to_le_bytes()].concat()).collect()
+    }
+
+    #[test]
+    fn mixes_the_tail_in_as_the_head_comes() {
+        let stereo = PcmFormat { sample_format: SampleFormat::S16Le, channels: 2 };
+        let tail = counting(-3_000, 40);
+        let mut whole = counting(1_000, 50);
+        crossfade(stereo, &mut whole[..160], &tail, 0, 40);
+
+        // Blocks that end in the middle of a frame leave it as it is, the rest lines up
+        let mut head = counting(1_000, 50);
+        let mut mix = TailMix::new(stereo, tail.len() as u32);
+        let mut tail_file = &tail[..];
+        for block in [0..70, 70..152, 152..200] {
+            mix.mix(&mut head[block], |buffer| Ok::<_, ()>(tail_file.read(buffer))).unwrap();
+        }
+        assert!(mix.is_done());
+        let split = 68..72;
+        assert_eq!(head[split.clone()], counting(1_017, 1)[..]);
+        assert_eq!(head[..split.start], whole[..split.start]);
+        assert_eq!(head[split.end..], whole[split.end..]);
+    }
+
+    #[test]
+    fn tail_that_ends_early_is_done() {
+        let mono = PcmFormat { sample_format: SampleFormat::U8, channels: 1 };
+        let mut head = [0xFF; 8];
+        let mut mix = TailMix::new(mono, 8);
+        let mut tail_file = &[0x00; 3][..];
+        mix.mix(&mut head, |buffer| Ok::<_, ()>(tail_file.read(buffer))).unwrap();
+        assert!(mix.is_done());
+        assert_eq!(head[3..], [0xFF; 5]);
+        assert_eq!(head[0], 0x00);
+    }
+}
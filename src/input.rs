@@ -0,0 +1,401 @@
+//! Turns raw button levels into gestures.
+//!
+//! The buttons are sampled every few milliseconds with a timestamp. A level only counts once it
+//! has been stable for `DEBOUNCE_US`, so contact bounce never shows up as extra presses. The
+//! debounced presses are then told apart by how long they are held and how quickly they follow
+//! each other. A short press is only reported once it can't turn into a double click anymore.
+//!
+//! While `SHIFT` is held, the other buttons make shifted gestures. A long press of `SHIFT` itself is
+//! only reported when it is let go without another button having been pressed, so using it to shift
+//! doesn't also make a long press.
+
+/// How long a level has to be stable before it counts
+pub const DEBOUNCE_US: u64 = 20_000;
+/// Holding a button this long makes it a long press
+pub const LONG_PRESS_US: u64 = 600_000;
+/// Time between repeats while a button is held after a long press
+pub const REPEAT_US: u64 = 150_000;
+/// A second press within this time after releasing the first makes a double click
+pub const DOUBLE_CLICK_US: u64 = 300_000;
+
+use crate::io::InputSource;
+
+/// Events that fit in the queue before new ones get dropped
+const QUEUE_LENGTH: usize = 8;
+
+/// Held down, it changes what the other buttons do
+pub const SHIFT: Button = Button::Pause;
+
+/// The buttons of the player.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Button {
+    /// On GPIO6
+    Pause,
+    /// btn_1 on GPIO7
+    Btn1,
+    /// btn_2 on GPIO8
+    Btn2,
+}
+
+impl Button {
+    pub const ALL: [Button; 3] = [Button::Pause, Button::Btn1, Button::Btn2];
+}
+
+/// What a button was used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Gesture {
+    /// Pressed and released quickly, once
+    Press,
+    /// Pressed and released quickly, twice
+    DoubleClick,
+    /// Held for `LONG_PRESS_US`
+    LongPress,
+    /// Still held, every `REPEAT_US` after a long press
+    Repeat,
+    /// Let go after a long press
+    Release,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct InputEvent {
+    pub button: Button,
+    pub gesture: Gesture,
+    /// `SHIFT` was held when the button was pressed
+    pub shifted: bool,
+}
+
+/// Where a button is in telling its gestures apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// Pressed, and not for long yet
+    Down { since: u64 },
+    /// Held past a long press
+    Held { next_repeat: u64 },
+    /// Released after a short press, a second one would make a double click
+    Released { at: u64 },
+    /// Still down after a double click, nothing more happens until it's released
+    Finished,
+}
+
+/// Debouncing and gesture detection for a single button.
+#[derive(Clone, Copy, Debug)]
+pub struct ButtonMachine {
+    /// The level from the last sample, and since when it has been like that
+    raw: bool,
+    raw_since: u64,
+    /// The debounced level
+    pressed: bool,
+    phase: Phase,
+}
+
+impl ButtonMachine {
+    pub const fn new() -> ButtonMachine {
+        ButtonMachine { raw: false, raw_since: 0, pressed: false, phase: Phase::Idle }
+    }
+
+    /// Takes a sample of the button at `now` microseconds, and returns a gesture if one was completed.
+    pub fn update(&mut self, pressed: bool, now: u64) -> Option<Gesture> {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_since = now;
+        }
+        let edge = self.raw != self.pressed && now.wrapping_sub(self.raw_since) >= DEBOUNCE_US;
+        if edge {
+            self.pressed = self.raw;
+        }
+
+        let (phase, gesture) = match (self.phase, edge, self.pressed) {
+            (Phase::Idle, true, true) => (Phase::Down { since: now }, None),
+            (Phase::Down { .. }, true, false) => (Phase::Released { at: now }, None),
+            (Phase::Down { since }, _, _) if now.wrapping_sub(since) >= LONG_PRESS_US => {
+                (Phase::Held { next_repeat: since + LONG_PRESS_US + REPEAT_US }, Some(Gesture::LongPress))
+            }
+            (Phase::Held { .. }, true, false) => (Phase::Idle, Some(Gesture::Release)),
+            (Phase::Held { next_repeat }, _, _) if now >= next_repeat => {
+                (Phase::Held { next_repeat: next_repeat + REPEAT_US }, Some(Gesture::Repeat))
+            }
+            (Phase::Released { .. }, true, true) => (Phase::Finished, Some(Gesture::DoubleClick)),
+            (Phase::Released { at }, _, _) if now.wrapping_sub(at) >= DOUBLE_CLICK_US => (Phase::Idle, Some(Gesture::Press)),
+            (Phase::Finished, true, false) => (Phase::Idle, None),
+            (phase, _, _) => (phase, None),
+        };
+        self.phase = phase;
+        gesture
+    }
+
+    /// Whether the button is down, debounced.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Makes the press the button is in do nothing more, until it's released.
+    pub fn cancel(&mut self) {
+        if let Phase::Down { .. } | Phase::Held { .. } = self.phase {
+            self.phase = Phase::Finished;
+        }
+    }
+}
+
+impl Default for ButtonMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// All buttons, and the events they produced that haven't been handled yet.
+pub struct Inputs {
+    machines: [ButtonMachine; Button::ALL.len()],
+    /// Whether `SHIFT` was held when each button was pressed last
+    shifted: [bool; Button::ALL.len()],
+    /// `SHIFT` was held long, which is reported once it's let go
+    shift_held: bool,
+    queue: [Option<InputEvent>; QUEUE_LENGTH],
+    /// Index of the oldest event in `queue`, and how many there are
+    first: usize,
+    length: usize,
+}
+
+impl Inputs {
+    pub const fn new() -> Inputs {
+        Inputs {
+            machines: [ButtonMachine::new(); Button::ALL.len()],
+            shifted: [false; Button::ALL.len()],
+            shift_held: false,
+            queue: [None; QUEUE_LENGTH],
+            first: 0,
+            length: 0,
+        }
+    }
+
+    /// Takes a sample of all buttons, in the order of `Button::ALL`, and queues what they did.
+    pub fn update(&mut self, pressed: [bool; Button::ALL.len()], now: u64) {
+        let shift = Button::ALL.iter().position(|&button| button == SHIFT).unwrap_or(0);
+        for (i, (pressed, button)) in pressed.into_iter().zip(Button::ALL).enumerate() {
+            let was_pressed = self.machines[i].is_pressed();
+            let gesture = self.machines[i].update(pressed, now);
+
+            if button != SHIFT && self.machines[i].is_pressed() && !was_pressed {
+                self.shifted[i] = self.machines[shift].is_pressed();
+                if self.shifted[i] {
+                    // It's used to shift, so it makes no gesture of its own
+                    self.machines[shift].cancel();
+                    self.shift_held = false;
+                }
+            }
+
+            let gesture = match (button == SHIFT, gesture) {
+                (false, gesture) => gesture,
+                (true, Some(Gesture::LongPress)) => {
+                    self.shift_held = true;
+                    None
+                }
+                (true, Some(Gesture::Repeat)) => None,
+                (true, Some(Gesture::Release)) => core::mem::take(&mut self.shift_held).then_some(Gesture::LongPress),
+                (true, gesture) => gesture,
+            };
+            if let Some(gesture) = gesture {
+                self.push(InputEvent { button, gesture, shifted: button != SHIFT && self.shifted[i] });
+            }
+        }
+    }
+
+    fn push(&mut self, event: InputEvent) {
+        // When nobody handles the events, the newest ones are the least surprising to lose
+        if self.length < QUEUE_LENGTH {
+            self.queue[(self.first + self.length) % QUEUE_LENGTH] = Some(event);
+            self.length += 1;
+        }
+    }
+
+    /// Samples all buttons of `source` at its current time.
+    pub fn poll(&mut self, source: &mut impl InputSource) {
+        let pressed = source.pressed();
+        self.update(pressed, source.now_us());
+    }
+
+    /// Takes the oldest event out of the queue.
+    pub fn pop(&mut self) -> Option<InputEvent> {
+        if self.length == 0 {
+            return None;
+        }
+        let event = self.queue[self.first].take();
+        self.first = (self.first + 1) % QUEUE_LENGTH;
+        self.length -= 1;
+        event
+    }
+}
+
+impl Default for Inputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::mock::HeldButtons;
+
+    /// Sample interval of the simulated buttons
+    const TICK: u64 = 5_000;
+
+    /// Samples `machine` from `from` until `to` with the button at `pressed`, and collects the gestures.
+    fn hold(machine: &mut ButtonMachine, pressed: bool, from: u64, to: u64) -> Vec<(u64, Gesture)> {
+        (from..to)
+            .step_by(TICK as usize)
+            .filter_map(|now| Some((now, machine.update(pressed, now)?)))
+            .collect()
+    }
+
+    fn gestures(events: &[(u64, Gesture)]) -> Vec<Gesture> {
+        events.iter().map(|&(_, gesture)| gesture).collect()
+    }
+
+    #[test]
+    fn short_press_waits_out_the_double_click() {
+        let mut machine = ButtonMachine::new();
+        assert!(hold(&mut machine, true, 0, 100_000).is_empty());
+        let events = hold(&mut machine, false, 100_000, 1_000_000);
+        assert_eq!(gestures(&events), [Gesture::Press]);
+        // Released at 120ms once debounced, so it's reported once the double click window passed
+        assert_eq!(events[0].0, 120_000 + DOUBLE_CLICK_US);
+    }
+
+    #[test]
+    fn bounces_are_ignored() {
+        let mut machine = ButtonMachine::new();
+        let mut events = Vec::new();
+        // Contacts chatter for a while each way, faster than the debounce time
+        for (at, pressed) in [(0, true), (5_000, false), (10_000, true), (15_000, false), (20_000, true)] {
+            events.extend(hold(&mut machine, pressed, at, at + TICK));
+        }
+        events.extend(hold(&mut machine, true, 25_000, 150_000));
+        for (at, pressed) in [(150_000, false), (155_000, true), (160_000, false)] {
+            events.extend(hold(&mut machine, pressed, at, at + TICK));
+        }
+        events.extend(hold(&mut machine, false, 165_000, 1_000_000));
+        assert_eq!(gestures(&events), [Gesture::Press]);
+    }
+
+    #[test]
+    fn glitches_shorter_than_the_debounce_do_nothing() {
+        let mut machine = ButtonMachine::new();
+        let mut events = hold(&mut machine, true, 0, DEBOUNCE_US - TICK);
+        events.extend(hold(&mut machine, false, DEBOUNCE_US - TICK, 1_000_000));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn double_click() {
+        let mut machine = ButtonMachine::new();
+        let mut events = hold(&mut machine, true, 0, 80_000);
+        events.extend(hold(&mut machine, false, 80_000, 200_000));
+        events.extend(hold(&mut machine, true, 200_000, 1_500_000));
+        events.extend(hold(&mut machine, false, 1_500_000, 2_000_000));
+        // Holding on after the second click doesn't turn it into a long press
+        assert_eq!(gestures(&events), [Gesture::DoubleClick]);
+    }
+
+    #[test]
+    fn slow_clicks_are_two_presses() {
+        let mut machine = ButtonMachine::new();
+        let mut events = hold(&mut machine, true, 0, 80_000);
+        events.extend(hold(&mut machine, false, 80_000, 600_000));
+        events.extend(hold(&mut machine, true, 600_000, 680_000));
+        events.extend(hold(&mut machine, false, 680_000, 1_200_000));
+        assert_eq!(gestures(&events), [Gesture::Press, Gesture::Press]);
+    }
+
+    #[test]
+    fn holding_repeats_after_a_long_press() {
+        let mut machine = ButtonMachine::new();
+        let mut events = hold(&mut machine, true, 0, 1_200_000);
+        events.extend(hold(&mut machine, false, 1_200_000, 2_000_000));
+
+        // Pressed at 20ms once debounced
+        let expected = [
+            (20_000 + LONG_PRESS_US, Gesture::LongPress),
+            (20_000 + LONG_PRESS_US + REPEAT_US, Gesture::Repeat),
+            (20_000 + LONG_PRESS_US + 2 * REPEAT_US, Gesture::Repeat),
+            (20_000 + LONG_PRESS_US + 3 * REPEAT_US, Gesture::Repeat),
+            (1_220_000, Gesture::Release),
+        ];
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn queues_events_per_button() {
+        let mut inputs = Inputs::new();
+        for now in (0..100_000).step_by(TICK as usize) {
+            inputs.update([false, true, false], now);
+        }
+        for now in (100_000..1_000_000).step_by(TICK as usize) {
+            inputs.update([false, false, now < 800_000], now);
+        }
+        assert_eq!(inputs.pop(), Some(InputEvent { button: Button::Btn1, gesture: Gesture::Press, shifted: false }));
+        assert_eq!(inputs.pop(), Some(InputEvent { button: Button::Btn2, gesture: Gesture::LongPress, shifted: false }));
+        assert_eq!(inputs.pop(), Some(InputEvent { button: Button::Btn2, gesture: Gesture::Release, shifted: false }));
+        assert_eq!(inputs.pop(), None);
+    }
+
+    #[test]
+    fn polls_an_input_source() {
+        let mut inputs = Inputs::new();
+        let mut buttons = HeldButtons::default();
+        buttons.pressed[2] = true;
+        while buttons.now_us <= DEBOUNCE_US + LONG_PRESS_US {
+            inputs.poll(&mut buttons);
+            buttons.now_us += TICK;
+        }
+        assert_eq!(inputs.pop(), Some(InputEvent { button: Button::Btn2, gesture: Gesture::LongPress, shifted: false }));
+    }
+
+    /// Feeds `inputs` the buttons of `presses` that are down at every tick from `from` until `to`,
+    /// given as the button and when it's pressed and released.
+    fn press(inputs: &mut Inputs, presses: &[(Button, u64, u64)], from: u64, to: u64) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        for now in (from..to).step_by(TICK as usize) {
+            let pressed = Button::ALL.map(|button| presses.iter().any(|&(pressed, from, to)| pressed == button && (from..to).contains(&now)));
+            inputs.update(pressed, now);
+            events.extend(core::iter::from_fn(|| inputs.pop()));
+        }
+        events
+    }
+
+    #[test]
+    fn shift_changes_the_other_buttons() {
+        let mut inputs = Inputs::new();
+        let events = press(&mut inputs, &[(SHIFT, 0, 1_500_000), (Button::Btn2, 100_000, 200_000)], 0, 2_000_000);
+        // Holding shift past a long press is no long press when it shifted something
+        assert_eq!(events, [InputEvent { button: Button::Btn2, gesture: Gesture::Press, shifted: true }]);
+
+        let events = press(&mut inputs, &[(Button::Btn2, 2_000_000, 2_100_000)], 2_000_000, 3_000_000);
+        assert_eq!(events, [InputEvent { button: Button::Btn2, gesture: Gesture::Press, shifted: false }]);
+    }
+
+    #[test]
+    fn long_shift_press_is_reported_when_let_go() {
+        let mut inputs = Inputs::new();
+        let presses = [(SHIFT, 0, 1_400_000)];
+        let events = press(&mut inputs, &presses, 0, 1_400_000);
+        assert!(events.is_empty());
+        let events = press(&mut inputs, &presses, 1_400_000, 1_600_000);
+        assert_eq!(events, [InputEvent { button: SHIFT, gesture: Gesture::LongPress, shifted: false }]);
+    }
+
+    #[test]
+    fn full_queue_drops_new_events() {
+        let mut inputs = Inputs::new();
+        for now in (0..10_000_000).step_by(TICK as usize) {
+            inputs.update([false, true, false], now);
+        }
+        let mut popped = 0;
+        while let Some(event) = inputs.pop() {
+            assert_eq!(event.button, Button::Btn1);
+            popped += 1;
+        }
+        assert_eq!(popped, QUEUE_LENGTH);
+    }
+}
@@ -0,0 +1,84 @@
+//! What the player needs from the hardware around it.
+//!
+//! The firmware implements these for the RP2040's PWM, DMA, SIO FIFO and GPIO, and `mock` has
+//! in-memory versions, so `playback` and `input` can run on the host.
+
+pub mod mock;
+
+use crate::{input::Button, protocol::{Command, Event}, pwm_timing::PwmTiming};
+
+/// Where duty values go to be played, one pair for channel A and B per sample.
+pub trait AudioSink {
+    /// Sets the PWM period the duty values are scaled to, and with it the sample rate.
+    fn configure(&mut self, timing: &PwmTiming);
+
+    /// Starts or stops the output. While it is stopped, it rests at mid-scale and the system clock may change.
+    fn set_enabled(&mut self, enabled: bool);
+
+    /// Blocks until there is room for more duty values, and returns how many fit.
+    fn wait_for_space(&mut self) -> usize;
+
+    /// Queues duty values for channel A and B, at most as many as `wait_for_space` made room for.
+    fn push(&mut self, duty: (u16, u16));
+
+    /// Whether values were played again because they weren't replaced in time, since the last call.
+    fn take_underrun(&mut self) -> bool;
+}
+
+/// Where the bytes of the samples come from.
+pub trait SampleSource {
+    /// Fills `buffer` with as many bytes as are available, and returns how many that were.
+    fn read(&mut self, buffer: &mut [u8]) -> usize;
+
+    /// Drops everything that is available now.
+    fn clear(&mut self);
+}
+
+/// The state of the buttons, and a clock to time them by.
+pub trait InputSource {
+    /// Which buttons are held down, in the order of `Button::ALL`.
+    fn pressed(&mut self) -> [bool; Button::ALL.len()];
+
+    /// Microseconds since some point in the past, that never goes back.
+    fn now_us(&self) -> u64;
+}
+
+/// The channel commands arrive on, and events go back through.
+pub trait CommandChannel {
+    /// Returns the next command, if one arrived.
+    fn try_receive(&mut self) -> Option<Command>;
+
+    /// Waits for the next command.
+    fn receive(&mut self) -> Command;
+
+    /// Sends an event that is only informative. It may be dropped when the channel is full,
+    /// the other side may be waiting on us.
+    fn notify(&mut self, event: Event);
+
+    /// Sends an event the other side is waiting for.
+    fn send(&mut self, event: Event);
+}
+
+impl SampleSource for &[u8] {
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let amount = buffer.len().min(self.len());
+        let (read, rest) = self.split_at(amount);
+        buffer[..amount].copy_from_slice(read);
+        *self = rest;
+        amount
+    }
+
+    fn clear(&mut self) {
+        *self = &[];
+    }
+}
+
+impl<const N: usize> SampleSource for crate::ring_buffer::Consumer<'_, N> {
+    fn read(&mut self, buffer: &mut [u8]) -> usize {
+        crate::ring_buffer::Consumer::read(self, buffer)
+    }
+
+    fn clear(&mut self) {
+        crate::ring_buffer::Consumer::clear(self)
+    }
+}
@@ -0,0 +1,85 @@
+//! In-memory implementations of the `io` traits, for tests and the simulator.
+
+use crate::{input::Button, pwm_timing::PwmTiming};
+
+use super::{AudioSink, InputSource};
+
+/// An audio sink that hands every duty value to a function, and has room for `space` at a time.
+pub struct MemorySink<F: FnMut((u16, u16))> {
+    output: F,
+    space: usize,
+    /// Values pushed since the last `wait_for_space`
+    pushed: usize,
+    /// The last timing it was configured with
+    pub timing: Option<PwmTiming>,
+    pub enabled: bool,
+    /// Reported by the next `take_underrun`
+    pub underrun: bool,
+}
+
+impl<F: FnMut((u16, u16))> MemorySink<F> {
+    pub fn new(space: usize, output: F) -> MemorySink<F> {
+        MemorySink { output, space, pushed: 0, timing: None, enabled: false, underrun: false }
+    }
+}
+
+impl<F: FnMut((u16, u16))> AudioSink for MemorySink<F> {
+    fn configure(&mut self, timing: &PwmTiming) {
+        self.timing = Some(*timing);
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn wait_for_space(&mut self) -> usize {
+        self.pushed = 0;
+        self.space
+    }
+
+    fn push(&mut self, duty: (u16, u16)) {
+        assert!(self.pushed < self.space, "pushed more duty values than there was room for");
+        self.pushed += 1;
+        (self.output)(duty);
+    }
+
+    fn take_underrun(&mut self) -> bool {
+        core::mem::take(&mut self.underrun)
+    }
+}
+
+/// Buttons that stay the way they are set, with a clock that only moves when told to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeldButtons {
+    pub pressed: [bool; Button::ALL.len()],
+    pub now_us: u64,
+}
+
+impl InputSource for HeldButtons {
+    fn pressed(&mut self) -> [bool; Button::ALL.len()] {
+        self.pressed
+    }
+
+    fn now_us(&self) -> u64 {
+        self.now_us
+    }
+}
+
+/// Drops what the library logs, the host has no probe to send it to.
+#[cfg(not(target_os = "none"))]
+#[defmt::global_logger]
+struct DroppedLogs;
+
+#[cfg(not(target_os = "none"))]
+unsafe impl defmt::Logger for DroppedLogs {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[cfg(not(target_os = "none"))]
+defmt::timestamp!("");
@@ -0,0 +1,24 @@
+//! Hardware independent parts of Dropstick.
+//!
+//! Everything in here only depends on `core`, so it also builds for the host
+//! where it can be unit tested, and where `src/bin/simulate.rs` runs files through it.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod error;
+pub mod fade;
+pub mod input;
+pub mod io;
+pub mod m3u;
+pub mod pcm;
+pub mod playback;
+pub mod player;
+pub mod playlist;
+pub mod protocol;
+pub mod pwm_timing;
+pub mod repeat;
+pub mod resample;
+pub mod ring_buffer;
+pub mod shuffle;
+pub mod state;
+pub mod volume;
@@ -0,0 +1,179 @@
+//! Reads M3U and M3U8 playlist files, a line at a time.
+//!
+//! Every line is a path to a track, relative to the folder of the playlist unless it starts with
+//! a `/`. Lines starting with `#` are comments, except for `#EXTINF:<seconds>,<title>`, which
+//! gives the title of the track on the next line. The file is fed in as it is read, so only the
+//! line that is being read has to fit in memory.
+
+/// Longest line that is read, longer ones are skipped
+pub const MAX_LINE_LENGTH: usize = 256;
+/// Bytes of an `#EXTINF` title that are kept
+pub const MAX_TITLE_LENGTH: usize = 64;
+
+/// Marks UTF-8 files, M3U8 files often start with it
+const BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+/// A track in a playlist file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// With `/` between folders, also when the file used `\`
+    pub path: &'a str,
+    /// From the `#EXTINF` line before it, if there was one
+    pub title: Option<&'a str>,
+}
+
+/// Turns the bytes of a playlist file into its entries.
+pub struct M3uParser {
+    line: [u8; MAX_LINE_LENGTH],
+    length: usize,
+    /// The line didn't fit, so the rest of it is skipped
+    overlong: bool,
+    /// The first line is being read, which may start with a byte order mark
+    at_start: bool,
+    title: [u8; MAX_TITLE_LENGTH],
+    /// Length of the title for the next entry, if there is one
+    title_length: Option<usize>,
+    /// Lines that were skipped for being too long or not UTF-8
+    skipped: usize,
+}
+
+impl M3uParser {
+    pub const fn new() -> M3uParser {
+        M3uParser {
+            line: [0; MAX_LINE_LENGTH],
+            length: 0,
+            overlong: false,
+            at_start: true,
+            title: [0; MAX_TITLE_LENGTH],
+            title_length: None,
+            skipped: 0,
+        }
+    }
+
+    /// Reads the next bytes of the file, calling `entry` for every track in them.
+    pub fn feed(&mut self, bytes: &[u8], mut entry: impl FnMut(Entry)) {
+        for &byte in bytes {
+            match byte {
+                b'\n' => self.end_line(&mut entry),
+                _ if self.length == MAX_LINE_LENGTH => self.overlong = true,
+                _ => {
+                    self.line[self.length] = byte;
+                    self.length += 1;
+                }
+            }
+        }
+    }
+
+    /// Reads the last line, which doesn't have to end in a newline.
+    pub fn finish(&mut self, mut entry: impl FnMut(Entry)) {
+        self.end_line(&mut entry);
+    }
+
+    /// Lines that were skipped for being longer than `MAX_LINE_LENGTH`, or not UTF-8.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn end_line(&mut self, entry: &mut impl FnMut(Entry)) {
+        let length = core::mem::take(&mut self.length);
+        let start = if core::mem::take(&mut self.at_start) && self.line[..length].starts_with(BYTE_ORDER_MARK) {
+            BYTE_ORDER_MARK.len()
+        } else {
+            0
+        };
+        if core::mem::take(&mut self.overlong) {
+            self.skipped += 1;
+            self.title_length = None;
+            return;
+        }
+        let Ok(line) = core::str::from_utf8(&self.line[start..length]) else {
+            self.skipped += 1;
+            self.title_length = None;
+            return;
+        };
+
+        let line = line.trim();
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // The title comes after the duration, and may have commas itself
+            let title = info.split_once(',').map_or("", |(_, title)| title.trim());
+            let mut title_length = title.len().min(MAX_TITLE_LENGTH);
+            while !title.is_char_boundary(title_length) {
+                title_length -= 1;
+            }
+            self.title[..title_length].copy_from_slice(&title.as_bytes()[..title_length]);
+            self.title_length = Some(title_length);
+            return;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+
+        // Playlists made on Windows use backslashes, which FAT doesn't allow in names anyway
+        let path = &mut self.line[start..length];
+        path.iter_mut().filter(|byte| **byte == b'\\').for_each(|byte| *byte = b'/');
+        let Ok(path) = core::str::from_utf8(path) else {
+            return;
+        };
+
+        let title = self.title_length.take().and_then(|title_length| core::str::from_utf8(&self.title[..title_length]).ok());
+        entry(Entry { path: path.trim(), title });
+    }
+}
+
+impl Default for M3uParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `file` in pieces of `chunk` bytes, and returns the paths and titles.
+    fn parse(file: &[u8], chunk: usize) -> (Vec<(String, Option<String>)>, usize) {
+        let mut parser = M3uParser::new();
+        let mut entries = Vec::new();
+        let mut push = |entry: Entry| entries.push((entry.path.to_string(), entry.title.map(str::to_string)));
+        for piece in file.chunks(chunk) {
+            parser.feed(piece, &mut push);
+        }
+        parser.finish(&mut push);
+        (entries, parser.skipped())
+    }
+
+    #[test]
+    fn reads_paths_and_titles() {
+        let file = b"\xEF\xBB\xBF#EXTM3U\r\n#EXTINF:215,Daisies, live\r\nAlbum\\01 Daisies.wav\r\n\r\n# a comment\r\n/Other/track.wav";
+        let expected = vec![
+            ("Album/01 Daisies.wav".to_string(), Some("Daisies, live".to_string())),
+            ("/Other/track.wav".to_string(), None),
+        ];
+        for chunk in [1, 7, 512] {
+            assert_eq!(parse(file, chunk), (expected.clone(), 0), "in pieces of {chunk} bytes");
+        }
+    }
+
+    #[test]
+    fn titles_only_go_with_the_next_path() {
+        let (entries, _) = parse(b"#EXTINF:-1,First\n#EXTINF:-1,Second\none.wav\ntwo.wav\n", 512);
+        assert_eq!(entries, [("one.wav".to_string(), Some("Second".to_string())), ("two.wav".to_string(), None)]);
+    }
+
+    #[test]
+    fn skips_lines_that_do_not_fit() {
+        let mut file = "a".repeat(MAX_LINE_LENGTH + 1).into_bytes();
+        file.extend_from_slice(b"\nshort.wav\n\xFF\xFE.wav\n");
+        let (entries, skipped) = parse(&file, 100);
+        assert_eq!(entries, [("short.wav".to_string(), None)]);
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn keeps_the_start_of_long_titles() {
+        let title = "é".repeat(MAX_TITLE_LENGTH);
+        let (entries, _) = parse(format!("#EXTINF:1,{title}\ntrack.wav").as_bytes(), 512);
+        assert_eq!(entries[0].1.as_deref(), Some(&*"é".repeat(MAX_TITLE_LENGTH / 2)));
+    }
+}
@@ -8,12 +8,15 @@
 extern crate alloc;
 
 use embedded_alloc::Heap;
-use rp2040_hal::{self as hal, pac, pll::common_configs::PLL_USB_48MHZ, Timer};
+use cortex_m::singleton;
+use rp2040_hal::{self as hal, dma::DMAExt, pac, pll::common_configs::PLL_USB_48MHZ, Timer};
+
+use dropstick::player::wav_streaming::SampleRing;
 
-mod player;
 mod clock_init;
 mod core0_main;
 mod core1_main;
+mod pwm_dma;
 
 // Formatting machinery
 use defmt_rtt as _;
@@ -73,11 +76,21 @@ fn main() -> ! {
     );
 
     // Init timer
-    let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks.manager);
 
     // Init PWMs
     let pwm_slices = hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS);
 
+    // Init DMA, it feeds the PWM
+    let dma = pac.DMA.split(&mut pac.RESETS);
+
+    // Samples stream from core 1 to core 0 through this
+    let sample_ring = singleton!(: SampleRing = SampleRing::new()).unwrap();
+    let (sample_producer, sample_consumer) = sample_ring.split();
+
+    // Core 1 may change this to match the sample rate of each file
+    let sys_freq = clocks.system_freq().to_Hz();
+
     core1_main::init(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo, move || {
         core1_main::main(
             &mut pac.RESETS,
@@ -88,16 +101,23 @@ fn main() -> ! {
             pins.gpio4,
             pins.gpio5,
             timer,
+            pins.gpio25,
+            pins.gpio6,
             pins.gpio7,
             pins.gpio8,
+            pac.ROSC,
+            sample_producer,
         )
     });
 
     core0_main::main(
-        pins.gpio6,
         pins.gpio16,
+        pins.gpio17,
         timer,
         pwm_slices,
+        (dma.ch0, dma.ch1),
+        sample_consumer,
         &mut sio.fifo,
+        sys_freq,
     );
 }
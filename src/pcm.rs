@@ -0,0 +1,223 @@
+//! Decoding of PCM samples, and their conversion to PWM duty values.
+
+/// Largest duty value of the 12 bit PWM output (`TOP = 4096`)
+pub const PWM_MAX: u16 = 0xFFF;
+
+/// Sample encodings the player can decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SampleFormat {
+    /// 8 bit unsigned, 128 is silence
+    U8,
+    /// 16 bit signed little-endian, 0 is silence
+    S16Le,
+}
+
+impl SampleFormat {
+    /// Picks the encoding for integer PCM with the given amount of bits per sample.
+    pub fn from_bits_per_sample(bits_per_sample: u16) -> Option<SampleFormat> {
+        match bits_per_sample {
+            8 => Some(SampleFormat::U8),
+            16 => Some(SampleFormat::S16Le),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16Le => 2,
+        }
+    }
+
+    /// Decodes the sample at the start of `bytes` into a signed 16 bit one.
+    pub fn to_i16(self, bytes: &[u8]) -> i16 {
+        match self {
+            SampleFormat::U8 => u8_to_i16(bytes[0]),
+            SampleFormat::S16Le => i16::from_le_bytes([bytes[0], bytes[1]]),
+        }
+    }
+
+    /// Encodes a signed 16 bit sample into the start of `bytes`, dropping the bits 8 bit samples
+    /// don't have.
+    pub fn write_i16(self, sample: i16, bytes: &mut [u8]) {
+        match self {
+            SampleFormat::U8 => bytes[0] = ((sample >> 8) + 128) as u8,
+            SampleFormat::S16Le => bytes[..2].copy_from_slice(&sample.to_le_bytes()),
+        }
+    }
+
+    /// Decodes the sample at the start of `bytes` into a duty value.
+    pub fn to_pwm(self, bytes: &[u8]) -> u16 {
+        match self {
+            SampleFormat::U8 => u8_to_pwm(bytes[0]),
+            SampleFormat::S16Le => s16_to_pwm(self.to_i16(bytes)),
+        }
+    }
+}
+
+/// Layout of the sample stream: how each sample is encoded and how many make up a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PcmFormat {
+    pub sample_format: SampleFormat,
+    /// Samples per frame, 1 for mono and 2 for stereo
+    pub channels: u8,
+}
+
+impl PcmFormat {
+    pub fn bytes_per_frame(self) -> usize {
+        self.sample_format.bytes_per_sample() * self.channels as usize
+    }
+
+    /// Decodes the frame at the start of `bytes` into left and right signed 16 bit samples.
+    /// Mono frames play the same sample on both sides.
+    pub fn decode(self, bytes: &[u8]) -> (i16, i16) {
+        let left = self.sample_format.to_i16(bytes);
+        let right = match self.channels {
+            1 => left,
+            _ => self.sample_format.to_i16(&bytes[self.sample_format.bytes_per_sample()..]),
+        };
+        (left, right)
+    }
+
+    /// Decodes the frame at the start of `bytes` into left and right duty values.
+    pub fn to_pwm(self, bytes: &[u8]) -> (u16, u16) {
+        let (left, right) = self.decode(bytes);
+        (s16_to_pwm(left), s16_to_pwm(right))
+    }
+}
+
+/// How frames are mapped onto the PWM outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ChannelMode {
+    /// Mix both channels into one, played on channel A
+    Downmix,
+    /// Left on channel A, right on channel B
+    Stereo,
+}
+
+impl ChannelMode {
+    /// Maps decoded left and right samples onto the A and B outputs.
+    pub fn apply(self, (left, right): (i16, i16)) -> (i16, i16) {
+        match self {
+            ChannelMode::Downmix => {
+                let mono = downmix(left, right);
+                (mono, mono)
+            }
+            ChannelMode::Stereo => (left, right),
+        }
+    }
+}
+
+/// Mixes two samples at half volume each, so the sum can't clip.
+pub fn downmix(left: i16, right: i16) -> i16 {
+    ((left as i32 + right as i32) / 2) as i16
+}
+
+/// Rescales a duty value from `0..=PWM_MAX` to `0..=top`, for PWM periods shorter than 12 bits.
+pub fn scale_to_top(value: u16, top: u16) -> u16 {
+    ((value as u32 * (top as u32 + 1)) >> 12) as u16
+}
+
+/// Widens an unsigned 8 bit sample to a signed 16 bit one.
+pub fn u8_to_i16(raw_value: u8) -> i16 {
+    ((raw_value as i16) - 128) << 8
+}
+
+/// Rescales an unsigned 8 bit sample to `0..=PWM_MAX`.
+pub fn u8_to_pwm(raw_value: u8) -> u16 {
+    ((raw_value as u16) << 4) & PWM_MAX
+}
+
+/// Rescales a signed 16 bit sample to `0..=PWM_MAX`.
+///
+/// Flipping the sign bit turns the two's complement value into an offset binary one,
+/// after which only the 12 most significant bits are kept.
+pub fn s16_to_pwm(raw_value: i16) -> u16 {
+    ((raw_value as u16) ^ 0x8000) >> 4
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u8_covers_pwm_range() {
+        assert_eq!(u8_to_pwm(0), 0);
+        assert_eq!(u8_to_pwm(128), 2048);
+        assert_eq!(u8_to_pwm(255), 4080);
+    }
+
+    #[test]
+    fn s16_covers_pwm_range() {
+        assert_eq!(s16_to_pwm(i16::MIN), 0);
+        assert_eq!(s16_to_pwm(0), 2048);
+        assert_eq!(s16_to_pwm(i16::MAX), PWM_MAX);
+        assert_eq!(s16_to_pwm(-1), 2047);
+    }
+
+    #[test]
+    fn s16_is_monotonic() {
+        let mut previous = 0;
+        for raw_value in i16::MIN..=i16::MAX {
+            let value = s16_to_pwm(raw_value);
+            assert!(value >= previous);
+            assert!(value <= PWM_MAX);
+            previous = value;
+        }
+    }
+
+    #[test]
+    fn s16_matches_u8_for_same_level() {
+        for raw_value in 0..=u8::MAX {
+            let widened = ((raw_value as i16) - 128) << 8;
+            assert_eq!(s16_to_pwm(widened), u8_to_pwm(raw_value));
+            assert_eq!(u8_to_i16(raw_value), widened);
+            let mut encoded = [0];
+            SampleFormat::U8.write_i16(widened, &mut encoded);
+            assert_eq!(encoded, [raw_value]);
+        }
+    }
+
+    #[test]
+    fn decodes_little_endian() {
+        assert_eq!(SampleFormat::S16Le.to_pwm(&[0x00, 0x80]), 0);
+        assert_eq!(SampleFormat::S16Le.to_pwm(&[0xFF, 0x7F]), PWM_MAX);
+        assert_eq!(SampleFormat::S16Le.to_pwm(&[0x00, 0x10]), 0x900);
+        assert_eq!(SampleFormat::U8.to_pwm(&[0x80, 0xFF]), 2048);
+    }
+
+    #[test]
+    fn decodes_stereo_frames() {
+        let stereo = PcmFormat { sample_format: SampleFormat::S16Le, channels: 2 };
+        assert_eq!(stereo.bytes_per_frame(), 4);
+        assert_eq!(stereo.to_pwm(&[0x00, 0x80, 0xFF, 0x7F]), (0, PWM_MAX));
+
+        let mono = PcmFormat { sample_format: SampleFormat::U8, channels: 1 };
+        assert_eq!(mono.bytes_per_frame(), 1);
+        assert_eq!(mono.to_pwm(&[0xFF]), (4080, 4080));
+    }
+
+    #[test]
+    fn downmixes_to_average() {
+        assert_eq!(ChannelMode::Downmix.apply((i16::MIN, i16::MAX)), (0, 0));
+        assert_eq!(ChannelMode::Downmix.apply((1000, 3000)), (2000, 2000));
+        assert_eq!(ChannelMode::Downmix.apply((i16::MIN, i16::MIN)), (i16::MIN, i16::MIN));
+        assert_eq!(ChannelMode::Stereo.apply((i16::MIN, i16::MAX)), (i16::MIN, i16::MAX));
+    }
+
+    #[test]
+    fn scales_to_shorter_periods() {
+        assert_eq!(scale_to_top(PWM_MAX, PWM_MAX), PWM_MAX);
+        assert_eq!(scale_to_top(2048, 2999), 1500);
+        assert_eq!(scale_to_top(PWM_MAX, 2999), 2999);
+        assert_eq!(scale_to_top(0, 2999), 0);
+    }
+
+    #[test]
+    fn picks_format_from_bit_depth() {
+        assert_eq!(SampleFormat::from_bits_per_sample(8), Some(SampleFormat::U8));
+        assert_eq!(SampleFormat::from_bits_per_sample(16), Some(SampleFormat::S16Le));
+        assert_eq!(SampleFormat::from_bits_per_sample(24), None);
+    }
+}
@@ -0,0 +1,512 @@
+//! What core 0 does: following the commands from core 1 and keeping the audio output fed.
+
+use crate::{
+    fade::Ramp,
+    io::{AudioSink, CommandChannel, SampleSource},
+    player::wav_streaming::WAVStreamPlayer,
+    protocol::{Command, Event},
+    pwm_timing::PwmTiming,
+};
+
+/// Duty values core 0 fills at once, one half of its DMA buffer, about 5.8ms at 44.1kHz
+pub const HALF_BUFFER_FRAMES: usize = 256;
+/// Bytes of samples the player takes out of the source at once
+pub const PLAYER_BUFFER_SIZE: usize = 128;
+
+/// How often the playback position is reported, in `step`s (about 0.4s at 44.1kHz with 256 frames each)
+pub const POSITION_REPORT_STEPS: u32 = 64;
+
+/// How long the output takes to change, so it doesn't click or thump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Fades {
+    /// Pausing, resuming, starting, stopping and seeking, and going over to mid-scale after a track
+    pub fade_ms: u32,
+    /// Ramping the output from 0 up to mid-scale after power up, and back down when idle
+    pub ramp_ms: u32,
+}
+
+/// How the firmware fades: 20ms doesn't click, and ramping for 500ms doesn't thump the speaker.
+pub const FADES: Fades = Fades { fade_ms: 20, ramp_ms: 500 };
+
+/// Plays samples from `R` into `S`, the way core 1 tells it to.
+pub struct Playback<'buf, S: AudioSink, R: SampleSource> {
+    player: WAVStreamPlayer<'buf>,
+    sink: S,
+    source: R,
+    paused: bool,
+    /// Nothing to play until the next track starts
+    stopped: bool,
+    /// Ran out of samples, and reported it already
+    starved: bool,
+    steps_since_report: u32,
+    /// The duty values last pushed, which are held while paused
+    duty: (u16, u16),
+    /// Where the output goes while stopped
+    ramp: Ramp,
+    /// Rate of the duty values pushed into the sink
+    output_rate: u32,
+    fades: Fades,
+    /// A `Stop`, `Seek` or `Flush` that waits for the sound to fade out,
+    /// or a `TrackStart` that waits for the output to ramp up
+    pending: Option<Command>,
+}
+
+impl<'buf, S: AudioSink, R: SampleSource> Playback<'buf, S, R> {
+    /// Starts out stopped, ramping the output up from 0 to mid-scale at `output_rate` before the
+    /// first track starts. With 0 for `fades`, the output changes right away.
+    pub fn new(player: WAVStreamPlayer<'buf>, sink: S, source: R, output_rate: u32, fades: Fades) -> Playback<'buf, S, R> {
+        let mut ramp = Ramp::new((0, 0));
+        ramp.start((0, 0), player.mid_scale(), output_rate / 1_000 * fades.ramp_ms);
+        Playback {
+            player,
+            sink,
+            source,
+            paused: false,
+            stopped: true,
+            starved: false,
+            steps_since_report: 0,
+            duty: ramp.value(),
+            ramp,
+            output_rate,
+            fades,
+            pending: None,
+        }
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn source(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Whether samples are played, which they still are while pausing fades out.
+    pub fn is_playing(&self) -> bool {
+        !self.stopped && (!self.paused || !self.player.is_silent())
+    }
+
+    /// Waits until the sink has room, handles the commands that arrived meanwhile, and fills the room.
+    pub fn step(&mut self, channel: &mut impl CommandChannel) {
+        let space = self.sink.wait_for_space();
+
+        if self.sink.take_underrun() {
+            channel.notify(Event::Underrun);
+        }
+
+        while let Some(command) = channel.try_receive() {
+            self.handle_command(command, channel);
+        }
+
+        // A track that waited for the output to ramp up starts here, as it stops the sink
+        // and waits for core 1 to change the clock
+        if let Some(command @ Command::TrackStart { .. }) = self.pending.filter(|&command| self.is_ready_for(command)) {
+            self.pending = None;
+            self.handle_command(command, channel);
+        }
+
+        self.fill(space, channel);
+    }
+
+    fn handle_command(&mut self, command: Command, channel: &mut impl CommandChannel) {
+        match command {
+            // The fade in starts from mid-scale, so after idling the output ramps back up first
+            Command::TrackStart { .. } if !self.is_ready_for(command) => {
+                if self.ramp.target() != self.player.mid_scale() {
+                    self.ramp.start(self.duty, self.player.mid_scale(), self.frames(self.fades.ramp_ms));
+                }
+                self.pending = Some(command);
+            }
+            Command::TrackStart { format, sample_rate, output_rate } => {
+                // Whatever is left belongs to the old track
+                self.source.clear();
+                self.player.flush();
+                self.player.set_format(format);
+                self.player.set_rates(sample_rate, output_rate);
+                self.player.set_position(0);
+                self.output_rate = output_rate;
+                let fade_length = self.frames(self.fades.fade_ms);
+                self.player.fade().set_length(fade_length);
+                self.fade_in();
+
+                // Core 1 may change the system clock to suit the new rate, rest at mid-scale until it's done
+                self.sink.set_enabled(false);
+                channel.send(Event::OutputStopped);
+                match channel.receive() {
+                    // Core 1 picked the clock for this rate, so there always is a timing for it
+                    Command::SystemClock { sys_freq } => match PwmTiming::for_rate(sys_freq, output_rate) {
+                        Some(timing) => {
+                            self.sink.configure(&timing);
+                            self.player.set_timing(&timing);
+                        }
+                        None => defmt::error!("No PWM timing for {}Hz at {}Hz!", output_rate, sys_freq),
+                    },
+                    other => defmt::error!("Expected a system clock after a track start, got {}!", other),
+                }
+                self.sink.set_enabled(true);
+
+                self.stopped = false;
+                self.starved = false;
+            }
+            // Only valid right after a `TrackStart`, which takes it
+            Command::SystemClock { .. } => defmt::error!("Got a system clock without a track start!"),
+            // Pausing holds the output once it faded out
+            Command::Pause => {
+                self.paused = true;
+                self.player.fade().fade_out();
+            }
+            Command::Resume => {
+                self.paused = false;
+                self.player.fade().fade_in();
+            }
+            // Dropping the samples right away would cut the sound off with a click,
+            // so they are dropped once it faded out
+            Command::Stop | Command::Seek { .. } | Command::Flush => {
+                self.player.fade().fade_out();
+                if self.is_ready_for(command) {
+                    self.flush(command, channel);
+                } else {
+                    self.pending = Some(command);
+                }
+            }
+            Command::SetVolume { gain } => self.player.set_gain(gain),
+            // Going from mid-scale to 0 thumps like switching off, unless it ramps
+            Command::Idle => {
+                if self.stopped {
+                    self.ramp.start(self.duty, (0, 0), self.frames(self.fades.ramp_ms));
+                }
+            }
+        }
+    }
+
+    /// Whether a command that waits can be handled now.
+    fn is_ready_for(&self, command: Command) -> bool {
+        match command {
+            Command::TrackStart { .. } => !self.stopped || self.duty == self.player.mid_scale(),
+            _ => self.stopped || self.player.is_silent(),
+        }
+    }
+
+    /// Frames of output that take `ms`.
+    fn frames(&self, ms: u32) -> u32 {
+        self.output_rate / 1_000 * ms
+    }
+
+    /// Drops all samples for a `Stop`, `Seek` or `Flush`, the ones that follow fade in.
+    fn flush(&mut self, command: Command, channel: &mut impl CommandChannel) {
+        self.source.clear();
+        self.player.flush();
+        match command {
+            // The last sample can be anywhere, go over to mid-scale where the next track starts from
+            Command::Stop => {
+                self.stopped = true;
+                self.ramp.start(self.duty, self.player.mid_scale(), self.frames(self.fades.fade_ms));
+            }
+            Command::Seek { frame } => self.player.set_position(frame),
+            _ => {}
+        }
+        self.fade_in();
+        channel.send(Event::Flushed);
+    }
+
+    /// Fades in from silence, once not paused.
+    fn fade_in(&mut self) {
+        let fade = self.player.fade();
+        fade.silence();
+        if !self.paused {
+            fade.fade_in();
+        }
+    }
+
+    /// Pushes `space` duty values into the sink.
+    fn fill(&mut self, space: usize, channel: &mut impl CommandChannel) {
+        for filled in 0..space {
+            // Handle the command that waited once the sound faded out for it, a track start waits for the next step
+            if let Some(command) = self.pending.filter(|&command| !matches!(command, Command::TrackStart { .. }) && self.is_ready_for(command)) {
+                self.pending = None;
+                self.handle_command(command, channel);
+            }
+
+            // Hold the last duty values while paused, and ramp them to where the output rests while stopped
+            if !self.is_playing() {
+                for _ in filled..space {
+                    if self.stopped {
+                        self.duty = self.ramp.next_value();
+                    }
+                    self.sink.push(self.duty);
+                }
+                return;
+            }
+
+            // Get more samples if we're out
+            if self.player.needs_more_data() {
+                let source = &mut self.source;
+                self.player.refill(|buffer| source.read(buffer));
+            }
+
+            // Core 1 didn't keep up, or the track is over
+            if self.player.needs_more_data() {
+                // Nothing is left to fade out with
+                if let Some(command) = self.pending.take() {
+                    self.flush(command, channel);
+                }
+                if !self.starved {
+                    self.starved = true;
+                    channel.notify(Event::Underrun);
+                }
+                for _ in filled..space {
+                    self.sink.push(self.duty);
+                }
+                break;
+            }
+            self.starved = false;
+
+            self.duty = self.player.get_next_sample();
+            self.sink.push(self.duty);
+        }
+
+        // Let core 1 know how far along the track we are every now and then
+        self.steps_since_report += 1;
+        if self.steps_since_report >= POSITION_REPORT_STEPS {
+            self.steps_since_report = 0;
+            channel.notify(Event::PositionReport { frame: self.player.position() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque};
+
+    use super::*;
+    use crate::{io::mock::MemorySink, pcm::{ChannelMode, PcmFormat, SampleFormat}, resample::Quality};
+
+    const SPACE: usize = 4;
+    const SILENCE: (u16, u16) = (2048, 2048);
+    const SYS_FREQ: u32 = 131_000_000;
+    const NO_FADES: Fades = Fades { fade_ms: 0, ramp_ms: 0 };
+
+    /// Commands waiting to be received, and the events that were sent. Like core 1, it only
+    /// sends the next of `replies` once the output stopped for it.
+    #[derive(Default)]
+    struct QueueChannel {
+        commands: VecDeque<Command>,
+        replies: VecDeque<Command>,
+        events: Vec<Event>,
+    }
+
+    impl CommandChannel for QueueChannel {
+        fn try_receive(&mut self) -> Option<Command> {
+            self.commands.pop_front()
+        }
+
+        fn receive(&mut self) -> Command {
+            self.commands.pop_front().expect("waited for a command that never comes")
+        }
+
+        fn notify(&mut self, event: Event) {
+            self.events.push(event);
+        }
+
+        fn send(&mut self, event: Event) {
+            if event == Event::OutputStopped {
+                self.commands.extend(self.replies.pop_front());
+            }
+            self.events.push(event);
+        }
+    }
+
+    fn track_start() -> [Command; 2] {
+        let format = PcmFormat { sample_format: SampleFormat::U8, channels: 1 };
+        [
+            Command::TrackStart { format, sample_rate: 32_000, output_rate: 32_000 },
+            Command::SystemClock { sys_freq: SYS_FREQ },
+        ]
+    }
+
+    type TestPlayback<'a, 'buf, F> = Playback<'buf, MemorySink<F>, &'a [u8]>;
+
+    /// Playback at 32kHz with an empty source, that pushes its duty values onto `output`.
+    fn playback<'a, 'buf>(buf: &'buf mut [u8], output: &'a RefCell<Vec<(u16, u16)>>, fades: Fades) -> (TestPlayback<'a, 'buf, impl FnMut((u16, u16)) + 'a>, QueueChannel) {
+        let player = WAVStreamPlayer::new(buf, ChannelMode::Downmix, Quality::Linear);
+        let sink = MemorySink::new(SPACE, |duty| output.borrow_mut().push(duty));
+        (Playback::new(player, sink, &[][..], 32_000, fades), QueueChannel::default())
+    }
+
+    /// Starts a track with `samples` in the source, which a track start empties.
+    fn start<'a>(playback: &mut TestPlayback<'a, '_, impl FnMut((u16, u16))>, channel: &mut QueueChannel, samples: &'a [u8]) {
+        let [track_start, system_clock] = track_start();
+        channel.commands.push_back(system_clock);
+        playback.handle_command(track_start, channel);
+        playback.source = samples;
+    }
+
+    /// Queues `commands`, and runs `steps`.
+    fn run(playback: &mut TestPlayback<'_, '_, impl FnMut((u16, u16))>, channel: &mut QueueChannel, commands: &[Command], steps: usize) {
+        channel.commands.extend(commands);
+        for _ in 0..steps {
+            playback.step(channel);
+        }
+    }
+
+    #[test]
+    fn starts_tracks_after_the_clock_is_known() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        let (mut playback, mut channel) = playback(&mut buf, &output, NO_FADES);
+        playback.source = &[0x80; 8];
+
+        // Nothing plays before the first track
+        run(&mut playback, &mut channel, &[], 1);
+        assert_eq!(*output.borrow(), [SILENCE; SPACE]);
+        assert!(!playback.is_playing());
+
+        // Samples that were there before the track started don't belong to it
+        run(&mut playback, &mut channel, &track_start(), 1);
+        assert_eq!(channel.events, [Event::OutputStopped, Event::Underrun]);
+        assert_eq!(playback.sink().timing, PwmTiming::for_rate(SYS_FREQ, 32_000));
+        assert!(playback.sink().enabled);
+        assert!(playback.is_playing());
+    }
+
+    #[test]
+    fn holds_the_last_value_when_samples_run_out() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        let (mut playback, mut channel) = playback(&mut buf, &output, NO_FADES);
+
+        start(&mut playback, &mut channel, &[0xFF; 6]);
+        run(&mut playback, &mut channel, &[], 3);
+
+        let output = output.borrow();
+        let last = *output.last().unwrap();
+        assert_ne!(last, SILENCE);
+        assert!(output.iter().skip_while(|&&duty| duty != last).all(|&duty| duty == last));
+        // Only reported once, not for every step that had nothing
+        assert_eq!(channel.events, [Event::OutputStopped, Event::Underrun]);
+    }
+
+    #[test]
+    fn pause_holds_and_resume_continues() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        let (mut playback, mut channel) = playback(&mut buf, &output, NO_FADES);
+
+        start(&mut playback, &mut channel, &[0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        run(&mut playback, &mut channel, &[Command::Pause], 2);
+        assert!(output.borrow().iter().all(|&duty| duty == SILENCE));
+
+        run(&mut playback, &mut channel, &[Command::Resume], 1);
+        assert!(output.borrow()[2 * SPACE..].iter().all(|&duty| duty != SILENCE));
+    }
+
+    #[test]
+    fn fades_out_before_pausing_and_flushing() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        // 1ms is 32 frames at 32kHz, 8 steps
+        let (mut playback, mut channel) = playback(&mut buf, &output, Fades { fade_ms: 1, ramp_ms: 0 });
+
+        start(&mut playback, &mut channel, &[0xFF; 256]);
+        run(&mut playback, &mut channel, &[], 8);
+        let faded_in: Vec<u16> = output.borrow().iter().map(|&(a, _)| a).collect();
+        assert!(faded_in.windows(2).all(|pair| pair[0] < pair[1]), "{faded_in:?}");
+
+        run(&mut playback, &mut channel, &[Command::Pause], 10);
+        let paused = output.borrow()[8 * SPACE..].to_vec();
+        let mid_scale = *paused.last().unwrap();
+        assert!(paused[..32].windows(2).all(|pair| pair[0] > pair[1]), "{paused:?}");
+        assert!(paused[32..].iter().all(|&duty| duty == mid_scale));
+        assert!(!playback.is_playing());
+
+        // Seeking while it plays waits for the fade out
+        run(&mut playback, &mut channel, &[Command::Resume], 10);
+        run(&mut playback, &mut channel, &[Command::Seek { frame: 0 }], 1);
+        assert_eq!(channel.events.last(), Some(&Event::OutputStopped));
+        run(&mut playback, &mut channel, &[], 8);
+        assert!(channel.events.ends_with(&[Event::Flushed, Event::Underrun]), "{:?}", channel.events);
+    }
+
+    #[test]
+    fn ramps_to_mid_scale_and_back_down_when_idle() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        // 1ms is 32 frames at 32kHz, 8 steps
+        let fades = Fades { fade_ms: 1, ramp_ms: 1 };
+        let (mut playback, mut channel) = playback(&mut buf, &output, fades);
+
+        // Up from 0 after power up, and the first track waits for it
+        let [track_start, system_clock] = track_start();
+        channel.replies.push_back(system_clock);
+        run(&mut playback, &mut channel, &[track_start], 9);
+        assert!(channel.events.is_empty());
+        assert_eq!(output.borrow().len(), 9 * SPACE);
+        // It starts on the step after the ramp got to mid-scale, not halfway through filling the sink
+        run(&mut playback, &mut channel, &[], 1);
+        let booted: Vec<u16> = output.borrow().iter().map(|&(a, _)| a).collect();
+        assert_eq!(booted[0], 0);
+        assert!(booted[..33].windows(2).all(|pair| pair[0] < pair[1]), "{booted:?}");
+        assert_eq!(booted[32], SILENCE.0);
+        assert_eq!(channel.events, [Event::OutputStopped, Event::Underrun]);
+        assert!(playback.is_playing());
+
+        // Stopping goes over to mid-scale, where the player's is now
+        playback.source = &[0xFF; 64];
+        run(&mut playback, &mut channel, &[], 8);
+        run(&mut playback, &mut channel, &[Command::Stop], 20);
+        let mid_scale = playback.player.mid_scale();
+        assert_eq!(*output.borrow().last().unwrap(), mid_scale);
+        assert!(!playback.is_playing());
+
+        // Down to 0 while idle, and back up before the next track
+        run(&mut playback, &mut channel, &[Command::Idle], 10);
+        assert_eq!(*output.borrow().last().unwrap(), (0, 0));
+        let from = output.borrow().len();
+        channel.replies.push_back(system_clock);
+        run(&mut playback, &mut channel, &[track_start], 12);
+        let woken = output.borrow()[from..].to_vec();
+        assert!(woken[..33].windows(2).all(|pair| pair[0] <= pair[1]), "{woken:?}");
+        assert_eq!(woken[32], mid_scale);
+        assert!(playback.is_playing());
+    }
+
+    #[test]
+    fn seek_and_stop_flush_the_source() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        let (mut playback, mut channel) = playback(&mut buf, &output, NO_FADES);
+
+        start(&mut playback, &mut channel, &[0x80; 64]);
+        run(&mut playback, &mut channel, &[Command::Seek { frame: 1_000 }], 1);
+        assert!(playback.source.is_empty());
+        assert_eq!(playback.player.position(), 1_000);
+        assert_eq!(channel.events, [Event::OutputStopped, Event::Flushed, Event::Underrun]);
+
+        run(&mut playback, &mut channel, &[Command::Stop], 1);
+        assert!(!playback.is_playing());
+        assert_eq!(channel.events.last(), Some(&Event::Flushed));
+    }
+
+    #[test]
+    fn reports_underruns_of_the_sink() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        let (mut playback, mut channel) = playback(&mut buf, &output, NO_FADES);
+
+        playback.sink().underrun = true;
+        run(&mut playback, &mut channel, &[], 2);
+        assert_eq!(channel.events, [Event::Underrun]);
+    }
+
+    #[test]
+    fn reports_the_position_while_playing() {
+        let output = RefCell::new(Vec::new());
+        let mut buf = [0; 16];
+        let (mut playback, mut channel) = playback(&mut buf, &output, NO_FADES);
+
+        let samples = [0x80; SPACE * POSITION_REPORT_STEPS as usize + 1];
+        start(&mut playback, &mut channel, &samples);
+        run(&mut playback, &mut channel, &[], POSITION_REPORT_STEPS as usize);
+        assert!(matches!(channel.events[..], [Event::OutputStopped, Event::PositionReport { frame }] if frame >= (SPACE as u32) * (POSITION_REPORT_STEPS - 1)));
+    }
+}
@@ -1,37 +1,217 @@
-use core::{iter::Cycle, ops::Range};
+use core::{convert::Infallible, iter::Cycle, ops::Range};
 
-use rp2040_hal::pwm::{FreeRunning, Slice, SliceId};
+use crate::pcm::SampleFormat;
 
+use super::wav_streaming::WAVStreamPlayer;
+
+/// `wFormatTag` of plain integer PCM data
+pub const WAVE_FORMAT_PCM: u16 = 0x0001;
+/// `wFormatTag` of files that store the real format tag in their `SubFormat` GUID
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Size of the RIFF header (`RIFF`, size, `WAVE`)
+const RIFF_HEADER_LEN: u32 = 12;
+/// Size of a chunk header (four character code and size)
+const CHUNK_HEADER_LEN: u32 = 8;
+/// Size of the fields every `fmt ` chunk has
+const FMT_CHUNK_MIN_LEN: u32 = 16;
+/// Size of a `fmt ` chunk using `WAVE_FORMAT_EXTENSIBLE`
+const FMT_CHUNK_EXTENSIBLE_LEN: u32 = 40;
+
+/// Sample format as described by the `fmt ` chunk of a WAV file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct WavFormat {
+    /// Encoding of the samples, with `WAVE_FORMAT_EXTENSIBLE` already resolved
+    pub format_tag: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    /// Size of one frame (a sample for every channel) in bytes
+    pub block_align: u16,
+}
+
+/// Everything needed to play a WAV file: its format and where the samples are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct WavHeader {
+    pub format: WavFormat,
+    /// Offset of the first sample from the start of the file
+    pub data_offset: u32,
+    /// Length of the sample data in bytes
+    pub data_length: u32,
+}
+
+/// Reasons a WAV file can not be parsed or played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum WavError<E> {
+    /// Reading the file failed
+    Io(E),
+    /// The file does not start with a `RIFF` header
+    NotRiff,
+    /// The RIFF file is not of the `WAVE` form
+    NotWave,
+    /// The `fmt ` chunk is too short or contradicts itself
+    InvalidFmtChunk,
+    /// A `data` chunk was found before any `fmt ` chunk
+    MissingFmtChunk,
+    /// The file ended before a `data` chunk was found
+    MissingDataChunk,
+    /// The samples are not integer PCM
+    UnsupportedEncoding(u16),
+    /// The player can not play this many bits per sample
+    UnsupportedBitDepth(u16),
+    /// The player can not play this many channels
+    UnsupportedChannels(u16),
+    /// There is no system clock and PWM setting that plays this sample rate
+    UnsupportedSampleRate(u32),
+}
+
+impl<E> From<E> for WavError<E> {
+    fn from(error: E) -> Self {
+        WavError::Io(error)
+    }
+}
+
+/// Parses the RIFF/WAVE header of a file by walking its chunks.
+///
+/// `read_at` fills the buffer with bytes starting at the given offset and returns
+/// how many it read, where reading less than asked means the file ended.
+/// Chunks other than `fmt ` and `data` (`LIST`, `fact`, `JUNK`, ...) are skipped.
+pub fn parse_header<E>(mut read_at: impl FnMut(u32, &mut [u8]) -> Result<usize, E>) -> Result<WavHeader, WavError<E>> {
+    let mut riff = [0u8; RIFF_HEADER_LEN as usize];
+    if read_at(0, &mut riff)? < riff.len() || &riff[0..4] != b"RIFF" {
+        return Err(WavError::NotRiff);
+    }
+    if &riff[8..12] != b"WAVE" {
+        return Err(WavError::NotWave);
+    }
+
+    let mut format: Option<WavFormat> = None;
+    let mut offset = RIFF_HEADER_LEN;
+    loop {
+        let mut chunk = [0u8; CHUNK_HEADER_LEN as usize];
+        if read_at(offset, &mut chunk)? < chunk.len() {
+            return Err(WavError::MissingDataChunk);
+        }
+        let id = &chunk[0..4];
+        let size = read_u32(&chunk, 4);
+        let body_offset = offset + CHUNK_HEADER_LEN;
+
+        match id {
+            b"fmt " => {
+                if size < FMT_CHUNK_MIN_LEN {
+                    return Err(WavError::InvalidFmtChunk);
+                }
+                let mut fmt = [0u8; FMT_CHUNK_EXTENSIBLE_LEN as usize];
+                let wanted = size.min(FMT_CHUNK_EXTENSIBLE_LEN) as usize;
+                if read_at(body_offset, &mut fmt[..wanted])? < wanted {
+                    return Err(WavError::InvalidFmtChunk);
+                }
+                format = Some(parse_fmt(&fmt[..wanted])?);
+            }
+            b"data" => {
+                let Some(format) = format else {
+                    return Err(WavError::MissingFmtChunk);
+                };
+                // Only play whole frames, some encoders leave a partial one at the end
+                let data_length = size - size % format.block_align as u32;
+                return Ok(WavHeader { format, data_offset: body_offset, data_length });
+            }
+            _ => {}
+        }
+
+        // Chunks are padded to an even size
+        offset = body_offset
+            .checked_add(size)
+            .and_then(|end| end.checked_add(size & 1))
+            .ok_or(WavError::MissingDataChunk)?;
+    }
+}
+
+/// Parses the header of a WAV file that is fully in memory.
+pub fn parse_header_from_slice(file: &[u8]) -> Result<WavHeader, WavError<Infallible>> {
+    let header = parse_header(|offset, buf| {
+        let start = (offset as usize).min(file.len());
+        let amount = buf.len().min(file.len() - start);
+        buf[..amount].copy_from_slice(&file[start..start + amount]);
+        Ok(amount)
+    })?;
+
+    // The data chunk may claim to be longer than the file when it got cut off
+    let available = (file.len() as u32).saturating_sub(header.data_offset) / header.format.block_align as u32;
+    let data_length = header.data_length.min(available * header.format.block_align as u32);
+    Ok(WavHeader { data_length, ..header })
+}
+
+fn parse_fmt<E>(fmt: &[u8]) -> Result<WavFormat, WavError<E>> {
+    let mut format_tag = read_u16(fmt, 0);
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        if fmt.len() < FMT_CHUNK_EXTENSIBLE_LEN as usize {
+            return Err(WavError::InvalidFmtChunk);
+        }
+        // The first two bytes of the SubFormat GUID are the actual format tag
+        format_tag = read_u16(fmt, 24);
+    }
+
+    let format = WavFormat {
+        format_tag,
+        channels: read_u16(fmt, 2),
+        sample_rate: read_u32(fmt, 4),
+        block_align: read_u16(fmt, 12),
+        bits_per_sample: read_u16(fmt, 14),
+    };
+
+    let bytes_per_sample = format.bits_per_sample.div_ceil(8);
+    if format.channels == 0
+        || format.sample_rate == 0
+        || format.bits_per_sample == 0
+        || format.block_align == 0
+        || format.block_align as u32 != format.channels as u32 * bytes_per_sample as u32
+    {
+        return Err(WavError::InvalidFmtChunk);
+    }
+
+    Ok(format)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+
+/// Plays WAV 8 bit unsigned mono files from memory at 32kHz
 pub struct WAVPlayer<'buf> {
     counter: Cycle<Range<usize>>,
     current_sample: usize,
+    data_offset: usize,
     buffer: &'buf[u8],
 }
 
 impl WAVPlayer<'_> {
-    pub fn new<'buf>(buffer: &'buf[u8]) -> WAVPlayer<'buf> {
-        WAVPlayer {
-            counter: (0x2C..buffer.len()).cycle().into_iter(),
+    pub fn new<'buf>(buffer: &'buf[u8]) -> Result<WAVPlayer<'buf>, WavError<Infallible>> {
+        let header = parse_header_from_slice(buffer)?;
+        // Of what the streaming player plays, this one only plays 8 bit mono
+        let format = WAVStreamPlayer::supports(&header.format)?;
+        if format.sample_format != SampleFormat::U8 {
+            return Err(WavError::UnsupportedBitDepth(header.format.bits_per_sample));
+        }
+        if format.channels != 1 {
+            return Err(WavError::UnsupportedChannels(header.format.channels));
+        }
+
+        let data_offset = header.data_offset as usize;
+        let data_end = data_offset + header.data_length as usize;
+        Ok(WAVPlayer {
+            counter: (data_offset..data_end).cycle(),
             current_sample: 0,
+            data_offset,
             buffer,
-        }
+        })
     }
 
-    pub fn init<PWM: SliceId>(&self, pwm: &mut Slice<PWM, FreeRunning>) -> () {
-        pwm.default_config();
-    
-        // 131,000,000 Hz divided by (top * div.int).
-        //
-        // fPWM = fSYS / ((TOP + 1) * (CSR_PH_CORRECT + 1) * (DIV_INT + (DIV_FRAC / 16)))
-        //
-        // 32kHz ~= 131,000,000 / ((4096 + 1) * 1 * 1)
-        const TOP: u16 = 4096;
-        pwm.set_top(TOP);
-        pwm.set_div_int(1);
-    
-        pwm.enable_interrupt();
-        pwm.enable();
-    }
     
     pub fn get_next_sample(&mut self) -> u16 {
         let sample = self.counter.next().unwrap();
@@ -39,30 +219,127 @@ impl WAVPlayer<'_> {
 
         let raw_value = self.buffer[sample];
 
-        // Rescale from unsigned u8 numbers to 0..4096 (the TOP register we specified earlier)
+        // Rescale from unsigned u8 numbers to 0..4096 (the TOP register of a PWM at 32kHz)
         //
         // The PWM channel will increment an internal counter register, and if the counter is
         // above or equal to this number, the PWM will output a logic high signal.
-        let mut value = ((raw_value as u16) << 4) & 0xFFF;
+        let value = ((raw_value as u16) << 4) & 0xFFF;
 
         // Half value to reduce loudness
-        value = value >> 1;
-
-        value
+        value >> 1
     }
     
-    pub fn await_next_tick(&self) -> () {
-        // Throttle until the PWM channel delivers us an interrupt saying it's done
-        // with this cycle (the internal counter wrapped). The interrupt handler will
-        // clear the interrupt and we'll send out the next sample.
-        cortex_m::asm::wfi();
-    }
-
     pub fn get_current_sample(&self) -> usize {
         self.current_sample
     }
 
-    pub fn reset(&mut self) -> () {
-        self.counter.find(|&x| x == 0x2C);
+    pub fn reset(&mut self) {
+        let data_offset = self.data_offset;
+        self.counter.find(|&x| x == data_offset);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a WAV file from a `fmt ` chunk body, extra chunks in front of it and sample data.
+    fn wav_file(fmt: &[u8], extra_chunks: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+        file.extend_from_slice(extra_chunks);
+        file.extend_from_slice(b"fmt ");
+        file.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        file.extend_from_slice(fmt);
+        file.extend_from_slice(b"data");
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(data);
+        file
+    }
+
+    fn fmt(format_tag: u16, channels: u16, sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+        let block_align = channels * bits_per_sample.div_ceil(8);
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&format_tag.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&sample_rate.to_le_bytes());
+        fmt.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&bits_per_sample.to_le_bytes());
+        fmt
+    }
+
+    #[test]
+    fn parses_a_plain_header() {
+        let file = wav_file(&fmt(WAVE_FORMAT_PCM, 2, 44_100, 16), &[], &[0; 8]);
+        let header = parse_header_from_slice(&file).unwrap();
+        assert_eq!(header.format, WavFormat {
+            format_tag: WAVE_FORMAT_PCM,
+            channels: 2,
+            sample_rate: 44_100,
+            bits_per_sample: 16,
+            block_align: 4,
+        });
+        assert_eq!(header.data_offset, 44);
+        assert_eq!(header.data_length, 8);
+    }
+
+    #[test]
+    fn skips_other_chunks_with_their_padding() {
+        let list = b"LIST\x03\0\0\0abc\0";
+        let file = wav_file(&fmt(WAVE_FORMAT_PCM, 1, 8_000, 8), list, &[1, 2, 3]);
+        let header = parse_header_from_slice(&file).unwrap();
+        assert_eq!(header.data_offset as usize, file.len() - 3);
+        assert_eq!(header.format.sample_rate, 8_000);
+    }
+
+    #[test]
+    fn resolves_extensible_formats() {
+        let mut extensible = fmt(WAVE_FORMAT_EXTENSIBLE, 2, 48_000, 16);
+        extensible.extend_from_slice(&22u16.to_le_bytes());
+        extensible.extend_from_slice(&[0; 6]);
+        extensible.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+        extensible.extend_from_slice(&[0; 14]);
+        let file = wav_file(&extensible, &[], &[0; 4]);
+        assert_eq!(parse_header_from_slice(&file).unwrap().format.format_tag, WAVE_FORMAT_PCM);
+    }
+
+    #[test]
+    fn cuts_data_to_whole_frames_in_the_file() {
+        let mut file = wav_file(&fmt(WAVE_FORMAT_PCM, 2, 44_100, 16), &[], &[0; 10]);
+        file.truncate(file.len() - 3);
+        assert_eq!(parse_header_from_slice(&file).unwrap().data_length, 4);
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert_eq!(parse_header_from_slice(b"RIFX\0\0\0\0WAVE"), Err(WavError::NotRiff));
+        assert_eq!(parse_header_from_slice(b"RIFF\0\0\0\0AVI "), Err(WavError::NotWave));
+        assert_eq!(parse_header_from_slice(b"RIFF\0\0\0\0WAVE"), Err(WavError::MissingDataChunk));
+        let mut no_fmt = b"RIFF\0\0\0\0WAVE".to_vec();
+        no_fmt.extend_from_slice(b"data\0\0\0\0");
+        assert_eq!(parse_header_from_slice(&no_fmt), Err(WavError::MissingFmtChunk));
+        let mut mono_with_stereo_frames = fmt(WAVE_FORMAT_PCM, 1, 8_000, 16);
+        mono_with_stereo_frames[12] = 4;
+        let file = wav_file(&mono_with_stereo_frames, &[], &[]);
+        assert_eq!(parse_header_from_slice(&file), Err(WavError::InvalidFmtChunk));
+    }
+
+    #[test]
+    fn player_loops_over_the_data_chunk() {
+        let list = b"LIST\x02\0\0\0ab";
+        let file = wav_file(&fmt(WAVE_FORMAT_PCM, 1, 32_000, 8), list, &[0x00, 0x80, 0xFF]);
+        let mut player = WAVPlayer::new(&file).unwrap();
+        let samples: Vec<u16> = (0..4).map(|_| player.get_next_sample()).collect();
+        assert_eq!(samples, [0x000, 0x400, 0x7F8, 0x000]);
+        assert_eq!(player.get_current_sample(), file.len() - 3);
+    }
+
+    #[test]
+    fn player_only_plays_8_bit_mono() {
+        let file = wav_file(&fmt(WAVE_FORMAT_PCM, 1, 32_000, 16), &[], &[0; 2]);
+        assert!(matches!(WAVPlayer::new(&file), Err(WavError::UnsupportedBitDepth(16))));
+        let file = wav_file(&fmt(WAVE_FORMAT_PCM, 2, 32_000, 8), &[], &[0; 2]);
+        assert!(matches!(WAVPlayer::new(&file), Err(WavError::UnsupportedChannels(2))));
     }
 }
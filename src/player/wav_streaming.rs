@@ -1,60 +1,236 @@
-use rp2040_hal::pwm::{FreeRunning, Slice, SliceId};
+use crate::{fade::Fade, pcm::{s16_to_pwm, scale_to_top, ChannelMode, PcmFormat, SampleFormat}, protocol::UNITY_GAIN, pwm_timing::{PwmTiming, MAX_TOP}, resample::{Quality, Resampler}, ring_buffer::RingBuffer};
 
-/// Plays WAV 8 bit unsigned mono files at 32kHz
+use super::wav::{WavError, WavFormat, WAVE_FORMAT_PCM};
+
+/// Size of the ring buffer samples stream through from core 1 to core 0, 16 SD card blocks
+pub const SAMPLE_RING_SIZE: usize = 8192;
+/// Shared memory samples stream through from core 1 to core 0.
+/// The SIO FIFO only carries `protocol` messages.
+pub type SampleRing = RingBuffer<SAMPLE_RING_SIZE>;
+
+/// Plays WAV 8 bit unsigned or 16 bit signed mono and stereo files,
+/// resampled to the rate the PWM is set to when that differs from theirs
 pub struct WAVStreamPlayer<'buf> {
-    pub counter: usize,
-    pub current_buffer: &'buf mut[u8],
+    counter: usize,
+    current_buffer: &'buf mut[u8],
+    /// Bytes of `current_buffer` that hold samples
+    filled: usize,
+    format: PcmFormat,
+    channel_mode: ChannelMode,
+    resampler: Resampler,
+    /// Volume in Q15, `UNITY_GAIN` leaves samples as they are
+    gain: u16,
+    /// Goes on top of the volume
+    fade: Fade,
+    /// Frames of the track that went into the resampler
+    position: u32,
+    top: u16,
 }
 
 impl WAVStreamPlayer<'_> {
-    pub fn new<'buf>(buf: &'buf mut [u8]) -> WAVStreamPlayer<'buf> {
+    pub fn new<'buf>(buf: &'buf mut [u8], channel_mode: ChannelMode, quality: Quality) -> WAVStreamPlayer<'buf> {
         WAVStreamPlayer {
             counter: 0,
             current_buffer: buf,
+            filled: 0,
+            format: PcmFormat { sample_format: SampleFormat::U8, channels: 1 },
+            channel_mode,
+            resampler: Resampler::new(quality),
+            gain: UNITY_GAIN,
+            fade: Fade::new(),
+            position: 0,
+            top: MAX_TOP,
         }
     }
 
-    pub fn init<PWM: SliceId>(&self, pwm: &mut Slice<PWM, FreeRunning>) -> () {
-        pwm.default_config();
-    
-        // 131,000,000 Hz divided by (top * div.int).
-        //
-        // fPWM = fSYS / ((TOP + 1) * (CSR_PH_CORRECT + 1) * (DIV_INT + (DIV_FRAC / 16)))
-        //
-        // 32kHz ~= 131,000,000 / ((4096 + 1) * 1 * 1)
-        const TOP: u16 = 4096;
-        pwm.set_top(TOP);
-        pwm.set_div_int(1);
-    
-        pwm.enable_interrupt();
-        pwm.enable();
-    }
-    
-    pub fn get_next_sample(&mut self) -> u16 {
-        let mut sample = self.counter + 1;
-        self.counter = sample;
-        if sample >= self.current_buffer.len() {
-            sample = 0;
+    /// Checks whether this player can play samples of the given format.
+    pub fn supports<E>(format: &WavFormat) -> Result<PcmFormat, WavError<E>> {
+        if format.format_tag != WAVE_FORMAT_PCM {
+            return Err(WavError::UnsupportedEncoding(format.format_tag));
+        }
+        let Some(sample_format) = SampleFormat::from_bits_per_sample(format.bits_per_sample) else {
+            return Err(WavError::UnsupportedBitDepth(format.bits_per_sample));
+        };
+        if !(1..=2).contains(&format.channels) {
+            return Err(WavError::UnsupportedChannels(format.channels));
+        }
+        Ok(PcmFormat { sample_format, channels: format.channels as u8 })
+    }
+
+    /// Sets the layout of the samples in `current_buffer`.
+    pub fn set_format(&mut self, format: PcmFormat) {
+        self.format = format;
+    }
+
+    /// Sets the rate of the samples in `current_buffer` and the rate the PWM plays at,
+    /// dropping whatever the resampler still holds of the previous track.
+    pub fn set_rates(&mut self, input_rate: u32, output_rate: u32) {
+        let mono = self.format.channels == 1 || self.channel_mode == ChannelMode::Downmix;
+        self.resampler.set_rates(input_rate, output_rate, mono);
+    }
+
+    /// Sets the volume in Q15, `UNITY_GAIN` leaves samples as they are.
+    pub fn set_gain(&mut self, gain: u16) {
+        self.gain = gain;
+    }
+
+    /// The fade every sample goes through, which takes a frame further with every sample.
+    pub fn fade(&mut self) -> &mut Fade {
+        &mut self.fade
+    }
+
+    /// The duty values of silence, half of the PWM period.
+    pub fn mid_scale(&self) -> (u16, u16) {
+        let mid = scale_to_top(s16_to_pwm(0), self.top);
+        (mid, mid)
+    }
+
+    /// Whether the fade turned the sound all the way down.
+    pub fn is_silent(&self) -> bool {
+        self.fade.is_silent()
+    }
+
+    /// Frames of the track that went into the resampler so far.
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// Sets where in the track the samples that follow start.
+    pub fn set_position(&mut self, frame: u32) {
+        self.position = frame;
+    }
+
+    /// Drops all samples, the next ones start from silence.
+    pub fn flush(&mut self) {
+        self.counter = 0;
+        self.filled = 0;
+        self.resampler.reset();
+    }
+
+    /// Tops up `current_buffer` with bytes from `read`, which returns how many it put in.
+    /// A partial frame left at the end is kept and completed.
+    pub fn refill(&mut self, read: impl FnOnce(&mut [u8]) -> usize) {
+        self.current_buffer.copy_within(self.counter..self.filled, 0);
+        self.filled -= self.counter;
+        self.counter = 0;
+        self.filled += read(&mut self.current_buffer[self.filled..]);
+    }
+
+    pub fn channel_mode(&self) -> ChannelMode {
+        self.channel_mode
+    }
+
+    /// Scales duty values to the PWM period of `timing`, which the PWM has to be set to as well.
+    pub fn set_timing(&mut self, timing: &PwmTiming) {
+        self.top = timing.top;
+    }
+
+    /// Whether the next sample needs frames that aren't in `current_buffer`.
+    pub fn needs_more_data(&mut self) -> bool {
+        self.feed();
+        self.resampler.needs_input()
+    }
+
+    /// Hands frames from `current_buffer` to the resampler until it has enough for the next sample.
+    fn feed(&mut self) {
+        let bytes_per_frame = self.format.bytes_per_frame();
+        while self.resampler.needs_input() && self.counter + bytes_per_frame <= self.filled {
+            let frame = self.format.decode(&self.current_buffer[self.counter..]);
+            self.resampler.push(self.channel_mode.apply(frame));
+            self.counter += bytes_per_frame;
+            self.position = self.position.wrapping_add(1);
         }
+    }
 
-        let raw_value = self.current_buffer[sample];
+    /// Returns the duty values for channel A and B.
+    pub fn get_next_sample(&mut self) -> (u16, u16) {
+        self.feed();
 
-        // Rescale from unsigned u8 numbers to 0..4096 (the TOP register we specified earlier)
+        // Rescale to 0..4096
+        let (left, right) = self.resampler.pull();
+        let gain = ((self.gain as u32 * self.fade.next_gain() as u32) >> 15) as u16;
+        let (left, right) = (apply_gain(left, gain), apply_gain(right, gain));
+        let (a, b) = (s16_to_pwm(left), s16_to_pwm(right));
+
+        // Rescale to the TOP register we specified earlier
         //
         // The PWM channel will increment an internal counter register, and if the counter is
         // above or equal to this number, the PWM will output a logic high signal.
-        let mut value = ((raw_value as u16) << 4) & 0xFFF;
+        (scale_to_top(a, self.top), scale_to_top(b, self.top))
+    }
+}
 
-        // Half value to reduce loudness
-        value = value >> 1;
+/// Scales a sample by a Q15 gain.
+fn apply_gain(sample: i16, gain: u16) -> i16 {
+    ((sample as i32 * gain as i32) >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING: PwmTiming = PwmTiming { top: MAX_TOP, div_int: 1, div_frac: 0 };
+    /// Duty value of silence, half of the PWM period
+    const MID_SCALE: u16 = 2048;
+
+    fn player(buf: &mut [u8]) -> WAVStreamPlayer<'_> {
+        let mut player = WAVStreamPlayer::new(buf, ChannelMode::Stereo, Quality::Linear);
+        player.set_format(PcmFormat { sample_format: SampleFormat::U8, channels: 2 });
+        player.set_rates(8_000, 8_000);
+        player.set_timing(&TIMING);
+        player
+    }
 
-        value
+    /// Plays everything `data` holds, refilling in small reads like the ring would hand them out.
+    fn play(player: &mut WAVStreamPlayer, mut data: &[u8]) -> Vec<(u16, u16)> {
+        let mut duty = Vec::new();
+        loop {
+            if player.needs_more_data() {
+                player.refill(|buffer| {
+                    let amount = buffer.len().min(data.len()).min(3);
+                    buffer[..amount].copy_from_slice(&data[..amount]);
+                    data = &data[amount..];
+                    amount
+                });
+            }
+            if player.needs_more_data() {
+                return duty;
+            }
+            duty.push(player.get_next_sample());
+        }
+    }
+
+    #[test]
+    fn plays_frames_split_across_reads() {
+        let mut buf = [0; 16];
+        let mut player = player(&mut buf);
+        let duty = play(&mut player, &[0x80, 0xFF, 0x00, 0x80, 0x80, 0x80]);
+        assert!(duty.contains(&(MID_SCALE, MAX_TOP - 15)));
+        assert!(duty.contains(&(0, MID_SCALE)));
+        assert_eq!(player.position(), 3);
     }
-    
-    pub fn await_next_tick(&self) -> () {
-        // Throttle until the PWM channel delivers us an interrupt saying it's done
-        // with this cycle (the internal counter wrapped). The interrupt handler will
-        // clear the interrupt and we'll send out the next sample.
-        cortex_m::asm::wfi();
+
+    #[test]
+    fn muted_gain_stays_at_mid_scale() {
+        let mut buf = [0; 16];
+        let mut player = player(&mut buf);
+        player.set_gain(0);
+        let duty = play(&mut player, &[0xFF, 0x00, 0x00, 0xFF]);
+        assert!(duty.iter().all(|&values| values == (MID_SCALE, MID_SCALE)));
+    }
+
+    #[test]
+    fn flush_drops_buffered_frames() {
+        let mut buf = [0; 16];
+        let mut player = player(&mut buf);
+        player.refill(|buffer| {
+            buffer[..4].fill(0xFF);
+            4
+        });
+        player.flush();
+        player.set_position(1_000);
+        assert!(player.needs_more_data());
+        assert_eq!(player.position(), 1_000);
     }
 }
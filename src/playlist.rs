@@ -0,0 +1,878 @@
+//! The list of tracks to play, and which one is playing.
+//!
+//! Tracks are kept by their 8.3 file name and the folder they are in, which is all it takes to
+//! open them again, in a fixed amount of memory. They are sorted and shown by their long file
+//! name, of which only the start is kept. Every folder is an album, whose tracks play one after
+//! another, unless they are shuffled.
+
+use core::cmp::Ordering;
+
+use crate::{repeat::Repeat, shuffle::{Shuffle, ShuffleState}};
+
+/// Longest 8.3 file name, "FILENAME.EXT"
+pub const MAX_NAME_LENGTH: usize = 12;
+
+/// A file name of at most `MAX_NAME_LENGTH` bytes.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct TrackName {
+    bytes: [u8; MAX_NAME_LENGTH],
+    length: u8,
+}
+
+impl TrackName {
+    /// Joins a base name and extension as they are stored in a directory entry.
+    /// Returns `None` if they don't fit in an 8.3 name.
+    pub fn from_parts(base_name: &[u8], extension: &[u8]) -> Option<TrackName> {
+        if base_name.len() > 8 || extension.len() > 3 {
+            return None;
+        }
+
+        let mut name = TrackName { bytes: [0; MAX_NAME_LENGTH], length: 0 };
+        name.append(base_name);
+        if !extension.is_empty() {
+            name.append(b".");
+            name.append(extension);
+        }
+        Some(name)
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        let start = self.length as usize;
+        self.bytes[start..start + bytes.len()].copy_from_slice(bytes);
+        self.length += bytes.len() as u8;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    /// The name as a string, FAT names that aren't ASCII come out empty.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+}
+
+impl Ord for TrackName {
+    /// Sorts like a file browser would, ignoring case.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lower = |name: &TrackName| -> [u8; MAX_NAME_LENGTH] { name.bytes.map(|byte| byte.to_ascii_lowercase()) };
+        lower(self)[..self.length as usize]
+            .cmp(&lower(other)[..other.length as usize])
+            .then_with(|| self.as_bytes().cmp(other.as_bytes()))
+    }
+}
+
+impl PartialOrd for TrackName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl core::fmt::Debug for TrackName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Bytes of a long file name that are kept, the rest is only remembered by `LongName::matches`
+pub const MAX_LONG_NAME_LENGTH: usize = 64;
+
+/// A long file name, cut off after `MAX_LONG_NAME_LENGTH` bytes of UTF-8.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LongName {
+    bytes: [u8; MAX_LONG_NAME_LENGTH],
+    length: u8,
+    /// Hash of the whole name ignoring case, so names that only differ after the cut don't match
+    hash: u32,
+}
+
+impl LongName {
+    pub const EMPTY: LongName = LongName { bytes: [0; MAX_LONG_NAME_LENGTH], length: 0, hash: LongName::hash(b"") };
+
+    pub fn new(name: &str) -> LongName {
+        let mut length = name.len().min(MAX_LONG_NAME_LENGTH);
+        while !name.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        let mut long_name = LongName { bytes: [0; MAX_LONG_NAME_LENGTH], length: length as u8, hash: LongName::hash(name.as_bytes()) };
+        long_name.bytes[..length].copy_from_slice(&name.as_bytes()[..length]);
+        long_name
+    }
+
+    /// 32 bit FNV-1a, of the name in lower case like FAT compares names
+    const fn hash(bytes: &[u8]) -> u32 {
+        let mut hash: u32 = 0x811C_9DC5;
+        let mut i = 0;
+        while i < bytes.len() {
+            hash = (hash ^ bytes[i].to_ascii_lowercase() as u32).wrapping_mul(0x0100_0193);
+            i += 1;
+        }
+        hash
+    }
+
+    /// The start of the name that was kept.
+    pub fn as_str(&self) -> &str {
+        // Only ever cut at a character boundary
+        core::str::from_utf8(&self.bytes[..self.length as usize]).unwrap_or("")
+    }
+
+    /// Whether this is `name`, ignoring case like FAT does.
+    pub fn matches(&self, name: &str) -> bool {
+        self.hash == LongName::hash(name.as_bytes())
+            && name.as_bytes().get(..self.length as usize).is_some_and(|start| start.eq_ignore_ascii_case(self.as_str().as_bytes()))
+    }
+}
+
+impl From<TrackName> for LongName {
+    /// The name of a file without a long file name.
+    fn from(name: TrackName) -> LongName {
+        LongName::new(name.as_str())
+    }
+}
+
+impl Ord for LongName {
+    /// Sorts like a file browser would, ignoring case.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lower = |name: &LongName| name.bytes.map(|byte| byte.to_ascii_lowercase());
+        lower(self)[..self.length as usize]
+            .cmp(&lower(other)[..other.length as usize])
+            .then_with(|| self.as_str().cmp(other.as_str()))
+            .then_with(|| self.hash.cmp(&other.hash))
+    }
+}
+
+impl PartialOrd for LongName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl core::fmt::Debug for LongName {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Most folders tracks are kept apart in, the root included
+pub const MAX_FOLDERS: usize = 32;
+/// How many folders deep tracks are looked for below the root
+pub const MAX_DEPTH: usize = 3;
+
+/// The root directory, where every other folder is in
+pub const ROOT: usize = 0;
+
+/// A directory with tracks, played as an album.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Folder {
+    pub name: LongName,
+    /// The name to open it by
+    pub short_name: TrackName,
+    /// Index of the folder this one is in, the root is in itself
+    parent: u8,
+    /// Folders between this one and the root, 0 for the root
+    depth: u8,
+}
+
+/// A track, and the folder it is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub struct Track {
+    /// Index of the folder in the playlist. Folders are numbered in the order they are played,
+    /// so sorting by it first keeps albums together.
+    pub folder: u8,
+    pub name: LongName,
+    /// The name to open it by
+    pub short_name: TrackName,
+}
+
+/// Up to `N` tracks in up to `MAX_FOLDERS` folders, played in order.
+pub struct Playlist<const N: usize> {
+    tracks: [Track; N],
+    length: usize,
+    folders: [Folder; MAX_FOLDERS],
+    folder_count: usize,
+    /// Index of the track that is playing
+    current: usize,
+    /// What plays after a track ends
+    repeat: Repeat,
+    /// Tracks at the start that `place` put in order
+    placed: usize,
+    /// The order tracks play in while shuffling
+    shuffle: Option<Shuffle<N>>,
+}
+
+impl<const N: usize> Playlist<N> {
+    pub const fn new() -> Playlist<N> {
+        const EMPTY: TrackName = TrackName { bytes: [0; MAX_NAME_LENGTH], length: 0 };
+        Playlist {
+            tracks: [Track { folder: ROOT as u8, name: LongName::EMPTY, short_name: EMPTY }; N],
+            length: 0,
+            folders: [Folder { name: LongName::EMPTY, short_name: EMPTY, parent: ROOT as u8, depth: 0 }; MAX_FOLDERS],
+            folder_count: 1,
+            current: 0,
+            repeat: Repeat::Off,
+            placed: 0,
+            shuffle: None,
+        }
+    }
+
+    /// Adds a folder inside `parent`, which the tracks pushed after it go into.
+    /// Add folders in the order they should play, each right before its tracks.
+    ///
+    /// Returns its index, or `None` if there are too many folders or it would be deeper than `MAX_DEPTH`.
+    pub fn add_folder(&mut self, name: LongName, short_name: TrackName, parent: usize) -> Option<usize> {
+        let depth = self.folders.get(parent)?.depth + 1;
+        if self.folder_count == MAX_FOLDERS || parent >= self.folder_count || depth as usize > MAX_DEPTH {
+            return None;
+        }
+        self.folders[self.folder_count] = Folder { name, short_name, parent: parent as u8, depth };
+        self.folder_count += 1;
+        Some(self.folder_count - 1)
+    }
+
+    /// Adds a track to the end of the folder added last, the root if none was.
+    /// Returns `false` if the playlist is full.
+    pub fn push(&mut self, name: LongName, short_name: TrackName) -> bool {
+        if self.length == N {
+            return false;
+        }
+        self.tracks[self.length] = Track { folder: (self.folder_count - 1) as u8, name, short_name };
+        self.length += 1;
+        true
+    }
+
+    /// Drops all tracks and folders, to fill it again from another card.
+    pub fn clear(&mut self) {
+        self.length = 0;
+        self.folder_count = 1;
+        self.current = 0;
+        self.placed = 0;
+        self.shuffle = None;
+    }
+
+    /// Sorts the tracks by folder, and by long name within them, and starts over at the first one.
+    pub fn sort(&mut self) {
+        self.tracks[..self.length].sort_unstable();
+        self.current = 0;
+        self.placed = 0;
+        self.shuffle = None;
+    }
+
+    /// Moves the track at `index` after the ones placed before, to play in the order of a
+    /// playlist file. A track that was placed already is added again, to play twice.
+    /// Returns `false` if there is no room for that.
+    pub fn place(&mut self, index: usize) -> bool {
+        if index >= self.length {
+            return false;
+        }
+        if index < self.placed {
+            if self.length == N {
+                return false;
+            }
+            self.tracks.copy_within(self.placed..self.length, self.placed + 1);
+            self.tracks[self.placed] = self.tracks[index];
+            self.length += 1;
+        } else {
+            self.tracks[self.placed..=index].rotate_right(1);
+        }
+        self.placed += 1;
+        true
+    }
+
+    /// Drops the tracks that weren't placed, and starts over at the first one.
+    pub fn keep_placed(&mut self) {
+        self.length = self.placed;
+        self.current = 0;
+        self.shuffle = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks[..self.length]
+    }
+
+    pub fn folder(&self, index: usize) -> Option<&Folder> {
+        self.folders[..self.folder_count].get(index)
+    }
+
+    /// Finds the folder called `name` in `parent`, by its long or short name.
+    pub fn find_folder(&self, parent: usize, name: &str) -> Option<usize> {
+        (1..self.folder_count).find(|&index| {
+            let folder = &self.folders[index];
+            folder.parent as usize == parent && (folder.name.matches(name) || folder.short_name.as_str().eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// Finds a track by its path from `folder`, or from the root if it starts with a `/`.
+    /// Folders are separated by `/`, and can be `.` and `..` like in a file browser.
+    pub fn find_path(&self, folder: usize, path: &str) -> Option<usize> {
+        let (mut folder, path) = match path.strip_prefix('/') {
+            Some(path) => (ROOT, path),
+            None => (folder, path),
+        };
+
+        let mut parts = path.split('/').filter(|&part| !part.is_empty() && part != ".");
+        let mut name = parts.next()?;
+        for next in parts {
+            folder = match name {
+                ".." => self.folders[folder].parent as usize,
+                _ => self.find_folder(folder, name)?,
+            };
+            name = next;
+        }
+        self.find(folder, name)
+    }
+
+    /// Finds the track called `name` in `folder`, by its long or short name.
+    pub fn find(&self, folder: usize, name: &str) -> Option<usize> {
+        self.tracks().iter().position(|track| {
+            track.folder as usize == folder && (track.name.matches(name) || track.short_name.as_str().eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// The folders from the root down to `index`, not including the root itself.
+    pub fn path(&self, index: usize) -> impl Iterator<Item = &Folder> {
+        let mut indices = [ROOT; MAX_DEPTH];
+        let mut depth = 0;
+        let mut folder = index.min(self.folder_count - 1);
+        while folder != ROOT && depth < MAX_DEPTH {
+            indices[depth] = folder;
+            depth += 1;
+            folder = self.folders[folder].parent as usize;
+        }
+        indices.into_iter().take(depth).rev().map(|index| &self.folders[index])
+    }
+
+    /// Position of the track that is playing.
+    pub fn index(&self) -> usize {
+        self.current
+    }
+
+    /// Goes to the track at `index`. Returns `false` if there is none, staying where it is.
+    pub fn select(&mut self, index: usize) -> bool {
+        if index >= self.length {
+            return false;
+        }
+        self.current = index;
+        true
+    }
+
+    /// A hash of all tracks in order, that tells apart cards with different tracks.
+    pub fn fingerprint(&self) -> u32 {
+        // 32 bit FNV-1a, with a separator after every name so they can't run into each other
+        let mut hash: u32 = 0x811C_9DC5;
+        for track in self.tracks() {
+            for &byte in [track.folder].iter().chain(track.short_name.as_bytes()).chain(b"/") {
+                hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+            }
+        }
+        hash
+    }
+
+    /// The track that is playing, `None` if there are no tracks.
+    pub fn current(&self) -> Option<&Track> {
+        self.tracks().get(self.current)
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    /// Sets what `advance` goes to after a track ended. While repeating a folder, skipping tracks
+    /// also stays in the folder of the current track.
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// What the shuffled order is made from, `None` while playing in order.
+    pub fn shuffle(&self) -> Option<ShuffleState> {
+        self.shuffle.as_ref().map(Shuffle::state)
+    }
+
+    /// Plays the tracks in the order of `state`, or in their own order again with `None`. The
+    /// current track stays, and the tracks after it are the ones after it in the new order.
+    /// Repeating a folder still plays it in order. Changing the tracks stops shuffling.
+    pub fn set_shuffle(&mut self, state: Option<ShuffleState>) {
+        self.shuffle = state.map(|state| Shuffle::new(self.length, state));
+    }
+
+    /// The shuffled order and where the current track is in it, unless repeating a folder.
+    fn shuffled(&mut self) -> Option<(&mut Shuffle<N>, usize)> {
+        let shuffle = self.shuffle.as_mut().filter(|_| self.repeat != Repeat::Folder)?;
+        let position = shuffle.position(self.current).unwrap_or(0);
+        Some((shuffle, position))
+    }
+
+    /// The tracks of the folder the track at `index` is in, as a range of indices.
+    fn folder_range(&self, index: usize) -> (usize, usize) {
+        let tracks = self.tracks();
+        let folder = tracks[index].folder;
+        let start = tracks[..index].iter().rposition(|track| track.folder != folder).map_or(0, |before| before + 1);
+        let end = tracks[index..].iter().position(|track| track.folder != folder).map_or(self.length, |after| index + after);
+        (start, end)
+    }
+
+    /// The tracks `next` and `previous` go around in.
+    fn skip_range(&self) -> (usize, usize) {
+        if self.repeat == Repeat::Folder {
+            self.folder_range(self.current)
+        } else {
+            (0, self.length)
+        }
+    }
+
+    /// Moves on to the track that follows the one that ended, as `repeat` says.
+    /// Returns `false` after the last one, which never comes while repeating.
+    pub fn advance(&mut self) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        match self.repeat {
+            Repeat::Off => {}
+            Repeat::One => return true,
+            Repeat::Folder | Repeat::All => {
+                self.next();
+                return true;
+            }
+        }
+        if let Some((shuffle, position)) = self.shuffled() {
+            let Some(&next) = shuffle.order().get(position + 1) else {
+                return false;
+            };
+            self.current = next as usize;
+            return true;
+        }
+        if self.current + 1 >= self.length {
+            return false;
+        }
+        self.current += 1;
+        true
+    }
+
+    /// The track `advance` moves on to, without moving there. `None` after the last track, and where
+    /// a shuffled order goes around, since the order of the next pass isn't drawn until then.
+    pub fn following(&self) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let repeating = match self.repeat {
+            Repeat::Off => false,
+            Repeat::One => return Some(self.current),
+            Repeat::Folder | Repeat::All => true,
+        };
+        if let Some(shuffle) = self.shuffle.as_ref().filter(|_| self.repeat != Repeat::Folder) {
+            let position = shuffle.position(self.current).unwrap_or(0);
+            return shuffle.order().get(position + 1).map(|&next| next as usize);
+        }
+        let (start, end) = self.skip_range();
+        match self.current + 1 {
+            next if next < end => Some(next),
+            _ if repeating => Some(start),
+            _ => None,
+        }
+    }
+
+    /// Skips to the following track, going around to the first after the last one.
+    /// Shuffled tracks are shuffled again when going around.
+    pub fn next(&mut self) {
+        if let Some((shuffle, position)) = self.shuffled() {
+            if position + 1 == shuffle.order().len() {
+                shuffle.next_pass();
+                self.current = shuffle.order()[0] as usize;
+            } else {
+                self.current = shuffle.order()[position + 1] as usize;
+            }
+            return;
+        }
+        if !self.is_empty() {
+            let (start, end) = self.skip_range();
+            self.current = start + (self.current - start + 1) % (end - start);
+        }
+    }
+
+    /// Goes back to the track before, going around to the last before the first one.
+    /// Shuffled tracks go back to the order of the pass before.
+    pub fn previous(&mut self) {
+        if let Some((shuffle, position)) = self.shuffled() {
+            let length = shuffle.order().len();
+            let state = shuffle.state();
+            if position == 0 && state.pass > 0 {
+                *shuffle = Shuffle::new(length, ShuffleState { pass: state.pass - 1, ..state });
+            }
+            self.current = shuffle.order()[(position + length - 1) % length] as usize;
+            return;
+        }
+        if !self.is_empty() {
+            let (start, end) = self.skip_range();
+            self.current = start + (self.current - start + (end - start) - 1) % (end - start);
+        }
+    }
+
+    /// Skips to the first track of the following folder, going around to the first folder after the last one.
+    pub fn next_folder(&mut self) {
+        if !self.is_empty() {
+            let (_, end) = self.folder_range(self.current);
+            self.current = end % self.length;
+        }
+    }
+
+    /// Goes back to the first track of the folder before, going around to the last folder before the first one.
+    pub fn previous_folder(&mut self) {
+        if !self.is_empty() {
+            let (start, _) = self.folder_range(self.current);
+            let before = (start + self.length - 1) % self.length;
+            self.current = self.folder_range(before).0;
+        }
+    }
+}
+
+impl<const N: usize> Default for Playlist<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> TrackName {
+        let (base_name, extension) = name.split_once('.').unwrap_or((name, ""));
+        TrackName::from_parts(base_name.as_bytes(), extension.as_bytes()).unwrap()
+    }
+
+    /// Pushes a track that has no long file name.
+    fn push<const N: usize>(playlist: &mut Playlist<N>, track: &str) -> bool {
+        playlist.push(name(track).into(), name(track))
+    }
+
+    fn playlist(names: &[&str]) -> Playlist<4> {
+        let mut playlist = Playlist::new();
+        for &track in names {
+            assert!(push(&mut playlist, track));
+        }
+        playlist.sort();
+        playlist
+    }
+
+    /// Albums with the tracks in them, pushed the way a directory listing would.
+    fn albums(albums: &[(&str, &[&str])]) -> Playlist<8> {
+        let mut playlist = Playlist::new();
+        for &(folder, tracks) in albums {
+            if !folder.is_empty() {
+                playlist.add_folder(name(folder).into(), name(folder), ROOT).unwrap();
+            }
+            for &track in tracks {
+                assert!(push(&mut playlist, track));
+            }
+        }
+        playlist.sort();
+        playlist
+    }
+
+    fn current<const N: usize>(playlist: &Playlist<N>) -> Option<&str> {
+        playlist.current().map(|track| track.name.as_str())
+    }
+
+    #[test]
+    fn joins_short_names() {
+        assert_eq!(name("DAISIES.WAV").as_str(), "DAISIES.WAV");
+        assert_eq!(name("README").as_str(), "README");
+        assert_eq!(TrackName::from_parts(b"TOOLONGNAME", b"WAV"), None);
+        assert_eq!(TrackName::from_parts(b"SONG", b"WAVE"), None);
+    }
+
+    #[test]
+    fn sorts_by_name_ignoring_case() {
+        let playlist = playlist(&["b.wav", "C.WAV", "A.WAV", "a1.wav"]);
+        let names: Vec<&str> = playlist.tracks().iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, ["A.WAV", "a1.wav", "b.wav", "C.WAV"]);
+    }
+
+    #[test]
+    fn advances_until_the_end() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV"]);
+        assert_eq!(current(&playlist), Some("1.WAV"));
+        assert!(playlist.advance());
+        assert_eq!(current(&playlist), Some("2.WAV"));
+        assert!(!playlist.advance());
+        assert_eq!(current(&playlist), Some("2.WAV"));
+    }
+
+    #[test]
+    fn skipping_goes_around() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV", "3.WAV"]);
+        playlist.previous();
+        assert_eq!(current(&playlist), Some("3.WAV"));
+        playlist.next();
+        assert_eq!(current(&playlist), Some("1.WAV"));
+        playlist.next();
+        assert_eq!(current(&playlist), Some("2.WAV"));
+    }
+
+    #[test]
+    fn refuses_tracks_when_full() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV", "3.WAV", "4.WAV"]);
+        assert!(!push(&mut playlist, "5.WAV"));
+        assert_eq!(playlist.len(), 4);
+
+        let mut empty = Playlist::<4>::new();
+        empty.next();
+        empty.previous();
+        empty.next_folder();
+        empty.previous_folder();
+        assert_eq!(empty.current(), None);
+    }
+
+    #[test]
+    fn clears_for_another_card() {
+        let mut playlist = albums(&[("", &["1.WAV"]), ("ALBUM", &["2.WAV"])]);
+        playlist.next();
+        playlist.clear();
+        assert!(playlist.is_empty());
+        assert_eq!(playlist.folder(1), None);
+        assert!(push(&mut playlist, "3.WAV"));
+        assert_eq!(playlist.current(), Some(&Track { folder: ROOT as u8, name: name("3.WAV").into(), short_name: name("3.WAV") }));
+    }
+
+    #[test]
+    fn selects_existing_tracks() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV", "3.WAV"]);
+        assert!(playlist.select(2));
+        assert_eq!(playlist.index(), 2);
+        assert!(!playlist.select(3));
+        assert_eq!(current(&playlist), Some("3.WAV"));
+    }
+
+    #[test]
+    fn fingerprint_follows_the_tracks() {
+        let tracks = playlist(&["1.WAV", "2.WAV"]).fingerprint();
+        assert_eq!(playlist(&["2.WAV", "1.WAV"]).fingerprint(), tracks);
+        assert_ne!(playlist(&["1.WAV", "3.WAV"]).fingerprint(), tracks);
+        assert_ne!(playlist(&["1.WAV"]).fingerprint(), tracks);
+        assert_ne!(playlist(&["1.WAV", "2.WAV", "3.WAV"]).fingerprint(), tracks);
+        assert_ne!(albums(&[("", &["1.WAV"]), ("A", &["2.WAV"])]).fingerprint(), tracks);
+    }
+
+    #[test]
+    fn keeps_albums_together() {
+        let playlist = albums(&[("", &["Z.WAV"]), ("A", &["2.WAV", "1.WAV"]), ("B", &["0.WAV"])]);
+        let tracks: Vec<(&str, &str)> = playlist.tracks().iter()
+            .map(|track| (playlist.folder(track.folder as usize).unwrap().name.as_str(), track.name.as_str()))
+            .collect();
+        assert_eq!(tracks, [("", "Z.WAV"), ("A", "1.WAV"), ("A", "2.WAV"), ("B", "0.WAV")]);
+    }
+
+    #[test]
+    fn folders_only_go_so_deep() {
+        let mut playlist = Playlist::<4>::new();
+        let mut parent = ROOT;
+        for depth in 0..MAX_DEPTH {
+            let folder = name(&format!("D{depth}"));
+            parent = playlist.add_folder(folder.into(), folder, parent).unwrap();
+        }
+        assert_eq!(playlist.add_folder(name("DEEPER").into(), name("DEEPER"), parent), None);
+
+        let path: Vec<&str> = playlist.path(parent).map(|folder| folder.name.as_str()).collect();
+        assert_eq!(path, ["D0", "D1", "D2"]);
+        assert_eq!(playlist.path(ROOT).count(), 0);
+    }
+
+    #[test]
+    fn skips_between_folders() {
+        let mut playlist = albums(&[("", &["1.WAV"]), ("A", &["2.WAV", "3.WAV"]), ("B", &["4.WAV"])]);
+        playlist.select(2);
+        playlist.next_folder();
+        assert_eq!(current(&playlist), Some("4.WAV"));
+        playlist.next_folder();
+        assert_eq!(current(&playlist), Some("1.WAV"));
+        playlist.previous_folder();
+        assert_eq!(current(&playlist), Some("4.WAV"));
+
+        // From inside a folder, back goes to the start of the one before rather than its own
+        playlist.select(2);
+        playlist.previous_folder();
+        assert_eq!(current(&playlist), Some("1.WAV"));
+    }
+
+    #[test]
+    fn repeats_a_folder() {
+        let mut playlist = albums(&[("", &["1.WAV"]), ("A", &["2.WAV", "3.WAV"]), ("B", &["4.WAV"])]);
+        playlist.select(1);
+        playlist.set_repeat(Repeat::Folder);
+        assert!(playlist.advance());
+        assert_eq!(current(&playlist), Some("3.WAV"));
+        assert!(playlist.advance());
+        assert_eq!(current(&playlist), Some("2.WAV"));
+        playlist.previous();
+        assert_eq!(current(&playlist), Some("3.WAV"));
+
+        // Skipping folders picks the folder that repeats
+        playlist.next_folder();
+        assert!(playlist.advance());
+        assert_eq!(current(&playlist), Some("4.WAV"));
+    }
+
+    #[test]
+    fn repeats_one_track_or_all() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV"]);
+        playlist.set_repeat(Repeat::One);
+        assert!(playlist.advance());
+        assert_eq!(current(&playlist), Some("1.WAV"));
+        // Skipping still moves on
+        playlist.next();
+        assert_eq!(current(&playlist), Some("2.WAV"));
+
+        playlist.set_repeat(Repeat::All);
+        assert!(playlist.advance());
+        assert_eq!(current(&playlist), Some("1.WAV"));
+    }
+
+    #[test]
+    fn knows_where_it_advances_to() {
+        let shuffled = Some(ShuffleState::new(5, 0));
+        for (repeat, shuffle) in [(Repeat::Off, None), (Repeat::One, None), (Repeat::Folder, None), (Repeat::All, None), (Repeat::Off, shuffled), (Repeat::All, shuffled)] {
+            let mut playlist = albums(&[("", &["1.WAV"]), ("ALBUM", &["2.WAV", "3.WAV"]), ("OTHER", &["4.WAV"])]);
+            playlist.set_repeat(repeat);
+            playlist.set_shuffle(shuffle);
+            for _ in 0..8 {
+                let following = playlist.following();
+                let index = playlist.index();
+                let advanced = playlist.advance();
+                match following {
+                    Some(following) => assert_eq!((advanced, playlist.index()), (true, following), "{repeat:?} from {index}"),
+                    // Only where a shuffled order goes around is not known ahead
+                    None => assert!(!advanced || playlist.shuffle().is_some_and(|state| state.pass > 0), "{repeat:?} from {index}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sorts_by_long_name() {
+        let mut playlist = Playlist::<4>::new();
+        assert!(playlist.push(LongName::new("Zebra song.wav"), name("AAAAAA~1.WAV")));
+        assert!(playlist.push(LongName::new("äpfel.wav"), name("PFEL~1.WAV")));
+        assert!(playlist.push(LongName::new("apple.wav"), name("ZZZ.WAV")));
+        playlist.sort();
+        let names: Vec<&str> = playlist.tracks().iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, ["apple.wav", "Zebra song.wav", "äpfel.wav"]);
+    }
+
+    #[test]
+    fn keeps_the_start_of_long_names() {
+        let long = "ü".repeat(MAX_LONG_NAME_LENGTH);
+        let name = LongName::new(&long);
+        assert_eq!(name.as_str(), "ü".repeat(MAX_LONG_NAME_LENGTH / 2));
+        assert!(name.matches(&long));
+        assert!(!name.matches(&"ü".repeat(MAX_LONG_NAME_LENGTH + 1)));
+        assert!(!name.matches(name.as_str()));
+    }
+
+    #[test]
+    fn finds_tracks_by_either_name() {
+        let mut playlist = albums(&[("ALBUM", &[])]);
+        assert!(playlist.push(LongName::new("Daisies (live).wav"), name("DAISIE~1.WAV")));
+        assert_eq!(playlist.find(1, "daisies (LIVE).wav"), Some(0));
+        assert_eq!(playlist.find(1, "DAISIE~1.WAV"), Some(0));
+        assert_eq!(playlist.find(ROOT, "Daisies (live).wav"), None);
+        assert_eq!(playlist.find(1, "Daisies.wav"), None);
+    }
+
+    #[test]
+    fn finds_tracks_by_path() {
+        let mut playlist = albums(&[("", &["1.WAV"]), ("A", &["2.WAV"]), ("B", &[])]);
+        let nested = playlist.add_folder(LongName::new("Live at home"), name("LIVEAT~1"), 2).unwrap();
+        assert!(playlist.push(LongName::new("Encore.wav"), name("ENCORE.WAV")));
+
+        assert_eq!(playlist.find_path(ROOT, "a/2.wav"), Some(1));
+        assert_eq!(playlist.find_path(ROOT, "./B/Live at home/Encore.wav"), Some(2));
+        assert_eq!(playlist.find_path(nested, "../../1.WAV"), Some(0));
+        assert_eq!(playlist.find_path(nested, "/A/2.WAV"), Some(1));
+        assert_eq!(playlist.find_path(ROOT, "A/1.WAV"), None);
+        assert_eq!(playlist.find_path(ROOT, "C/2.WAV"), None);
+        assert_eq!(playlist.find_path(ROOT, ""), None);
+    }
+
+    #[test]
+    fn places_tracks_in_the_order_given() {
+        let mut playlist = Playlist::<6>::new();
+        for track in ["1.WAV", "2.WAV", "3.WAV", "4.WAV"] {
+            assert!(push(&mut playlist, track));
+        }
+        // Placing moves tracks, so they are looked up again every time
+        for track in ["3.WAV", "1.WAV", "2.WAV"] {
+            assert!(playlist.place(playlist.find(ROOT, track).unwrap()));
+        }
+        assert!(!playlist.place(9));
+        playlist.keep_placed();
+
+        let names: Vec<&str> = playlist.tracks().iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, ["3.WAV", "1.WAV", "2.WAV"]);
+    }
+
+    #[test]
+    fn places_tracks_twice_while_there_is_room() {
+        let mut playlist = Playlist::<3>::new();
+        for track in ["1.WAV", "2.WAV"] {
+            assert!(push(&mut playlist, track));
+        }
+        assert!(playlist.place(1));
+        assert!(playlist.place(0));
+        assert!(!playlist.place(0), "no room for a third track");
+        let mut playlist = Playlist::<4>::new();
+        for track in ["1.WAV", "2.WAV"] {
+            assert!(push(&mut playlist, track));
+        }
+        assert!(playlist.place(0));
+        assert!(playlist.place(0));
+        assert!(playlist.place(2));
+        playlist.keep_placed();
+
+        let names: Vec<&str> = playlist.tracks().iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, ["1.WAV", "1.WAV", "2.WAV"]);
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV", "3.WAV", "4.WAV"]);
+        playlist.select(2);
+        let state = ShuffleState::new(42, 2);
+        let order = Shuffle::<4>::new(4, state);
+        playlist.set_shuffle(Some(state));
+
+        let mut played = vec![playlist.index() as u16];
+        while playlist.advance() {
+            played.push(playlist.index() as u16);
+        }
+        assert_eq!(played, order.order());
+
+        // Going around starts the next pass, and going back returns to the one before
+        playlist.next();
+        assert_eq!(playlist.shuffle().map(|state| state.pass), Some(1));
+        playlist.previous();
+        assert_eq!(playlist.shuffle(), Some(state));
+        assert_eq!(playlist.index(), played[3] as usize);
+
+        playlist.set_shuffle(None);
+        playlist.select(0);
+        playlist.next();
+        assert_eq!(current(&playlist), Some("2.WAV"));
+    }
+
+    #[test]
+    fn shuffle_stops_when_the_tracks_change() {
+        let mut playlist = playlist(&["1.WAV", "2.WAV"]);
+        playlist.set_shuffle(Some(ShuffleState::new(1, 0)));
+        playlist.sort();
+        assert_eq!(playlist.shuffle(), None);
+    }
+}
@@ -0,0 +1,291 @@
+//! Messages between the cores, sent as words over the SIO FIFO.
+//!
+//! The samples themselves go through a ring buffer, the FIFO only carries these.
+//! The first word of a message has a tag in its top byte and up to 24 bits of payload below it,
+//! messages with more payload than that send it in the words right after:
+//!
+//! ```text
+//! 31      24 23                  0
+//! [  tag   ][  inline payload    ]  [extra word]...
+//! ```
+//!
+//! A message is read whole before its payload is checked, so a bad one doesn't put the words
+//! after it out of step.
+
+use crate::pcm::{PcmFormat, SampleFormat};
+
+/// Bits of payload that fit next to the tag
+const TAG_SHIFT: u32 = 24;
+const PAYLOAD_MASK: u32 = (1 << TAG_SHIFT) - 1;
+
+/// Most words a message takes
+pub const MAX_MESSAGE_WORDS: usize = 3;
+
+/// Volume gain that leaves samples as they are
+pub const UNITY_GAIN: u16 = 1 << 15;
+
+/// Commands core 1 sends to core 0, which plays the samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    /// Samples of a new track follow in the ring, once core 0 answered with `Event::OutputStopped`
+    /// and got the `SystemClock` the track plays at.
+    TrackStart { format: PcmFormat, sample_rate: u32, output_rate: u32 },
+    /// The system clock after a `TrackStart`, core 0 restarts its output with it
+    SystemClock { sys_freq: u32 },
+    /// Fade out, and hold the output there
+    Pause,
+    /// Fade back in after a `Pause`
+    Resume,
+    /// Fade out, drop all samples and stay silent until the next `TrackStart`, answered with `Event::Flushed`
+    Stop,
+    /// Drop all samples, the ones that follow start at `frame` of the track.
+    /// Answered with `Event::Flushed`.
+    Seek { frame: u32 },
+    /// Scale samples by `gain`, with `UNITY_GAIN` leaving them as they are
+    SetVolume { gain: u16 },
+    /// Drop all samples and keep playing the ones that follow, answered with `Event::Flushed`
+    Flush,
+    /// Nothing plays for a while after a `Stop`, ramp the output down to 0.
+    /// The next `TrackStart` ramps it back up first.
+    Idle,
+}
+
+/// Events core 0 reports back to core 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Event {
+    /// The output stopped after a `TrackStart`, so the system clock can be changed
+    OutputStopped,
+    /// All samples from before a `Stop`, `Seek` or `Flush` have been dropped
+    Flushed,
+    /// Frames of the current track that went into the player so far. The few that are still in the
+    /// resampler and the DMA halves play after it, so this is a little ahead of what is heard.
+    /// Core 0 doesn't know where a track that follows on without a `TrackStart` begins, it counts
+    /// on from the start of the track before, core 1 keeps track of which one plays.
+    PositionReport { frame: u32 },
+    /// The ring ran empty while playing
+    Underrun,
+}
+
+/// Reasons a message can not be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ProtocolError {
+    /// The tag doesn't belong to any message going this way
+    UnknownTag(u8),
+    /// The format of a `TrackStart` is not one the player knows
+    InvalidFormat(u32),
+}
+
+mod tag {
+    // Commands
+    pub const TRACK_START: u8 = 0x01;
+    pub const SYSTEM_CLOCK: u8 = 0x02;
+    pub const PAUSE: u8 = 0x03;
+    pub const RESUME: u8 = 0x04;
+    pub const STOP: u8 = 0x05;
+    pub const SEEK: u8 = 0x06;
+    pub const SET_VOLUME: u8 = 0x07;
+    pub const FLUSH: u8 = 0x08;
+    pub const IDLE: u8 = 0x09;
+
+    // Events, kept apart from the commands so a word going the wrong way is caught
+    pub const OUTPUT_STOPPED: u8 = 0x81;
+    pub const FLUSHED: u8 = 0x82;
+    pub const POSITION_REPORT: u8 = 0x83;
+    pub const UNDERRUN: u8 = 0x84;
+}
+
+impl Command {
+    /// Encodes the command, calling `write_word` for each word in order.
+    pub fn encode(&self, mut write_word: impl FnMut(u32)) {
+        match *self {
+            Command::TrackStart { format, sample_rate, output_rate } => {
+                write_word(header(tag::TRACK_START, encode_format(format)));
+                write_word(sample_rate);
+                write_word(output_rate);
+            }
+            Command::SystemClock { sys_freq } => {
+                write_word(header(tag::SYSTEM_CLOCK, 0));
+                write_word(sys_freq);
+            }
+            Command::Pause => write_word(header(tag::PAUSE, 0)),
+            Command::Resume => write_word(header(tag::RESUME, 0)),
+            Command::Stop => write_word(header(tag::STOP, 0)),
+            Command::Seek { frame } => {
+                write_word(header(tag::SEEK, 0));
+                write_word(frame);
+            }
+            Command::SetVolume { gain } => write_word(header(tag::SET_VOLUME, gain as u32)),
+            Command::Flush => write_word(header(tag::FLUSH, 0)),
+            Command::Idle => write_word(header(tag::IDLE, 0)),
+        }
+    }
+
+    /// Decodes a command starting with `first`, calling `read_word` for any words that follow it.
+    pub fn decode(first: u32, mut read_word: impl FnMut() -> u32) -> Result<Command, ProtocolError> {
+        let (tag, payload) = split_header(first);
+        Ok(match tag {
+            tag::TRACK_START => {
+                let [sample_rate, output_rate] = read_words(read_word);
+                Command::TrackStart { format: decode_format(payload)?, sample_rate, output_rate }
+            }
+            tag::SYSTEM_CLOCK => Command::SystemClock { sys_freq: read_word() },
+            tag::PAUSE => Command::Pause,
+            tag::RESUME => Command::Resume,
+            tag::STOP => Command::Stop,
+            tag::SEEK => Command::Seek { frame: read_word() },
+            tag::SET_VOLUME => Command::SetVolume { gain: payload as u16 },
+            tag::FLUSH => Command::Flush,
+            tag::IDLE => Command::Idle,
+            _ => return Err(ProtocolError::UnknownTag(tag)),
+        })
+    }
+}
+
+impl Event {
+    /// Encodes the event, calling `write_word` for each word in order.
+    pub fn encode(&self, mut write_word: impl FnMut(u32)) {
+        match *self {
+            Event::OutputStopped => write_word(header(tag::OUTPUT_STOPPED, 0)),
+            Event::Flushed => write_word(header(tag::FLUSHED, 0)),
+            Event::PositionReport { frame } => {
+                write_word(header(tag::POSITION_REPORT, 0));
+                write_word(frame);
+            }
+            Event::Underrun => write_word(header(tag::UNDERRUN, 0)),
+        }
+    }
+
+    /// Decodes an event starting with `first`, calling `read_word` for any words that follow it.
+    pub fn decode(first: u32, mut read_word: impl FnMut() -> u32) -> Result<Event, ProtocolError> {
+        let (tag, _) = split_header(first);
+        Ok(match tag {
+            tag::OUTPUT_STOPPED => Event::OutputStopped,
+            tag::FLUSHED => Event::Flushed,
+            tag::POSITION_REPORT => Event::PositionReport { frame: read_word() },
+            tag::UNDERRUN => Event::Underrun,
+            _ => return Err(ProtocolError::UnknownTag(tag)),
+        })
+    }
+}
+
+fn header(tag: u8, payload: u32) -> u32 {
+    ((tag as u32) << TAG_SHIFT) | (payload & PAYLOAD_MASK)
+}
+
+fn split_header(word: u32) -> (u8, u32) {
+    ((word >> TAG_SHIFT) as u8, word & PAYLOAD_MASK)
+}
+
+/// Reads the `N` words that follow a header.
+fn read_words<const N: usize>(mut read_word: impl FnMut() -> u32) -> [u32; N] {
+    core::array::from_fn(|_| read_word())
+}
+
+/// Bytes per sample in bits 0 to 7, channels in bits 8 to 15
+fn encode_format(format: PcmFormat) -> u32 {
+    ((format.channels as u32) << 8) | format.sample_format.bytes_per_sample() as u32
+}
+
+fn decode_format(payload: u32) -> Result<PcmFormat, ProtocolError> {
+    let sample_format = match payload & 0xFF {
+        1 => SampleFormat::U8,
+        2 => SampleFormat::S16Le,
+        _ => return Err(ProtocolError::InvalidFormat(payload)),
+    };
+    let channels = match (payload >> 8) & 0xFF {
+        channels @ 1..=2 => channels as u8,
+        _ => return Err(ProtocolError::InvalidFormat(payload)),
+    };
+    Ok(PcmFormat { sample_format, channels })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO_16: PcmFormat = PcmFormat { sample_format: SampleFormat::S16Le, channels: 2 };
+    const MONO_8: PcmFormat = PcmFormat { sample_format: SampleFormat::U8, channels: 1 };
+
+    fn encode_command(command: Command) -> Vec<u32> {
+        let mut words = Vec::new();
+        command.encode(|word| words.push(word));
+        words
+    }
+
+    fn encode_event(event: Event) -> Vec<u32> {
+        let mut words = Vec::new();
+        event.encode(|word| words.push(word));
+        words
+    }
+
+    #[test]
+    fn commands_survive_the_round_trip() {
+        let commands = [
+            Command::TrackStart { format: STEREO_16, sample_rate: 44_100, output_rate: 44_100 },
+            Command::TrackStart { format: MONO_8, sample_rate: 11_025, output_rate: 32_000 },
+            Command::SystemClock { sys_freq: 176_000_000 },
+            Command::Pause,
+            Command::Resume,
+            Command::Stop,
+            Command::Seek { frame: u32::MAX },
+            Command::SetVolume { gain: UNITY_GAIN },
+            Command::SetVolume { gain: 0 },
+            Command::Flush,
+            Command::Idle,
+        ];
+        for command in commands {
+            let words = encode_command(command);
+            let mut rest = words[1..].iter();
+            let decoded = Command::decode(words[0], || *rest.next().unwrap());
+            assert_eq!(decoded, Ok(command));
+            assert_eq!(rest.len(), 0, "{command:?} left words unread");
+        }
+    }
+
+    #[test]
+    fn events_survive_the_round_trip() {
+        let events = [
+            Event::OutputStopped,
+            Event::Flushed,
+            Event::PositionReport { frame: 123_456_789 },
+            Event::Underrun,
+        ];
+        for event in events {
+            let words = encode_event(event);
+            let mut rest = words[1..].iter();
+            let decoded = Event::decode(words[0], || *rest.next().unwrap());
+            assert_eq!(decoded, Ok(event));
+            assert_eq!(rest.len(), 0, "{event:?} left words unread");
+        }
+    }
+
+    #[test]
+    fn words_going_the_wrong_way_are_rejected() {
+        let event = encode_event(Event::Underrun)[0];
+        assert_eq!(Command::decode(event, || 0), Err(ProtocolError::UnknownTag(tag::UNDERRUN)));
+        let command = encode_command(Command::Pause)[0];
+        assert_eq!(Event::decode(command, || 0), Err(ProtocolError::UnknownTag(tag::PAUSE)));
+        assert_eq!(Command::decode(0, || 0), Err(ProtocolError::UnknownTag(0)));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let three_bytes = header(tag::TRACK_START, (1 << 8) | 3);
+        assert_eq!(Command::decode(three_bytes, || 0), Err(ProtocolError::InvalidFormat((1 << 8) | 3)));
+        let six_channels = header(tag::TRACK_START, (6 << 8) | 2);
+        assert_eq!(Command::decode(six_channels, || 0), Err(ProtocolError::InvalidFormat((6 << 8) | 2)));
+    }
+
+    #[test]
+    fn reads_all_of_a_bad_message() {
+        let mut words = encode_command(Command::TrackStart { format: MONO_8, sample_rate: 8_000, output_rate: 32_000 });
+        words[0] = header(tag::TRACK_START, (1 << 8) | 3);
+        words.extend(encode_command(Command::Pause));
+        let mut rest = words[1..].iter();
+        assert!(Command::decode(words[0], || *rest.next().unwrap()).is_err());
+        // The next message starts right after it
+        let next = *rest.next().unwrap();
+        assert_eq!(Command::decode(next, || *rest.next().unwrap()), Ok(Command::Pause));
+    }
+}
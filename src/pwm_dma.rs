@@ -0,0 +1,140 @@
+//! Feeds duty values to the audio PWM with DMA, paced by the PWM wrap.
+//!
+//! Two DMA channels take turns writing one half of a ping-pong buffer to the CC register,
+//! one duty value per PWM period. They are chained to each other, so the output keeps going
+//! without any help from the CPU. Whenever a channel finishes its half it raises DMA_IRQ_0,
+//! and that half can be refilled while the other channel plays.
+
+use core::marker::PhantomData;
+
+use rp2040_hal::{dma::{Channel, SingleChannel, CH0, CH1}, pac, pwm::{CcFormat, SliceId}};
+
+use dropstick::playback::HALF_BUFFER_FRAMES;
+
+/// Duty values for channel A and B, one per PWM period
+pub type HalfBuffer = [CcFormat; HALF_BUFFER_FRAMES];
+
+/// `DATA_SIZE` of word sized transfers, a `CcFormat` sets both PWM channels at once
+const DATA_SIZE_WORD: u8 = 2;
+
+/// The two DMA channels that play the halves of a ping-pong buffer into the PWM slice `S`.
+pub struct DutyDma<S: SliceId> {
+    channels: (Channel<CH0>, Channel<CH1>),
+    /// Start addresses of both halves, the channels need them again after every pass
+    half_addresses: [u32; 2],
+    /// Halves that have been played since they were last handed out to refill
+    finished: [bool; 2],
+    /// A half was played again before it got refilled
+    missed: bool,
+    slice: PhantomData<S>,
+}
+
+impl<S: SliceId> DutyDma<S> {
+    /// Sets up both channels to play `halves` in turns, and starts with the first half.
+    ///
+    /// The PWM slice should be running without its wrap interrupt, its DREQ paces the transfers.
+    ///
+    /// # Safety
+    ///
+    /// The DMA keeps reading `halves` from then on, so they must never be moved or freed.
+    pub unsafe fn start(channels: (Channel<CH0>, Channel<CH1>), halves: &[HalfBuffer; 2]) -> DutyDma<S> {
+        let mut duty_dma = DutyDma {
+            channels,
+            half_addresses: [halves[0].as_ptr() as u32, halves[1].as_ptr() as u32],
+            finished: [false; 2],
+            missed: false,
+            slice: PhantomData,
+        };
+
+        // Safety: only the CC register of our own slice is written, and only by the DMA.
+        let cc_address = unsafe { &*pac::PWM::ptr() }.ch(S::DYN.num as usize).cc().as_ptr() as u32;
+        let (first, second) = (duty_dma.channels.0.id(), duty_dma.channels.1.id());
+        configure(&duty_dma.channels.0, duty_dma.half_addresses[0], cc_address, S::WRAP_DREQ, second);
+        configure(&duty_dma.channels.1, duty_dma.half_addresses[1], cc_address, S::WRAP_DREQ, first);
+
+        duty_dma.channels.0.enable_irq0();
+        duty_dma.channels.1.enable_irq0();
+
+        // Safety: only triggers our own channel.
+        unsafe { &*pac::DMA::ptr() }
+            .multi_chan_trigger()
+            .write(|w| unsafe { w.bits(1 << first) });
+
+        duty_dma
+    }
+
+    /// Rewinds the channels that finished their half. Call this from DMA_IRQ_0.
+    pub fn on_interrupt(&mut self) {
+        if self.channels.0.check_irq0() {
+            rewind(&self.channels.0, self.half_addresses[0]);
+            self.missed |= self.finished[0];
+            self.finished[0] = true;
+        }
+        if self.channels.1.check_irq0() {
+            rewind(&self.channels.1, self.half_addresses[1]);
+            self.missed |= self.finished[1];
+            self.finished[1] = true;
+        }
+    }
+
+    /// Stops feeding the PWM, holding both of its channels at `duty` until `resume`.
+    /// The PWM keeps running, so the output doesn't stop at whatever level it had.
+    pub fn pause(&mut self, duty: u16) {
+        // A disabled channel ignores its DREQ, and goes on with the same transfer once enabled again
+        set_enabled(&self.channels.0, false);
+        set_enabled(&self.channels.1, false);
+
+        // Safety: only the CC register of our own slice is written, and the DMA doesn't anymore.
+        unsafe { &*pac::PWM::ptr() }
+            .ch(S::DYN.num as usize)
+            .cc()
+            .write(|w| unsafe { w.a().bits(duty).b().bits(duty) });
+    }
+
+    /// Goes on feeding the PWM from where `pause` stopped.
+    pub fn resume(&mut self) {
+        set_enabled(&self.channels.0, true);
+        set_enabled(&self.channels.1, true);
+    }
+
+    /// Whether a half has been played twice because it wasn't refilled in time, since the last call.
+    pub fn take_missed(&mut self) -> bool {
+        core::mem::take(&mut self.missed)
+    }
+
+    /// Returns the index of a half that has been played and can be refilled.
+    pub fn take_finished(&mut self) -> Option<usize> {
+        let half = self.finished.iter().position(|&finished| finished)?;
+        self.finished[half] = false;
+        Some(half)
+    }
+}
+
+/// Configures a channel to write a half buffer to the CC register once per `treq` request,
+/// and to start `chain_to` when it is done. Doesn't start the channel.
+fn configure(channel: &impl SingleChannel, half_address: u32, cc_address: u32, treq: u8, chain_to: u8) {
+    let ch = channel.ch();
+    ch.ch_read_addr().write(|w| unsafe { w.bits(half_address) });
+    ch.ch_write_addr().write(|w| unsafe { w.bits(cc_address) });
+    ch.ch_trans_count().write(|w| unsafe { w.bits(HALF_BUFFER_FRAMES as u32) });
+    ch.ch_al1_ctrl().write(|w| unsafe {
+        w.data_size().bits(DATA_SIZE_WORD);
+        w.incr_read().set_bit();
+        w.incr_write().clear_bit();
+        w.treq_sel().bits(treq);
+        w.chain_to().bits(chain_to);
+        w.en().set_bit();
+        w
+    });
+}
+
+/// Points a finished channel back at the start of its half. The transfer count reloads by itself
+/// when the other channel triggers it.
+fn rewind(channel: &impl SingleChannel, half_address: u32) {
+    channel.ch().ch_read_addr().write(|w| unsafe { w.bits(half_address) });
+}
+
+/// Lets a channel respond to its DREQ, or pauses it without losing its place.
+fn set_enabled(channel: &impl SingleChannel, enabled: bool) {
+    channel.ch().ch_al1_ctrl().modify(|_, w| w.en().bit(enabled));
+}
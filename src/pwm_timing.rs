@@ -0,0 +1,198 @@
+//! Choosing a system clock and PWM divider settings for a sample rate.
+//!
+//! The PWM wraps once per sample, so its period sets the sample rate:
+//!
+//! fPWM = fSYS / ((TOP + 1) * (CSR_PH_CORRECT + 1) * (DIV_INT + (DIV_FRAC / 16)))
+//!
+//! A larger TOP gives more duty resolution, so the divider is kept as small as possible.
+
+/// Largest TOP we use, samples are scaled to 12 bits of duty resolution
+pub const MAX_TOP: u16 = 4095;
+
+/// Rate errors below this are inaudible (about 0.2 cent), so they count as exact
+const INAUDIBLE_ERROR_PPM: u32 = 100;
+
+/// Smallest divider the PWM supports (1.0 in 1/16ths)
+const MIN_DIV16: u32 = 16;
+/// Largest divider the PWM supports (255 + 15/16 in 1/16ths)
+const MAX_DIV16: u32 = 255 * 16 + 15;
+
+/// Rate files are resampled to when the PWM can't play them at their own rate
+pub const FALLBACK_RATE: u32 = 44_100;
+
+/// System clocks the player can switch between, 131MHz for multiples of 32kHz
+/// and 176MHz for multiples of 44.1kHz. The PLL settings for them are in `clock_init`.
+pub const AUDIO_SYS_FREQS: [u32; 2] = [131_000_000, 176_000_000];
+
+/// Rate errors above this are audible as a change in pitch (about 1.7 cent)
+const MAX_ERROR_PPM: u32 = 1_000;
+
+/// PWM periods shorter than this lose too much duty resolution (10 bits)
+const MIN_TOP: u16 = 1023;
+
+/// PWM counter settings for one sample rate at one system clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PwmTiming {
+    pub top: u16,
+    pub div_int: u8,
+    /// Fractional part of the divider in 1/16ths
+    pub div_frac: u8,
+}
+
+impl PwmTiming {
+    /// Finds the settings that come closest to `sample_rate` when running from `sys_freq`.
+    /// Returns `None` if the rate is out of reach of the PWM divider.
+    pub fn for_rate(sys_freq: u32, sample_rate: u32) -> Option<PwmTiming> {
+        if sample_rate == 0 {
+            return None;
+        }
+
+        // System clock cycles per sample, in 1/16ths to match the divider
+        let cycles16 = (sys_freq as u64 * 16 + sample_rate as u64 / 2) / sample_rate as u64;
+        let min_div16 = (cycles16.div_ceil(MAX_TOP as u64 + 1) as u32).max(MIN_DIV16);
+
+        // Dividers just above the minimum may hit the rate better for a little less resolution
+        (min_div16..min_div16 + 16)
+            .take_while(|&div16| div16 <= MAX_DIV16)
+            .filter_map(|div16| {
+                let period = (cycles16 + div16 as u64 / 2) / div16 as u64;
+                if !(2..=MAX_TOP as u64 + 1).contains(&period) {
+                    return None;
+                }
+                Some(PwmTiming {
+                    top: (period - 1) as u16,
+                    div_int: (div16 / 16) as u8,
+                    div_frac: (div16 % 16) as u8,
+                })
+            })
+            .min_by_key(|timing| timing.rank(sys_freq, sample_rate))
+    }
+
+    /// The sample rate these settings produce, in millihertz.
+    pub fn output_rate_millihertz(&self, sys_freq: u32) -> u64 {
+        let div16 = self.div_int as u64 * 16 + self.div_frac as u64;
+        sys_freq as u64 * 16 * 1000 / (div16 * (self.top as u64 + 1))
+    }
+
+    /// How far the produced sample rate is off, in parts per million.
+    pub fn error_ppm(&self, sys_freq: u32, sample_rate: u32) -> u32 {
+        let wanted = sample_rate as u64 * 1000;
+        let actual = self.output_rate_millihertz(sys_freq);
+        (actual.abs_diff(wanted) * 1_000_000 / wanted) as u32
+    }
+
+    /// Whether the rate is close enough and the resolution high enough to play at.
+    pub fn is_good(&self, sys_freq: u32, sample_rate: u32) -> bool {
+        self.error_ppm(sys_freq, sample_rate) <= MAX_ERROR_PPM && self.top >= MIN_TOP
+    }
+
+    /// Orders settings from best to worst: audibly closer first, then an integer divider
+    /// (no period jitter), then the most duty resolution.
+    fn rank(&self, sys_freq: u32, sample_rate: u32) -> (u32, bool, u16) {
+        (
+            self.error_ppm(sys_freq, sample_rate).max(INAUDIBLE_ERROR_PPM),
+            self.div_frac != 0,
+            MAX_TOP - self.top,
+        )
+    }
+}
+
+/// Sample rate the PWM output runs at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum OutputRate {
+    /// Follow the sample rate of each file, only resampling rates the PWM can't play well
+    MatchFile,
+    /// Always run at this rate, resampling every file that differs
+    Fixed(u32),
+}
+
+impl OutputRate {
+    /// Picks the rate to run the PWM at for a file, and the system clock to run it from.
+    pub fn choose(self, sample_rate: u32, sys_freqs: &[u32]) -> Option<(u32, ClockChoice)> {
+        let output_rate = match self {
+            OutputRate::MatchFile => sample_rate,
+            OutputRate::Fixed(rate) => rate,
+        };
+        match choose_clock(output_rate, sys_freqs) {
+            Some(choice) if choice.timing.is_good(choice.sys_freq, output_rate) => Some((output_rate, choice)),
+            _ if output_rate != FALLBACK_RATE => Some((FALLBACK_RATE, choose_clock(FALLBACK_RATE, sys_freqs)?)),
+            _ => None,
+        }
+    }
+}
+
+/// A system clock and the PWM settings to use with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct ClockChoice {
+    /// Index into the system clocks that were given to `choose_clock`
+    pub clock_index: usize,
+    pub sys_freq: u32,
+    pub timing: PwmTiming,
+}
+
+/// Picks the system clock that plays `sample_rate` best. Earlier clocks win ties.
+pub fn choose_clock(sample_rate: u32, sys_freqs: &[u32]) -> Option<ClockChoice> {
+    sys_freqs
+        .iter()
+        .enumerate()
+        .filter_map(|(clock_index, &sys_freq)| {
+            let timing = PwmTiming::for_rate(sys_freq, sample_rate)?;
+            Some(ClockChoice { clock_index, sys_freq, timing })
+        })
+        .min_by_key(|choice| choice.timing.rank(choice.sys_freq, sample_rate))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYS_131MHZ: u32 = 131_000_000;
+    const SYS_176MHZ: u32 = 176_000_000;
+
+    #[test]
+    fn all_common_rates_are_inaudibly_close() {
+        for sample_rate in [8_000, 11_025, 16_000, 22_050, 32_000, 44_100, 48_000] {
+            let choice = choose_clock(sample_rate, &[SYS_131MHZ, SYS_176MHZ]).unwrap();
+            let error = choice.timing.error_ppm(choice.sys_freq, sample_rate);
+            assert!(error < INAUDIBLE_ERROR_PPM, "{sample_rate}Hz is {error}ppm off");
+            assert!(choice.timing.top > 2048, "{sample_rate}Hz only has TOP {}", choice.timing.top);
+        }
+    }
+
+    #[test]
+    fn rate_families_pick_their_clock() {
+        assert_eq!(choose_clock(32_000, &[SYS_131MHZ, SYS_176MHZ]).unwrap().sys_freq, SYS_131MHZ);
+        assert_eq!(choose_clock(8_000, &[SYS_131MHZ, SYS_176MHZ]).unwrap().sys_freq, SYS_131MHZ);
+        assert_eq!(choose_clock(44_100, &[SYS_131MHZ, SYS_176MHZ]).unwrap().sys_freq, SYS_176MHZ);
+        assert_eq!(choose_clock(22_050, &[SYS_131MHZ, SYS_176MHZ]).unwrap().sys_freq, SYS_176MHZ);
+    }
+
+    #[test]
+    fn keeps_full_resolution_at_32khz() {
+        let timing = PwmTiming::for_rate(SYS_131MHZ, 32_000).unwrap();
+        assert_eq!(timing, PwmTiming { top: 4093, div_int: 1, div_frac: 0 });
+    }
+
+    #[test]
+    fn divides_down_for_low_rates() {
+        let timing = PwmTiming::for_rate(SYS_131MHZ, 8_000).unwrap();
+        assert_eq!(timing.div_int, 4);
+        assert!(timing.error_ppm(SYS_131MHZ, 8_000) < INAUDIBLE_ERROR_PPM);
+    }
+
+    #[test]
+    fn falls_back_for_unplayable_rates() {
+        let sys_freqs = [SYS_131MHZ, SYS_176MHZ];
+        assert_eq!(OutputRate::MatchFile.choose(22_050, &sys_freqs).unwrap().0, 22_050);
+        assert_eq!(OutputRate::MatchFile.choose(384_000, &sys_freqs).unwrap().0, FALLBACK_RATE);
+        assert_eq!(OutputRate::Fixed(32_000).choose(44_100, &sys_freqs).unwrap().0, 32_000);
+    }
+
+    #[test]
+    fn rejects_unreachable_rates() {
+        assert_eq!(PwmTiming::for_rate(SYS_131MHZ, 0), None);
+        assert_eq!(PwmTiming::for_rate(SYS_131MHZ, 100_000_000), None);
+        assert_eq!(PwmTiming::for_rate(SYS_131MHZ, 10), None);
+    }
+}
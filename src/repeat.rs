@@ -0,0 +1,96 @@
+//! Playing things again: what comes after a track ends, and a section of a track looped to practise it.
+
+/// What plays after a track ends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub enum Repeat {
+    /// The next track, stopping after the last one
+    #[default]
+    Off,
+    /// The same track again
+    One,
+    /// The next track of the folder, going around to its first one
+    Folder,
+    /// The next track, going around to the first one after the last
+    All,
+}
+
+impl Repeat {
+    /// The mode the button switches to after this one.
+    pub fn next(self) -> Repeat {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Folder,
+            Repeat::Folder => Repeat::Off,
+        }
+    }
+}
+
+/// A section of a track that plays over and over, from point A to point B. Both are marked while
+/// listening, as bytes into the samples of the track.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub enum AbLoop {
+    #[default]
+    Off,
+    /// Only A is marked, playback goes on past it until B is
+    Marked { a: u32 },
+    Looping { a: u32, b: u32 },
+}
+
+impl AbLoop {
+    /// Marks A, then B, and the mark after that stops looping. A B before A marks A again instead.
+    pub fn mark(&mut self, at: u32) {
+        *self = match *self {
+            AbLoop::Off => AbLoop::Marked { a: at },
+            AbLoop::Marked { a } if at > a => AbLoop::Looping { a, b: at },
+            AbLoop::Marked { .. } => AbLoop::Marked { a: at },
+            AbLoop::Looping { .. } => AbLoop::Off,
+        };
+    }
+
+    /// A and B, once both are marked.
+    pub fn section(&self) -> Option<(u32, u32)> {
+        match *self {
+            AbLoop::Looping { a, b } => Some((a, b)),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn button_goes_through_every_mode() {
+        let mut repeat = Repeat::default();
+        let mut modes = Vec::new();
+        for _ in 0..4 {
+            repeat = repeat.next();
+            modes.push(repeat);
+        }
+        assert_eq!(modes, [Repeat::All, Repeat::One, Repeat::Folder, Repeat::Off]);
+    }
+
+    #[test]
+    fn marks_a_then_b_then_stops() {
+        let mut ab = AbLoop::default();
+        ab.mark(4_000);
+        assert_eq!(ab.section(), None);
+        ab.mark(9_000);
+        assert_eq!(ab.section(), Some((4_000, 9_000)));
+        ab.mark(6_000);
+        assert_eq!(ab, AbLoop::Off);
+    }
+
+    #[test]
+    fn b_before_a_marks_a_again() {
+        let mut ab = AbLoop::default();
+        ab.mark(4_000);
+        ab.mark(2_000);
+        assert_eq!(ab, AbLoop::Marked { a: 2_000 });
+        ab.mark(2_000);
+        assert_eq!(ab.section(), None);
+    }
+}
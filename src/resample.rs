@@ -0,0 +1,410 @@
+//! Fixed point sample rate conversion, for input rates the PWM can't be clocked at.
+//!
+//! Only integer math is used, the RP2040's M0+ cores have no FPU.
+//! Frames are pushed in at the input rate and pulled out at the output rate:
+//!
+//! ```text
+//! while resampler.needs_input() {
+//!     resampler.push(next_input_frame());
+//! }
+//! let frame = resampler.pull();
+//! ```
+
+/// Taps of the windowed-sinc filter, half of them on either side of the output position
+pub const TAPS: usize = 32;
+const HALF_TAPS: usize = TAPS / 2;
+
+/// Fractional positions the filter is tabulated at, positions in between are interpolated
+pub const PHASES: usize = 32;
+const PHASE_BITS: u32 = PHASES.trailing_zeros();
+const SUB_PHASE_BITS: u32 = FRAC_BITS - PHASE_BITS;
+
+/// Fractional bits of interpolation positions and cutoff frequencies
+const FRAC_BITS: u32 = 16;
+const ONE: u32 = 1 << FRAC_BITS;
+
+/// Fraction of the Nyquist frequency that is kept, leaving room for the filter to roll off
+const PASSBAND_PERCENT: u32 = 90;
+
+/// Largest input to output rate ratio, each output frame has to be computed within one PWM period
+pub const MAX_DOWNSAMPLING: u32 = 4;
+
+/// How output frames are interpolated from the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Quality {
+    /// Straight line between the two nearest frames. Cheap, but dulls highs and aliases.
+    Linear,
+    /// Band limited interpolation with a `TAPS` long Blackman windowed-sinc filter
+    Sinc,
+}
+
+/// Converts a stream of stereo frames from one sample rate to another.
+pub struct Resampler {
+    quality: Quality,
+    output_rate: u32,
+    /// Whole input frames per output frame
+    step_whole: u32,
+    /// Remainder of input frames per output frame, in 1/`output_rate`ths
+    step_rest: u32,
+    /// Position of the next output frame past the interpolation base frame, in 1/`output_rate`ths.
+    /// Stepping in exact fractions keeps the rate from drifting.
+    position: u32,
+    /// Converts `position` to a fraction with `FRAC_BITS` bits, `(1 << 32) / output_rate`
+    position_scale: u32,
+    /// Input frames that have to be pushed before the next output frame
+    pending: u32,
+    /// Only compute the first channel and copy it, for mono streams
+    mono: bool,
+    /// Last `TAPS` input frames per channel, stored twice so a window never wraps
+    history: [[i16; 2 * TAPS]; 2],
+    /// Index of the newest frame in `history`
+    newest: usize,
+    /// Filter coefficients in Q15 for every phase, plus one past the last for interpolation
+    table: [[i32; TAPS]; PHASES + 1],
+    /// `2 * cutoff` (relative to the input rate) the table was built for, in 16.16
+    table_cutoff: u32,
+}
+
+impl Resampler {
+    pub fn new(quality: Quality) -> Resampler {
+        let mut resampler = Resampler {
+            quality,
+            output_rate: 1,
+            step_whole: 1,
+            step_rest: 0,
+            position: 0,
+            position_scale: 0,
+            pending: 1,
+            mono: false,
+            history: [[0; 2 * TAPS]; 2],
+            newest: 0,
+            table: [[0; TAPS]; PHASES + 1],
+            table_cutoff: 0,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    /// Whether converting between these rates is within what we can compute in real time.
+    pub fn supports(input_rate: u32, output_rate: u32) -> bool {
+        input_rate > 0 && output_rate > 0 && input_rate <= output_rate * MAX_DOWNSAMPLING
+    }
+
+    /// Sets up conversion between two rates, and forgets the previous stream.
+    pub fn set_rates(&mut self, input_rate: u32, output_rate: u32, mono: bool) {
+        self.output_rate = output_rate;
+        self.step_whole = input_rate / output_rate;
+        self.step_rest = input_rate % output_rate;
+        self.position_scale = ((1u64 << 32) / output_rate as u64) as u32;
+        self.mono = mono;
+        self.reset();
+
+        if self.quality == Quality::Sinc && !self.is_bypassed() {
+            // Keep below the lower of both Nyquist frequencies
+            let ratio = ((output_rate as u64 * ONE as u64) / input_rate as u64).min(ONE as u64) as u32;
+            let cutoff = ratio / 100 * PASSBAND_PERCENT;
+            if cutoff != self.table_cutoff {
+                self.build_table(cutoff);
+            }
+        }
+    }
+
+    /// Equal rates pass frames through untouched.
+    pub fn is_bypassed(&self) -> bool {
+        self.step_whole == 1 && self.step_rest == 0
+    }
+
+    /// Forgets all input, so a new stream starts from silence.
+    pub fn reset(&mut self) {
+        self.history = [[0; 2 * TAPS]; 2];
+        self.newest = 0;
+        self.position = 0;
+        self.pending = 1;
+    }
+
+    /// Whether another input frame has to be pushed before `pull` can be called.
+    pub fn needs_input(&self) -> bool {
+        self.pending > 0
+    }
+
+    pub fn push(&mut self, frame: (i16, i16)) {
+        self.newest = (self.newest + 1) % TAPS;
+        for (channel, value) in [frame.0, frame.1].into_iter().enumerate() {
+            self.history[channel][self.newest] = value;
+            self.history[channel][self.newest + TAPS] = value;
+        }
+        self.pending = self.pending.saturating_sub(1);
+    }
+
+    /// Computes the next output frame and advances by one output period.
+    pub fn pull(&mut self) -> (i16, i16) {
+        let left = self.interpolate(0);
+        let right = if self.mono { left } else { self.interpolate(1) };
+
+        self.pending += self.step_whole;
+        self.position += self.step_rest;
+        if self.position >= self.output_rate {
+            self.position -= self.output_rate;
+            self.pending += 1;
+        }
+
+        (left, right)
+    }
+
+    /// The last `TAPS` frames of a channel, oldest first.
+    fn window(&self, channel: usize) -> &[i16] {
+        let oldest = self.newest + 1;
+        &self.history[channel][oldest..oldest + TAPS]
+    }
+
+    fn interpolate(&self, channel: usize) -> i16 {
+        let window = self.window(channel);
+        if self.is_bypassed() {
+            return window[TAPS - 1];
+        }
+
+        let frac = ((self.position as u64 * self.position_scale as u64) >> (32 - FRAC_BITS)) as u32;
+        match self.quality {
+            Quality::Linear => {
+                // Between the two newest frames
+                let a = window[TAPS - 2] as i32;
+                let b = window[TAPS - 1] as i32;
+                (a + (((b - a) * frac as i32) >> FRAC_BITS)) as i16
+            }
+            Quality::Sinc => {
+                // Between the two frames in the middle of the window
+                let phase = (frac >> SUB_PHASE_BITS) as usize;
+                let sub_phase = (frac & ((1 << SUB_PHASE_BITS) - 1)) as i64;
+                let a = convolve(window, &self.table[phase]) as i64;
+                let b = convolve(window, &self.table[phase + 1]) as i64;
+                let value = (a + (((b - a) * sub_phase) >> SUB_PHASE_BITS)) >> 15;
+                value.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+            }
+        }
+    }
+
+    /// Tabulates a Blackman windowed-sinc low-pass filter at every phase.
+    ///
+    /// `cutoff` is twice the cutoff frequency relative to the input rate, so `1.0` is Nyquist.
+    fn build_table(&mut self, cutoff: u32) {
+        for (phase, coefficients) in self.table.iter_mut().enumerate() {
+            let offset = (phase as i64) << (FRAC_BITS - PHASE_BITS);
+            for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+                // Distance from the tap to the output position, in frames
+                let distance = (((tap as i64) - (HALF_TAPS as i64 - 1)) << FRAC_BITS) - offset;
+                *coefficient = ((sinc_q15(distance, cutoff) * blackman_q15(distance) as i64) >> 15) as i32;
+            }
+
+            // Normalize for unity gain at DC, rounding errors go to the largest tap
+            let sum: i32 = coefficients.iter().sum();
+            for coefficient in coefficients.iter_mut() {
+                *coefficient = (*coefficient as i64 * (1 << 15) / sum as i64) as i32;
+            }
+            let error = (1 << 15) - coefficients.iter().sum::<i32>();
+            let center = if phase < PHASES / 2 { HALF_TAPS - 1 } else { HALF_TAPS };
+            coefficients[center] += error;
+        }
+        self.table_cutoff = cutoff;
+    }
+}
+
+fn convolve(window: &[i16], coefficients: &[i32; TAPS]) -> i32 {
+    window
+        .iter()
+        .zip(coefficients)
+        .map(|(&sample, &coefficient)| sample as i32 * coefficient)
+        .sum()
+}
+
+/// Impulse response of an ideal low-pass filter, `sin(pi * cutoff * x) / (pi * x)`, in Q15.
+///
+/// `distance` is in 16.16 frames, `cutoff` is twice the cutoff frequency in 16.16.
+fn sinc_q15(distance: i64, cutoff: u32) -> i64 {
+    if distance == 0 {
+        return (cutoff >> 1) as i64;
+    }
+    // Half a turn per frame at cutoff 1.0
+    let turns = (distance * cutoff as i64) >> (FRAC_BITS + 1);
+    let sin = sin_q15(turns as i32) as i64;
+    (sin << 32) / (PI_16_16 * distance)
+}
+
+/// Blackman window spanning `TAPS` frames, in Q15.
+fn blackman_q15(distance: i64) -> i32 {
+    // 0.42 + 0.5 * cos(pi * x) + 0.08 * cos(2 * pi * x), with x in -1..=1 over the window
+    let x = (distance / HALF_TAPS as i64) as i32;
+    let cos_1 = sin_q15(x / 2 + QUARTER_TURN);
+    let cos_2 = sin_q15(x + QUARTER_TURN);
+    (13763 + cos_1 / 2 + ((cos_2 * 2621) >> 15)).max(0)
+}
+
+/// `pi` in 16.16
+const PI_16_16: i64 = 205_887;
+/// A quarter turn, with 1 << 16 being a full one
+const QUARTER_TURN: i32 = 1 << 14;
+
+/// Sine of an angle in turns (1 << 16 is a full turn), in Q15.
+///
+/// Uses a 7th order polynomial over a quarter turn, which is off by less than 2e-4.
+fn sin_q15(turns: i32) -> i32 {
+    let mut turns = turns & 0xFFFF;
+    let negative = turns >= 2 * QUARTER_TURN;
+    if negative {
+        turns -= 2 * QUARTER_TURN;
+    }
+    if turns > QUARTER_TURN {
+        turns = 2 * QUARTER_TURN - turns;
+    }
+
+    // x in Q30, from 0 to 1 over the quarter turn
+    let x = (turns as i64) << 16;
+    let x2 = (x * x) >> 30;
+    // Taylor coefficients of sin(pi / 2 * x), in Q30
+    const A1: i64 = 1_686_629_713;
+    const A3: i64 = -693_598_668;
+    const A5: i64 = 85_569_306;
+    const A7: i64 = -5_026_995;
+    let polynomial = A1 + ((x2 * (A3 + ((x2 * (A5 + ((x2 * A7) >> 30))) >> 30))) >> 30);
+    let sin = ((x * polynomial) >> 30) >> 15;
+
+    if negative { -sin as i32 } else { sin as i32 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    /// Resamples a sine and returns the output, skipping the filter's warm up.
+    fn resample_sine(quality: Quality, input_rate: u32, output_rate: u32, frequency: f64) -> Vec<f64> {
+        let mut resampler = Resampler::new(quality);
+        resampler.set_rates(input_rate, output_rate, false);
+
+        let mut input = (0..).map(|n| {
+            let value = (2.0 * PI * frequency * n as f64 / input_rate as f64).sin() * 16_000.0;
+            (value.round() as i16, value.round() as i16)
+        });
+        let mut output = Vec::new();
+        for _ in 0..(output_rate as usize / 4) {
+            while resampler.needs_input() {
+                resampler.push(input.next().unwrap());
+            }
+            let (left, _) = resampler.pull();
+            output.push(left as f64);
+        }
+        // Start once the filter is filled with the sine, and no longer with silence
+        output.split_off(2 * TAPS * (output_rate / input_rate + 1) as usize)
+    }
+
+    /// Least squares fits a sine of the given frequency to the signal, and returns its
+    /// amplitude and the signal-to-noise ratio in dB of what is left over.
+    fn fit_sine(signal: &[f64], rate: u32, frequency: f64) -> (f64, f64) {
+        let omega = 2.0 * PI * frequency / rate as f64;
+        let basis = |n: usize| ((omega * n as f64).sin(), (omega * n as f64).cos());
+
+        let (mut ss, mut sc, mut cc, mut ys, mut yc) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (n, value) in signal.iter().enumerate() {
+            let (sin, cos) = basis(n);
+            ss += sin * sin;
+            sc += sin * cos;
+            cc += cos * cos;
+            ys += value * sin;
+            yc += value * cos;
+        }
+        let determinant = ss * cc - sc * sc;
+        let a = (ys * cc - yc * sc) / determinant;
+        let b = (yc * ss - ys * sc) / determinant;
+
+        let (mut signal_power, mut noise_power) = (0.0, 0.0);
+        for (n, value) in signal.iter().enumerate() {
+            let (sin, cos) = basis(n);
+            let fitted = a * sin + b * cos;
+            signal_power += fitted * fitted;
+            noise_power += (value - fitted).powi(2);
+        }
+        ((a * a + b * b).sqrt(), 10.0 * (signal_power / noise_power).log10())
+    }
+
+    fn sweep(from: f64, to: f64) -> impl Iterator<Item = f64> {
+        (0..12).map(move |step| from * (to / from).powf(step as f64 / 11.0))
+    }
+
+    #[test]
+    fn sin_matches_float() {
+        for turns in (-70_000..70_000).step_by(7) {
+            let expected = (2.0 * PI * turns as f64 / 65536.0).sin() * 32768.0;
+            let actual = sin_q15(turns) as f64;
+            assert!((expected - actual).abs() < 8.0, "sin({turns}) = {actual}, expected {expected}");
+        }
+    }
+
+    #[test]
+    fn equal_rates_pass_through() {
+        let mut resampler = Resampler::new(Quality::Sinc);
+        resampler.set_rates(32_000, 32_000, false);
+        assert!(resampler.is_bypassed());
+        for value in [1, -2, 300, i16::MIN, i16::MAX] {
+            assert!(resampler.needs_input());
+            resampler.push((value, value / 2));
+            assert!(!resampler.needs_input());
+            assert_eq!(resampler.pull(), (value, value / 2));
+        }
+    }
+
+    #[test]
+    fn consumes_input_at_rate_ratio() {
+        let mut resampler = Resampler::new(Quality::Linear);
+        resampler.set_rates(48_000, 32_000, false);
+        let mut pushed = 0;
+        for _ in 0..32_000 {
+            while resampler.needs_input() {
+                resampler.push((0, 0));
+                pushed += 1;
+            }
+            resampler.pull();
+        }
+        assert!((47_999..=48_001).contains(&pushed), "pushed {pushed} frames");
+    }
+
+    #[test]
+    fn sinc_sweep_is_clean() {
+        for (input_rate, output_rate) in [(44_100, 32_000), (22_050, 32_000), (48_000, 44_100), (11_025, 48_000)] {
+            let top = input_rate.min(output_rate) as f64 * 0.3;
+            for frequency in sweep(50.0, top) {
+                let output = resample_sine(Quality::Sinc, input_rate, output_rate, frequency);
+                let (amplitude, snr) = fit_sine(&output, output_rate, frequency);
+                assert!(snr > 70.0, "{input_rate}->{output_rate} at {frequency:.0}Hz: {snr:.1}dB SNR");
+                let gain = 20.0 * (amplitude / 16_000.0).log10();
+                assert!(gain.abs() < 0.5, "{input_rate}->{output_rate} at {frequency:.0}Hz: {gain:.2}dB gain");
+            }
+        }
+    }
+
+    #[test]
+    fn linear_sweep_is_usable() {
+        for (input_rate, output_rate) in [(44_100, 32_000), (22_050, 32_000)] {
+            for frequency in sweep(50.0, 1_000.0) {
+                let output = resample_sine(Quality::Linear, input_rate, output_rate, frequency);
+                let (_, snr) = fit_sine(&output, output_rate, frequency);
+                assert!(snr > 40.0, "{input_rate}->{output_rate} at {frequency:.0}Hz: {snr:.1}dB SNR");
+            }
+        }
+    }
+
+    #[test]
+    fn sinc_rejects_aliases() {
+        // 21kHz is above the 16kHz output Nyquist frequency, and would alias to 11kHz
+        let output = resample_sine(Quality::Sinc, 48_000, 32_000, 21_000.0);
+        let rms = (output.iter().map(|value| value * value).sum::<f64>() / output.len() as f64).sqrt();
+        let attenuation = 20.0 * (rms / (16_000.0 / 2f64.sqrt())).log10();
+        assert!(attenuation < -50.0, "alias only attenuated by {attenuation:.1}dB");
+    }
+
+    #[test]
+    fn rejects_too_much_downsampling() {
+        assert!(Resampler::supports(96_000, 32_000));
+        assert!(!Resampler::supports(192_000, 32_000));
+        assert!(!Resampler::supports(0, 32_000));
+    }
+}
@@ -0,0 +1,314 @@
+//! Lock-free single producer, single consumer ring buffer, for streaming bytes between the cores.
+//!
+//! Each side only ever stores its own index and loads the other one, so no compare-and-swap
+//! is needed, which the RP2040's M0+ cores don't have. The indices count every byte that was
+//! ever written or read and wrap around, which tells a full buffer apart from an empty one.
+//!
+//! Building with `--cfg loom` swaps the atomics for loom's, so the tests can check every
+//! interleaving of both sides.
+
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicUsize, Ordering};
+
+/// Shared memory for `N` bytes, where `N` is a power of two.
+pub struct RingBuffer<const N: usize> {
+    storage: Storage<N>,
+    /// Bytes written so far, only stored by the producer
+    head: AtomicUsize,
+    /// Bytes read so far, only stored by the consumer
+    tail: AtomicUsize,
+}
+
+// Safety: the producer only writes bytes the consumer is done with, and the consumer only reads
+// bytes the producer is done with. The release and acquire on the indices hand them over.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    /// The indices wrap around at `usize::MAX`, which only lines up with the storage for powers of two
+    const SIZE_IS_POWER_OF_TWO: () = assert!(N.is_power_of_two(), "RingBuffer size must be a power of two");
+
+    #[cfg(not(loom))]
+    pub const fn new() -> RingBuffer<N> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::SIZE_IS_POWER_OF_TWO;
+        RingBuffer {
+            storage: Storage::new(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    #[cfg(loom)]
+    pub fn new() -> RingBuffer<N> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::SIZE_IS_POWER_OF_TWO;
+        RingBuffer {
+            storage: Storage::new(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Splits the buffer into its writing and reading side, which can go to different cores.
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        let ring = &*self;
+        (Producer { ring }, Consumer { ring })
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The writing side of a `RingBuffer`.
+pub struct Producer<'ring, const N: usize> {
+    ring: &'ring RingBuffer<N>,
+}
+
+impl<const N: usize> Producer<'_, N> {
+    /// How many bytes can be written before the consumer has to catch up.
+    pub fn free(&self) -> usize {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        N - head.wrapping_sub(tail)
+    }
+
+    /// Writes as much of `data` as there is room for, and returns how much that was.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        let amount = data.len().min(N - head.wrapping_sub(tail));
+
+        // The free space may wrap around the end of the storage
+        let start = head % N;
+        let first = amount.min(N - start);
+        // Safety: these bytes have been released by the consumer, and it won't touch
+        // them again until the new head is stored.
+        unsafe {
+            self.ring.storage.copy_in(start, &data[..first]);
+            self.ring.storage.copy_in(0, &data[first..amount]);
+        }
+
+        self.ring.head.store(head.wrapping_add(amount), Ordering::Release);
+        amount
+    }
+}
+
+/// The reading side of a `RingBuffer`.
+pub struct Consumer<'ring, const N: usize> {
+    ring: &'ring RingBuffer<N>,
+}
+
+impl<const N: usize> Consumer<'_, N> {
+    /// How many bytes can be read right now.
+    pub fn available(&self) -> usize {
+        let head = self.ring.head.load(Ordering::Acquire);
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        head.wrapping_sub(tail)
+    }
+
+    /// Fills `buffer` with as many bytes as are available, and returns how many that were.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let head = self.ring.head.load(Ordering::Acquire);
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let amount = buffer.len().min(head.wrapping_sub(tail));
+
+        // The written bytes may wrap around the end of the storage
+        let start = tail % N;
+        let first = amount.min(N - start);
+        // Safety: these bytes have been committed by the producer, and it won't touch
+        // them again until the new tail is stored.
+        unsafe {
+            self.ring.storage.copy_out(start, &mut buffer[..first]);
+            self.ring.storage.copy_out(0, &mut buffer[first..amount]);
+        }
+
+        self.ring.tail.store(tail.wrapping_add(amount), Ordering::Release);
+        amount
+    }
+
+    /// Drops everything that has been written so far.
+    pub fn clear(&mut self) {
+        let head = self.ring.head.load(Ordering::Acquire);
+        self.ring.tail.store(head, Ordering::Release);
+    }
+}
+
+/// The bytes of the ring, in a single cell.
+#[cfg(not(loom))]
+struct Storage<const N: usize>(core::cell::UnsafeCell<[u8; N]>);
+
+#[cfg(not(loom))]
+impl<const N: usize> Storage<N> {
+    const fn new() -> Storage<N> {
+        Storage(core::cell::UnsafeCell::new([0; N]))
+    }
+
+    /// Safety: nobody else may access `at..at + data.len()` at the same time.
+    unsafe fn copy_in(&self, at: usize, data: &[u8]) {
+        let bytes = self.0.get() as *mut u8;
+        core::ptr::copy_nonoverlapping(data.as_ptr(), bytes.add(at), data.len());
+    }
+
+    /// Safety: nobody else may access `at..at + buffer.len()` at the same time.
+    unsafe fn copy_out(&self, at: usize, buffer: &mut [u8]) {
+        let bytes = self.0.get() as *const u8;
+        core::ptr::copy_nonoverlapping(bytes.add(at), buffer.as_mut_ptr(), buffer.len());
+    }
+}
+
+/// The bytes of the ring, each in its own cell so loom can check who accesses which.
+#[cfg(loom)]
+struct Storage<const N: usize>([loom::cell::UnsafeCell<u8>; N]);
+
+#[cfg(loom)]
+impl<const N: usize> Storage<N> {
+    fn new() -> Storage<N> {
+        Storage(core::array::from_fn(|_| loom::cell::UnsafeCell::new(0)))
+    }
+
+    unsafe fn copy_in(&self, at: usize, data: &[u8]) {
+        for (cell, &byte) in self.0[at..at + data.len()].iter().zip(data) {
+            cell.with_mut(|pointer| *pointer = byte);
+        }
+    }
+
+    unsafe fn copy_out(&self, at: usize, buffer: &mut [u8]) {
+        for (cell, byte) in self.0[at..at + buffer.len()].iter().zip(buffer) {
+            *byte = cell.with(|pointer| *pointer);
+        }
+    }
+}
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_up_and_drains() {
+        let mut ring = RingBuffer::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        assert_eq!(producer.free(), 8);
+        assert_eq!(producer.write(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]), 8);
+        assert_eq!(producer.free(), 0);
+        assert_eq!(producer.write(&[11]), 0);
+
+        let mut buffer = [0; 16];
+        assert_eq!(consumer.available(), 8);
+        assert_eq!(consumer.read(&mut buffer), 8);
+        assert_eq!(buffer[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(consumer.read(&mut buffer), 0);
+        assert_eq!(producer.free(), 8);
+    }
+
+    #[test]
+    fn wraps_around_the_end() {
+        let mut ring = RingBuffer::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let mut buffer = [0; 8];
+
+        for round in 0..20u8 {
+            let data = [round, round + 1, round + 2, round + 3, round + 4];
+            assert_eq!(producer.write(&data), 5);
+            assert_eq!(consumer.read(&mut buffer[..3]), 3);
+            assert_eq!(consumer.read(&mut buffer[3..]), 2);
+            assert_eq!(buffer[..5], data);
+        }
+    }
+
+    #[test]
+    fn clear_drops_what_was_written() {
+        let mut ring = RingBuffer::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let mut buffer = [0; 8];
+
+        producer.write(&[1, 2, 3]);
+        consumer.clear();
+        assert_eq!(consumer.available(), 0);
+        assert_eq!(producer.free(), 8);
+
+        producer.write(&[4, 5]);
+        assert_eq!(consumer.read(&mut buffer), 2);
+        assert_eq!(buffer[..2], [4, 5]);
+    }
+
+    #[test]
+    fn streams_between_threads() {
+        const LENGTH: usize = 20_000;
+        let byte_at = |index: usize| (index * 31 + index / 251) as u8;
+
+        let mut ring = RingBuffer::<64>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                // Write in uneven chunks, so they wrap at every possible place
+                let mut written = 0;
+                let mut chunk = [0; 37];
+                while written < LENGTH {
+                    let wanted = chunk.len().min(LENGTH - written);
+                    for (offset, byte) in chunk[..wanted].iter_mut().enumerate() {
+                        *byte = byte_at(written + offset);
+                    }
+                    let mut sent = 0;
+                    while sent < wanted {
+                        sent += producer.write(&chunk[sent..wanted]);
+                        std::hint::spin_loop();
+                    }
+                    written += wanted;
+                }
+            });
+
+            let mut read = 0;
+            let mut chunk = [0; 23];
+            while read < LENGTH {
+                let amount = consumer.read(&mut chunk);
+                for (offset, &byte) in chunk[..amount].iter().enumerate() {
+                    assert_eq!(byte, byte_at(read + offset), "byte {} is wrong", read + offset);
+                }
+                read += amount;
+                std::hint::spin_loop();
+            }
+        });
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    /// Streams a few bytes through a tiny ring, in every order the two threads could run in.
+    #[test]
+    fn every_interleaving_keeps_the_bytes_in_order() {
+        loom::model(|| {
+            let ring: &'static mut RingBuffer<4> = Box::leak(Box::new(RingBuffer::new()));
+            let (mut producer, mut consumer) = ring.split();
+            const DATA: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+            let writer = loom::thread::spawn(move || {
+                let mut sent = 0;
+                while sent < DATA.len() {
+                    sent += producer.write(&DATA[sent..(sent + 3).min(DATA.len())]);
+                    loom::thread::yield_now();
+                }
+            });
+
+            let mut received = [0; 6];
+            let mut read = 0;
+            while read < DATA.len() {
+                let end = (read + 2).min(DATA.len());
+                read += consumer.read(&mut received[read..end]);
+                loom::thread::yield_now();
+            }
+            assert_eq!(received, DATA);
+
+            writer.join().unwrap();
+        });
+    }
+}
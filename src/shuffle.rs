@@ -0,0 +1,214 @@
+//! Shuffled play orders, that play every track once before any plays again.
+//!
+//! The order of a pass through the playlist is a Fisher-Yates shuffle, drawn from a generator
+//! seeded with the seed and the number of the pass. The first pass starts with the track that
+//! was playing when shuffling started. So only those three have to be saved to get the same
+//! order back after a power cycle.
+
+/// What a shuffled order is made from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct ShuffleState {
+    pub seed: u32,
+    /// Passes through the playlist since shuffling started, each has an order of its own
+    pub pass: u32,
+    /// Index of the track the first pass starts with
+    pub first: u16,
+}
+
+impl ShuffleState {
+    /// Starts shuffling with the first pass of `seed`, which starts with the track at `first`.
+    pub const fn new(seed: u32, first: u16) -> ShuffleState {
+        ShuffleState { seed, pass: 0, first }
+    }
+}
+
+/// A xorshift generator, which is plenty random for picking tracks.
+#[derive(Clone, Copy, Debug)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    /// Starts from `seed`, which can be anything.
+    pub const fn new(seed: u32) -> Random {
+        // Xorshift stays at 0 forever, every other state goes through all others
+        let state = mix(seed);
+        Random { state: if state == 0 { 0x9E37_79B9 } else { state } }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// A number below `bound`, every one of them as likely.
+    pub fn below(&mut self, bound: u32) -> u32 {
+        // Multiplying and shifting, without the few products that would make some numbers likelier
+        let threshold = bound.wrapping_neg() % bound.max(1);
+        loop {
+            let product = self.next_u32() as u64 * bound as u64;
+            if product as u32 >= threshold {
+                return (product >> 32) as u32;
+            }
+        }
+    }
+}
+
+/// Spreads every bit of `x` over all bits, so similar seeds give unrelated sequences.
+const fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7FEB_352D);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846C_A68B);
+    x ^= x >> 16;
+    x
+}
+
+/// The order `length` tracks play in while shuffling, as their indices.
+#[derive(Clone, Copy, Debug)]
+pub struct Shuffle<const N: usize> {
+    order: [u16; N],
+    length: usize,
+    state: ShuffleState,
+}
+
+impl<const N: usize> Shuffle<N> {
+    /// The order of `state` for `length` tracks, at most `N`.
+    pub fn new(length: usize, state: ShuffleState) -> Shuffle<N> {
+        let mut shuffle = Shuffle { order: [0; N], length: length.min(N), state };
+        shuffle.draw();
+        shuffle
+    }
+
+    pub fn state(&self) -> ShuffleState {
+        self.state
+    }
+
+    pub fn order(&self) -> &[u16] {
+        &self.order[..self.length]
+    }
+
+    /// Where the track at `index` comes in the order.
+    pub fn position(&self, index: usize) -> Option<usize> {
+        self.order().iter().position(|&track| track as usize == index)
+    }
+
+    /// Shuffles again for the following pass.
+    pub fn next_pass(&mut self) {
+        self.state.pass = self.state.pass.wrapping_add(1);
+        self.draw();
+    }
+
+    /// Fills the order for the pass of `state`.
+    fn draw(&mut self) {
+        let pass = self.state.pass;
+        self.fill(pass);
+        if pass == 0 || self.length < 2 {
+            return;
+        }
+
+        // The track that ended the pass before shouldn't play again right away,
+        // so two tracks can only take turns
+        if self.length == 2 {
+            self.fill(0);
+            return;
+        }
+        // Later passes only ever swap their first two, so the last one of the pass before
+        // is as it was filled
+        let mut before = *self;
+        before.fill(pass - 1);
+        if self.order[0] == before.order[self.length - 1] {
+            self.order.swap(0, 1);
+        }
+    }
+
+    /// Fills the order with the Fisher-Yates shuffle of `pass`.
+    fn fill(&mut self, pass: u32) {
+        let mut random = Random::new(self.state.seed ^ mix(pass));
+        for (index, track) in self.order[..self.length].iter_mut().enumerate() {
+            *track = index as u16;
+        }
+        for last in (1..self.length).rev() {
+            let other = random.below(last as u32 + 1) as usize;
+            self.order.swap(last, other);
+        }
+
+        if pass == 0 {
+            if let Some(first) = self.position(self.state.first as usize) {
+                self.order.swap(0, first);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_every_track_once_per_pass() {
+        let mut shuffle = Shuffle::<64>::new(50, ShuffleState::new(1234, 0));
+        for _ in 0..5 {
+            let mut order = shuffle.order().to_vec();
+            order.sort_unstable();
+            assert_eq!(order, (0..50).collect::<Vec<u16>>());
+            shuffle.next_pass();
+        }
+    }
+
+    #[test]
+    fn passes_and_seeds_have_their_own_order() {
+        let first = Shuffle::<64>::new(64, ShuffleState::new(1, 0));
+        let mut second = first;
+        second.next_pass();
+        let other_seed = Shuffle::<64>::new(64, ShuffleState::new(2, 0));
+        assert_ne!(first.order(), second.order());
+        assert_ne!(first.order(), other_seed.order());
+        assert_ne!(first.order(), (0..64).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn same_state_gives_the_same_order() {
+        let mut shuffle = Shuffle::<64>::new(40, ShuffleState::new(99, 5));
+        shuffle.next_pass();
+        shuffle.next_pass();
+        let restored = Shuffle::<64>::new(40, shuffle.state());
+        assert_eq!(restored.state().pass, 2);
+        assert_eq!(restored.order(), shuffle.order());
+    }
+
+    #[test]
+    fn starts_with_the_first_track() {
+        for first in 0..10 {
+            let shuffle = Shuffle::<16>::new(10, ShuffleState::new(3, first));
+            assert_eq!(shuffle.order()[0], first);
+        }
+    }
+
+    #[test]
+    fn never_repeats_a_track_between_passes() {
+        for length in [2, 3, 4] {
+            let mut shuffle = Shuffle::<4>::new(length, ShuffleState::new(7, 1));
+            for _ in 0..100 {
+                let last = *shuffle.order().last().unwrap();
+                shuffle.next_pass();
+                assert_ne!(shuffle.order()[0], last, "with {length} tracks");
+            }
+        }
+    }
+
+    #[test]
+    fn every_number_below_the_bound_is_as_likely() {
+        let mut random = Random::new(0);
+        let mut counts = [0u32; 6];
+        for _ in 0..60_000 {
+            counts[random.below(6) as usize] += 1;
+        }
+        assert!(counts.iter().all(|&count| (9_500..10_500).contains(&count)), "{counts:?}");
+    }
+}
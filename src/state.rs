@@ -0,0 +1,229 @@
+//! Where playback is, kept on the card so it goes on from there after a power cycle.
+//!
+//! Cards wear out from writing the same block over and over, so the state file has
+//! `SLOT_COUNT` slots a block apart, and every save goes to the slot after the last one.
+//! The newest slot with a correct check is the one that counts, so a save that is cut short
+//! by the power going out only loses that save.
+//!
+//! The file is as long as all its slots from the first save on, and the firmware keeps it open
+//! while the card is mounted. So a save only writes the block of its slot, and not the directory
+//! entry or the FAT, which every save would wear out otherwise.
+
+use crate::shuffle::ShuffleState;
+
+/// Slots the saves rotate through
+pub const SLOT_COUNT: usize = 4;
+/// Bytes from the start of one slot to the next, so every slot has an SD card block to itself
+pub const SLOT_STRIDE: u32 = 512;
+/// Bytes of a slot that are used
+pub const SLOT_SIZE: usize = 32;
+/// Bytes before the check, which is at the end of a slot
+const CHECKED: usize = SLOT_SIZE - 2;
+
+/// Size of an SD card block, which resume offsets are aligned to
+const BLOCK_SIZE: u32 = 512;
+
+/// Where playback was, as it is saved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PlayState {
+    /// `Playlist::fingerprint` of the card, so it is only used with the same tracks
+    pub tracks: u32,
+    /// Index of the track in the playlist
+    pub track: u16,
+    /// Bytes into the samples of the track
+    pub offset: u32,
+    /// The shuffled order, `None` when playing in order
+    pub shuffle: Option<ShuffleState>,
+}
+
+impl PlayState {
+    /// Stores the state with its `sequence` number and a check, so a damaged slot isn't mistaken for one.
+    fn to_bytes(self, sequence: u32) -> [u8; SLOT_SIZE] {
+        let mut bytes = [0; SLOT_SIZE];
+        bytes[0..4].copy_from_slice(&sequence.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tracks.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.track.to_le_bytes());
+        bytes[10..14].copy_from_slice(&self.offset.to_le_bytes());
+        if let Some(shuffle) = self.shuffle {
+            bytes[14] = 1;
+            bytes[16..20].copy_from_slice(&shuffle.seed.to_le_bytes());
+            bytes[20..24].copy_from_slice(&shuffle.pass.to_le_bytes());
+            bytes[24..26].copy_from_slice(&shuffle.first.to_le_bytes());
+        }
+        let check = check(&bytes[..CHECKED]);
+        bytes[CHECKED..].copy_from_slice(&check.to_le_bytes());
+        bytes
+    }
+
+    /// Reads a state and its sequence number stored by `to_bytes`.
+    fn from_bytes(bytes: &[u8]) -> Option<(u32, PlayState)> {
+        let bytes: &[u8; SLOT_SIZE] = bytes.try_into().ok()?;
+        if u16::from_le_bytes([bytes[CHECKED], bytes[CHECKED + 1]]) != check(&bytes[..CHECKED]) {
+            return None;
+        }
+        let word = |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        let state = PlayState {
+            tracks: word(4),
+            track: u16::from_le_bytes([bytes[8], bytes[9]]),
+            offset: word(10),
+            shuffle: (bytes[14] & 1 != 0).then(|| ShuffleState { seed: word(16), pass: word(20), first: u16::from_le_bytes([bytes[24], bytes[25]]) }),
+        };
+        Some((word(0), state))
+    }
+}
+
+/// 32 bit FNV-1a folded into 16 bits, which an all zero slot never passes.
+fn check(bytes: &[u8]) -> u16 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for &byte in bytes {
+        hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+    }
+    (hash ^ (hash >> 16)) as u16
+}
+
+/// Keeps track of the slots of the state file: which one is newest, and what is in it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StateSlots {
+    /// Sequence number of the newest slot
+    sequence: u32,
+    saved: Option<PlayState>,
+}
+
+impl StateSlots {
+    /// Slots of a file that has nothing saved yet.
+    pub const fn new() -> StateSlots {
+        StateSlots { sequence: 0, saved: None }
+    }
+
+    /// Reads one slot of the file, in any order, keeping it if it is newer than the ones before.
+    pub fn read(&mut self, bytes: &[u8]) {
+        let Some((sequence, state)) = PlayState::from_bytes(bytes) else {
+            return;
+        };
+        // Sequence numbers wrap around, newer ones are less than half the range ahead
+        if self.saved.is_none() || (sequence.wrapping_sub(self.sequence) as i32) > 0 {
+            self.sequence = sequence;
+            self.saved = Some(state);
+        }
+    }
+
+    /// The newest state in the file.
+    pub fn saved(&self) -> Option<PlayState> {
+        self.saved
+    }
+
+    /// Returns the slot `state` goes in and its bytes, or `None` when it is what was saved last,
+    /// so nothing has to be written.
+    pub fn save(&mut self, state: PlayState) -> Option<(usize, [u8; SLOT_SIZE])> {
+        if self.saved == Some(state) {
+            return None;
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.saved = Some(state);
+        Some((self.sequence as usize % SLOT_COUNT, state.to_bytes(self.sequence)))
+    }
+}
+
+/// Rounds `offset` into the samples down to whole frames of `block_align` bytes, that start an SD
+/// card block from the start of the samples when the frames fit in blocks evenly.
+pub fn align_offset(offset: u32, block_align: u16) -> u32 {
+    let block_align = (block_align as u32).max(1);
+    let (mut a, mut b) = (BLOCK_SIZE, block_align);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    // The least common multiple, so both line up
+    let alignment = BLOCK_SIZE / a * block_align;
+    offset - offset % alignment
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: PlayState = PlayState { tracks: 0xDA15_1E50, track: 3, offset: 1_048_576, shuffle: None };
+
+    /// A file with `saves` written to it, like the firmware does.
+    fn file(saves: &[PlayState]) -> ([[u8; SLOT_SIZE]; SLOT_COUNT], StateSlots) {
+        let mut slots = StateSlots::new();
+        let mut file = [[0; SLOT_SIZE]; SLOT_COUNT];
+        for &state in saves {
+            if let Some((slot, bytes)) = slots.save(state) {
+                file[slot] = bytes;
+            }
+        }
+        (file, slots)
+    }
+
+    fn load(file: &[[u8; SLOT_SIZE]]) -> StateSlots {
+        let mut slots = StateSlots::new();
+        file.iter().for_each(|slot| slots.read(slot));
+        slots
+    }
+
+    #[test]
+    fn finds_the_newest_save() {
+        let saves: Vec<_> = (0..10).map(|offset| PlayState { offset, ..STATE }).collect();
+        let (file, _) = file(&saves);
+        assert_eq!(load(&file).saved(), Some(saves[9]));
+        // Not in the order of the file
+        assert_eq!(load(&[file[2], file[1], file[0], file[3]]).saved(), Some(saves[9]));
+        assert_eq!(load(&[[0; SLOT_SIZE]; SLOT_COUNT]).saved(), None);
+    }
+
+    #[test]
+    fn rotates_through_the_slots() {
+        let mut slots = StateSlots::new();
+        let used: Vec<_> = (0..SLOT_COUNT as u32 * 2).map(|offset| slots.save(PlayState { offset, ..STATE }).unwrap().0).collect();
+        for slot in 0..SLOT_COUNT {
+            assert_eq!(used.iter().filter(|&&used| used == slot).count(), 2);
+        }
+    }
+
+    #[test]
+    fn only_writes_changes() {
+        let (file, mut slots) = file(&[STATE]);
+        assert_eq!(slots.save(STATE), None);
+        let mut slots = load(&file);
+        assert_eq!(slots.save(STATE), None);
+        assert!(slots.save(PlayState { track: 4, ..STATE }).is_some());
+    }
+
+    #[test]
+    fn keeps_the_shuffled_order() {
+        let state = PlayState { shuffle: Some(ShuffleState { seed: 0xC0FFEE, pass: 3, first: 17 }), ..STATE };
+        let (file, _) = file(&[STATE, state]);
+        assert_eq!(load(&file).saved(), Some(state));
+    }
+
+    #[test]
+    fn skips_a_damaged_save() {
+        let (mut file, mut slots) = file(&[STATE]);
+        let (slot, mut bytes) = slots.save(PlayState { offset: 0, ..STATE }).unwrap();
+        // The power went out while this was written
+        bytes[12] ^= 0x40;
+        file[slot] = bytes;
+        assert_eq!(load(&file).saved(), Some(STATE));
+    }
+
+    #[test]
+    fn continues_after_the_sequence_wraps() {
+        let mut slots = StateSlots { sequence: u32::MAX - 1, saved: Some(STATE) };
+        let mut file = [[0; SLOT_SIZE]; SLOT_COUNT];
+        for offset in 0..3 {
+            let (slot, bytes) = slots.save(PlayState { offset, ..STATE }).unwrap();
+            file[slot] = bytes;
+        }
+        assert_eq!(load(&file).saved(), Some(PlayState { offset: 2, ..STATE }));
+    }
+
+    #[test]
+    fn aligns_to_blocks_and_frames() {
+        assert_eq!(align_offset(1_000, 4), 512);
+        assert_eq!(align_offset(511, 4), 0);
+        // 6 byte frames only line up with blocks every 1536 bytes
+        assert_eq!(align_offset(3_000, 6), 1_536);
+        assert_eq!(align_offset(3_000, 0), 2_560);
+    }
+}
@@ -0,0 +1,139 @@
+//! Volume steps on a decibel curve.
+//!
+//! Loudness is heard logarithmically, so every step changes the gain by the same number of
+//! decibels rather than by the same amount. The gains are Q15, like `protocol::UNITY_GAIN`.
+
+use crate::protocol::UNITY_GAIN;
+
+/// The loudest step, which leaves samples as they are. Step 0 is muted.
+pub const MAX_STEP: u8 = 20;
+/// Decibels between two steps, so step 1 is at -38dB
+pub const STEP_DB: u8 = 2;
+
+/// A step down in Q15, 10^(-STEP_DB/20)
+const STEP_FACTOR: u32 = 26_029;
+
+/// Gain of every step, from muted up to unity
+const GAINS: [u16; MAX_STEP as usize + 1] = {
+    let mut gains = [0; MAX_STEP as usize + 1];
+    gains[MAX_STEP as usize] = UNITY_GAIN;
+    let mut step = MAX_STEP as usize;
+    while step > 1 {
+        gains[step - 1] = ((gains[step] as u32 * STEP_FACTOR + (1 << 14)) >> 15) as u16;
+        step -= 1;
+    }
+    gains
+};
+
+/// The volume, and how loud it may go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Volume {
+    step: u8,
+    /// Highest step `up` goes to, to protect the listener's hearing
+    limit: u8,
+}
+
+impl Volume {
+    /// Starts at `step`, never going above `limit`.
+    pub fn new(step: u8, limit: u8) -> Volume {
+        let limit = limit.min(MAX_STEP);
+        Volume { step: step.min(limit), limit }
+    }
+
+    pub fn step(&self) -> u8 {
+        self.step
+    }
+
+    /// The gain to scale samples by.
+    pub fn gain(&self) -> u16 {
+        GAINS[self.step as usize]
+    }
+
+    /// Goes a step louder. Returns `false` if it is at the limit already.
+    pub fn up(&mut self) -> bool {
+        if self.step >= self.limit {
+            return false;
+        }
+        self.step += 1;
+        true
+    }
+
+    /// Goes a step quieter. Returns `false` if it is muted already.
+    pub fn down(&mut self) -> bool {
+        if self.step == 0 {
+            return false;
+        }
+        self.step -= 1;
+        true
+    }
+
+    /// Stores the step with a check byte, so a damaged file isn't mistaken for a volume.
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [self.step, !self.step]
+    }
+
+    /// Reads a step stored by `to_bytes`, keeping it below `limit`.
+    pub fn from_bytes(bytes: &[u8], limit: u8) -> Option<Volume> {
+        match *bytes {
+            [step, check] if check == !step && step <= MAX_STEP => Some(Volume::new(step, limit)),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decibels(gain: u16) -> f64 {
+        20.0 * (gain as f64 / UNITY_GAIN as f64).log10()
+    }
+
+    #[test]
+    fn steps_follow_the_decibel_curve() {
+        assert_eq!(GAINS[0], 0);
+        assert_eq!(GAINS[MAX_STEP as usize], UNITY_GAIN);
+        for step in 1..=MAX_STEP {
+            let wanted = -((MAX_STEP - step) as f64) * STEP_DB as f64;
+            let actual = decibels(GAINS[step as usize]);
+            assert!((actual - wanted).abs() < 0.05, "step {step} is {actual}dB instead of {wanted}dB");
+        }
+    }
+
+    #[test]
+    fn stays_between_mute_and_the_limit() {
+        let mut volume = Volume::new(MAX_STEP, 15);
+        assert_eq!(volume.step(), 15);
+        assert!(!volume.up());
+        assert!(volume.down());
+        assert!(volume.up());
+        assert_eq!(volume.step(), 15);
+
+        let mut volume = Volume::new(1, MAX_STEP);
+        assert!(volume.down());
+        assert_eq!(volume.gain(), 0);
+        assert!(!volume.down());
+    }
+
+    #[test]
+    fn limit_is_kept_below_the_loudest_step() {
+        let mut volume = Volume::new(MAX_STEP, u8::MAX);
+        assert!(!volume.up());
+        assert_eq!(volume.gain(), UNITY_GAIN);
+    }
+
+    #[test]
+    fn survives_being_stored() {
+        let volume = Volume::new(12, MAX_STEP);
+        assert_eq!(Volume::from_bytes(&volume.to_bytes(), MAX_STEP), Some(volume));
+        assert_eq!(Volume::from_bytes(&volume.to_bytes(), 10).map(|volume| volume.step()), Some(10));
+    }
+
+    #[test]
+    fn rejects_damaged_bytes() {
+        assert_eq!(Volume::from_bytes(&[12, 12], MAX_STEP), None);
+        assert_eq!(Volume::from_bytes(&[12], MAX_STEP), None);
+        assert_eq!(Volume::from_bytes(&[200, !200], MAX_STEP), None);
+    }
+}